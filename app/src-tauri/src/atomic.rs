@@ -0,0 +1,129 @@
+// Crash-safe file modification: changes go to a sibling temp file which is
+// fsynced and renamed over the original, so a crash or full disk leaves either
+// the old or the new file, never a half-written one. That includes appending or
+// cutting off a trailer: the copy is made with `fs::copy`, so memory use stays
+// the same for any file size.
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::anyhow;
+
+fn sibling(path: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let parent = path.parent().ok_or_else(|| anyhow!("No parent dir for {}", path.display()))?;
+    let name = path.file_name().ok_or_else(|| anyhow!("No file name in {}", path.display()))?;
+    Ok(parent.join(format!("{}{}", name.to_string_lossy(), suffix)))
+}
+
+fn temp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_nanos();
+    sibling(path, &format!(".{}-{}.tmp", std::process::id(), nanos))
+}
+
+pub fn backup_path(path: &Path) -> anyhow::Result<PathBuf> {
+    sibling(path, ".bak")
+}
+
+/// Keep the pre-change file as `<name>.bak`. An existing backup is left alone,
+/// so repeated edits keep the oldest (original) version.
+fn make_backup(path: &Path) -> anyhow::Result<()> {
+    let bak = backup_path(path)?;
+    if bak.exists() { return Ok(()); }
+    if fs::hard_link(path, &bak).is_err() {
+        fs::copy(path, &bak)?;
+    }
+    Ok(())
+}
+
+fn sync_parent(path: &Path) {
+    // Persist the rename itself; directories can't be opened for this on Windows
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) { let _ = dir.sync_all(); }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+fn commit<T>(path: &Path, tmp: &Path, keep_backup: bool, result: anyhow::Result<T>) -> anyhow::Result<T> {
+    let finish = || -> anyhow::Result<()> {
+        if keep_backup { make_backup(path)?; }
+        fs::rename(tmp, path)?;
+        sync_parent(path);
+        Ok(())
+    };
+    match result.and_then(|v| finish().map(|_| v)) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            let _ = fs::remove_file(tmp);
+            Err(anyhow!("Failed to write {}: {}", path.display(), e))
+        }
+    }
+}
+
+/// Modify a copy of `path` with `edit` and swap it in. `edit` gets the copy opened
+/// read/write. The copy uses `fs::copy`, which clones or copies in-kernel where the
+/// file system supports it.
+pub fn modify<T, F>(path: &Path, keep_backup: bool, edit: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&mut File) -> anyhow::Result<T>,
+{
+    let tmp = temp_path(path)?;
+    let result = (|| {
+        fs::copy(path, &tmp)?;
+        let mut f = OpenOptions::new().read(true).write(true).open(&tmp)?;
+        let v = edit(&mut f)?;
+        f.sync_all()?;
+        Ok(v)
+    })();
+    commit(path, &tmp, keep_backup, result)
+}
+
+/// Replace `path` with content written by `write` to a fresh file.
+pub fn replace<T, F>(path: &Path, keep_backup: bool, write: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&mut File) -> anyhow::Result<T>,
+{
+    let tmp = temp_path(path)?;
+    let result = (|| {
+        let mut f = File::create(&tmp)?;
+        let v = write(&mut f)?;
+        f.sync_all()?;
+        if let Ok(meta) = fs::metadata(path) {
+            fs::set_permissions(&tmp, meta.permissions())?;
+        }
+        Ok(v)
+    })();
+    commit(path, &tmp, keep_backup, result)
+}
+
+/// A few bytes to overwrite at an offset along with an append or truncation,
+/// such as a container's size field.
+pub type Patch<'a> = Option<(u64, &'a [u8])>;
+
+/// Append `data` to a copy of `path`, with `patch`, and swap it in.
+pub fn append(path: &Path, keep_backup: bool, data: &[u8], patch: Patch) -> anyhow::Result<()> {
+    modify(path, keep_backup, |f| {
+        f.seek(SeekFrom::End(0))?;
+        f.write_all(data)?;
+        write_patch(f, patch)?;
+        Ok(())
+    })
+}
+
+/// Cut a copy of `path` to `len` bytes, with `patch`, and swap it in.
+pub fn truncate(path: &Path, keep_backup: bool, len: u64, patch: Patch) -> anyhow::Result<()> {
+    modify(path, keep_backup, |f| {
+        f.set_len(len)?;
+        write_patch(f, patch)?;
+        Ok(())
+    })
+}
+
+fn write_patch(f: &mut File, patch: Patch) -> std::io::Result<()> {
+    if let Some((offset, bytes)) = patch {
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(bytes)?;
+    }
+    Ok(())
+}
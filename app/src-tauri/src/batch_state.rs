@@ -0,0 +1,89 @@
+// State file a batch keeps in its `-Copies` folder, so that `resume_batch` can
+// pick it up after a crash or cancellation. It holds the arguments and preferences
+// the batch was started with (and the seed and date its copies depend on) and, for every copy
+// that finished, the size and SHA-256 of every file it left. A copy whose output
+// no longer hashes the same has been changed or partly deleted since, and is
+// made again.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use walkdir::WalkDir;
+
+use crate::{atomic, signing, BatchArgs, Preferences};
+
+pub const STATE_FILE: &str = "batch-state.json";
+
+/// Size and SHA-256 (base64) of one output file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileDigest {
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// What a finished copy left on disk: the files of its folder by path relative
+/// to it, or its zip when zipping.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyOutput {
+    pub files: BTreeMap<String, FileDigest>,
+}
+
+impl CopyOutput {
+    /// Digest every file under `folder`. Missing folders have no files.
+    pub fn of_folder(folder: &Path) -> anyhow::Result<CopyOutput> {
+        let mut files = BTreeMap::new();
+        for entry in WalkDir::new(folder).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+            let rel = entry.path().strip_prefix(folder)?.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.insert(rel, digest(entry.path())?);
+        }
+        Ok(CopyOutput { files })
+    }
+
+    /// Digest the single file `path`, if it exists.
+    pub fn of_file(path: &Path) -> anyhow::Result<CopyOutput> {
+        let mut files = BTreeMap::new();
+        if path.is_file() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            files.insert(name, digest(path)?);
+        }
+        Ok(CopyOutput { files })
+    }
+}
+
+fn digest(path: &Path) -> anyhow::Result<FileDigest> {
+    let bytes = fs::metadata(path)?.len();
+    Ok(FileDigest { bytes, sha256: STANDARD.encode(signing::hash_file_prefix(path, bytes)?) })
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BatchState {
+    pub args: BatchArgs,
+    /// Font, key, JPEG quality and the like are taken from these on resume, not
+    /// from the preferences of the day.
+    pub prefs: Preferences,
+    pub seed: u64,
+    /// Value of the `{date}` label placeholder, kept for the copies made on resume.
+    pub date: String,
+    /// Finished copies by order number, e.g. "007".
+    pub finished: BTreeMap<String, CopyOutput>,
+}
+
+impl BatchState {
+    pub fn load(copies_folder: &Path) -> anyhow::Result<BatchState> {
+        let path = copies_folder.join(STATE_FILE);
+        let data = fs::read(&path).map_err(|e| anyhow!("No batch to resume in {}: {}", copies_folder.display(), e))?;
+        serde_json::from_slice(&data).map_err(|e| anyhow!("Unreadable batch state {}: {}", path.display(), e))
+    }
+
+    /// Replace the state file; a crash while writing leaves the previous one.
+    pub fn save(&self, copies_folder: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        atomic::replace(&copies_folder.join(STATE_FILE), false, |f| Ok(f.write_all(&data)?))
+    }
+}
@@ -0,0 +1,491 @@
+// "Structured" watermark storage inside the container format instead of after it:
+// a private PNG chunk, a JPEG COM segment, a top-level ISO BMFF `uuid` box and a
+// Matroska `Tags` element. The stored blob is an encoded trailer (see `marker.rs`),
+// so parsing, checksums and signatures are shared with the tail marker.
+//
+// Images are small and handled in memory; video containers are walked with seeks
+// and only the watermark element is read or written.
+//
+// Matroska elements of unknown size (live or streamed recordings) can't be
+// skipped, so nothing after them could be found again; such files get a tail
+// marker instead.
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::anyhow;
+
+use crate::atomic;
+use crate::signing;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Png,
+    Jpeg,
+    /// MP4 / MOV
+    IsoBmff,
+    Matroska,
+}
+
+/// Watermark blob stored in the file, plus where it sits.
+pub struct Embedded {
+    pub blob: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+/// Fails if the file's layout does not allow adding a watermark without
+/// rewriting the media data, or where it could not be found again.
+pub fn check_appendable(path: &Path, container: Container) -> anyhow::Result<()> {
+    match container {
+        Container::Png | Container::Jpeg => Ok(()),
+        Container::IsoBmff => bmff::check_appendable(&mut File::open(path)?),
+        Container::Matroska => mkv::check_appendable(&mut File::open(path)?),
+    }
+}
+
+/// Store `blob` in the file. Fails if the file already holds one or
+/// [`check_appendable`] does.
+pub fn embed(path: &Path, container: Container, blob: &[u8], keep_backup: bool) -> anyhow::Result<()> {
+    if find(path, container)?.is_some() {
+        return Err(anyhow!("{} already contains a structured watermark", path.display()));
+    }
+    check_appendable(path, container)?;
+    match container {
+        Container::Png => {
+            let data = fs::read(path)?;
+            let out = png::insert(&data, blob)?;
+            atomic::replace(path, keep_backup, |f| Ok(f.write_all(&out)?))
+        }
+        Container::Jpeg => {
+            let data = fs::read(path)?;
+            let out = jpeg::insert(&data, blob)?;
+            atomic::replace(path, keep_backup, |f| Ok(f.write_all(&out)?))
+        }
+        Container::IsoBmff => {
+            let boxed = bmff::uuid_box(blob);
+            atomic::append(path, keep_backup, &boxed, None)
+        }
+        Container::Matroska => {
+            let seg = mkv::segment(&mut File::open(path)?)?;
+            let tags = mkv::tags_element(blob);
+            let new_size = match seg.size {
+                Some(size) => Some(mkv::encode_size(size + tags.len() as u64, seg.size_width)?),
+                None => None,
+            };
+            atomic::append(path, keep_backup, &tags, new_size.as_deref().map(|size| (seg.size_offset, size)))
+        }
+    }
+}
+
+pub fn find(path: &Path, container: Container) -> anyhow::Result<Option<Embedded>> {
+    match container {
+        Container::Png => Ok(png::find(&fs::read(path)?)?),
+        Container::Jpeg => Ok(jpeg::find(&fs::read(path)?)?),
+        Container::IsoBmff => bmff::find(&mut File::open(path)?),
+        Container::Matroska => mkv::find(&mut File::open(path)?),
+    }
+}
+
+/// Take the watermark out again. Returns false if there was none.
+pub fn remove(path: &Path, container: Container, keep_backup: bool) -> anyhow::Result<bool> {
+    let Some(found) = find(path, container)? else { return Ok(false) };
+    match container {
+        Container::Png | Container::Jpeg => {
+            let mut data = fs::read(path)?;
+            data.drain(found.offset as usize..(found.offset + found.len) as usize);
+            atomic::replace(path, keep_backup, |f| Ok(f.write_all(&data)?))?;
+        }
+        Container::IsoBmff => {
+            let file_len = fs::metadata(path)?.len();
+            if found.offset + found.len != file_len {
+                return Err(anyhow!("Watermark box in {} is not the last box, refusing to shift media data", path.display()));
+            }
+            atomic::truncate(path, keep_backup, found.offset, None)?;
+        }
+        Container::Matroska => {
+            let seg = mkv::segment(&mut File::open(path)?)?;
+            let file_len = fs::metadata(path)?.len();
+            if found.offset + found.len != file_len {
+                return Err(anyhow!("Watermark tags in {} are not at the end of the segment", path.display()));
+            }
+            let new_size = match seg.size {
+                Some(size) => Some(mkv::encode_size(size - found.len, seg.size_width)?),
+                None => None,
+            };
+            atomic::truncate(path, keep_backup, found.offset, new_size.as_deref().map(|size| (seg.size_offset, size)))?;
+        }
+    }
+    Ok(true)
+}
+
+/// SHA-256 of the file as it was before `found` was embedded.
+pub fn original_hash(path: &Path, container: Container, found: &Embedded) -> anyhow::Result<[u8; 32]> {
+    use sha2::{Digest, Sha256};
+    match container {
+        Container::Png | Container::Jpeg => {
+            let mut data = fs::read(path)?;
+            data.drain(found.offset as usize..(found.offset + found.len) as usize);
+            Ok(Sha256::digest(&data).into())
+        }
+        Container::IsoBmff => signing::hash_file_prefix(path, found.offset),
+        Container::Matroska => {
+            let seg = mkv::segment(&mut File::open(path)?)?;
+            match seg.size {
+                Some(size) => {
+                    let old = mkv::encode_size(size - found.len, seg.size_width)?;
+                    signing::hash_file_prefix_patched(path, found.offset, seg.size_offset, &old)
+                }
+                None => signing::hash_file_prefix(path, found.offset),
+            }
+        }
+    }
+}
+
+mod png {
+    use super::*;
+
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    /// Ancillary, private, safe-to-copy chunk.
+    const CHUNK_TYPE: &[u8; 4] = b"edWm";
+
+    /// (offset, total length, type) of each chunk.
+    fn chunks(data: &[u8]) -> anyhow::Result<Vec<(usize, usize, [u8; 4])>> {
+        if !data.starts_with(SIGNATURE) { return Err(anyhow!("Not a PNG file")); }
+        let mut out = Vec::new();
+        let mut pos = SIGNATURE.len();
+        while pos + 12 <= data.len() {
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let ty: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+            let total = 12 + len;
+            if pos + total > data.len() { return Err(anyhow!("Truncated PNG chunk")); }
+            out.push((pos, total, ty));
+            pos += total;
+            if &ty == b"IEND" { break; }
+        }
+        Ok(out)
+    }
+
+    pub fn insert(data: &[u8], blob: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let iend = chunks(data)?.into_iter().find(|c| &c.2 == b"IEND")
+            .ok_or_else(|| anyhow!("PNG has no IEND chunk"))?.0;
+        let mut chunk = Vec::with_capacity(blob.len() + 12);
+        chunk.extend_from_slice(&(blob.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(CHUNK_TYPE);
+        chunk.extend_from_slice(blob);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(CHUNK_TYPE);
+        hasher.update(blob);
+        chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+        let mut out = Vec::with_capacity(data.len() + chunk.len());
+        out.extend_from_slice(&data[..iend]);
+        out.extend_from_slice(&chunk);
+        out.extend_from_slice(&data[iend..]);
+        Ok(out)
+    }
+
+    pub fn find(data: &[u8]) -> anyhow::Result<Option<Embedded>> {
+        Ok(chunks(data)?.into_iter().find(|c| &c.2 == CHUNK_TYPE).map(|(offset, len, _)| Embedded {
+            blob: data[offset + 8..offset + len - 4].to_vec(),
+            offset: offset as u64,
+            len: len as u64,
+        }))
+    }
+}
+
+mod jpeg {
+    use super::*;
+
+    const COM: u8 = 0xFE;
+    const SOS: u8 = 0xDA;
+    const EOI: u8 = 0xD9;
+    /// Start of our COM segment payload, so other comments are left alone.
+    const TAG: &[u8] = b"endecode-wm\0";
+
+    /// (offset, total length, marker) of each segment between SOI and SOS.
+    fn segments(data: &[u8]) -> anyhow::Result<Vec<(usize, usize, u8)>> {
+        if !data.starts_with(&[0xFF, 0xD8]) { return Err(anyhow!("Not a JPEG file")); }
+        let mut out = Vec::new();
+        let mut pos = 2;
+        loop {
+            if pos + 4 > data.len() || data[pos] != 0xFF { return Err(anyhow!("Malformed JPEG segment at {}", pos)); }
+            let marker = data[pos + 1];
+            if marker == SOS || marker == EOI { break; }
+            if marker == 0xFF { pos += 1; continue; }
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            if len < 2 || pos + 2 + len > data.len() { return Err(anyhow!("Truncated JPEG segment at {}", pos)); }
+            out.push((pos, 2 + len, marker));
+            pos += 2 + len;
+        }
+        Ok(out)
+    }
+
+    pub fn insert(data: &[u8], blob: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let len = 2 + TAG.len() + blob.len();
+        if len > u16::MAX as usize { return Err(anyhow!("Watermark too large for a JPEG comment")); }
+        // After the leading APPn segments, so JFIF/EXIF stay first
+        let insert_at = segments(data)?.into_iter()
+            .take_while(|s| (0xE0..=0xEF).contains(&s.2))
+            .last().map(|s| s.0 + s.1).unwrap_or(2);
+
+        let mut out = Vec::with_capacity(data.len() + len + 2);
+        out.extend_from_slice(&data[..insert_at]);
+        out.extend_from_slice(&[0xFF, COM]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.extend_from_slice(TAG);
+        out.extend_from_slice(blob);
+        out.extend_from_slice(&data[insert_at..]);
+        Ok(out)
+    }
+
+    pub fn find(data: &[u8]) -> anyhow::Result<Option<Embedded>> {
+        Ok(segments(data)?.into_iter()
+            .find(|&(offset, len, marker)| marker == COM && data[offset + 4..offset + len].starts_with(TAG))
+            .map(|(offset, len, _)| Embedded {
+                blob: data[offset + 4 + TAG.len()..offset + len].to_vec(),
+                offset: offset as u64,
+                len: len as u64,
+            }))
+    }
+}
+
+mod bmff {
+    use super::*;
+
+    const USER_TYPE: &[u8; 16] = b"endecode-wm-v1\0\0";
+
+    struct BoxHeader {
+        offset: u64,
+        size: u64,
+        header_len: u64,
+        ty: [u8; 4],
+    }
+
+    fn boxes(f: &mut File) -> anyhow::Result<Vec<BoxHeader>> {
+        let file_len = f.metadata()?.len();
+        let mut out = Vec::new();
+        let mut pos = 0u64;
+        while pos + 8 <= file_len {
+            let mut head = [0u8; 8];
+            f.seek(SeekFrom::Start(pos))?;
+            f.read_exact(&mut head)?;
+            let ty: [u8; 4] = head[4..8].try_into().unwrap();
+            let (size, header_len) = match u32::from_be_bytes(head[0..4].try_into().unwrap()) {
+                0 => (file_len - pos, 8),
+                1 => {
+                    let mut large = [0u8; 8];
+                    f.read_exact(&mut large)?;
+                    (u64::from_be_bytes(large), 16)
+                }
+                n => (n as u64, 8),
+            };
+            if size < header_len || pos + size > file_len {
+                return Err(anyhow!("Malformed box at offset {}", pos));
+            }
+            out.push(BoxHeader { offset: pos, size, header_len, ty });
+            pos += size;
+        }
+        if pos != file_len { return Err(anyhow!("Trailing bytes after last box")); }
+        Ok(out)
+    }
+
+    /// Appending only works if the last box has an explicit size.
+    pub fn check_appendable(f: &mut File) -> anyhow::Result<()> {
+        let list = boxes(f)?;
+        if !list.iter().any(|b| &b.ty == b"ftyp" || &b.ty == b"moov") {
+            return Err(anyhow!("Not an MP4/MOV file"));
+        }
+        let mut head = [0u8; 4];
+        if let Some(last) = list.last() {
+            f.seek(SeekFrom::Start(last.offset))?;
+            f.read_exact(&mut head)?;
+            if u32::from_be_bytes(head) == 0 {
+                return Err(anyhow!("Last box extends to end of file, cannot append"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn uuid_box(blob: &[u8]) -> Vec<u8> {
+        let size = 8 + USER_TYPE.len() + blob.len();
+        let mut out = Vec::with_capacity(size);
+        out.extend_from_slice(&(size as u32).to_be_bytes());
+        out.extend_from_slice(b"uuid");
+        out.extend_from_slice(USER_TYPE);
+        out.extend_from_slice(blob);
+        out
+    }
+
+    pub fn find(f: &mut File) -> anyhow::Result<Option<Embedded>> {
+        for b in boxes(f)? {
+            if &b.ty != b"uuid" || b.size < b.header_len + 16 || b.size > 1 << 20 { continue; }
+            let mut body = vec![0u8; (b.size - b.header_len) as usize];
+            f.seek(SeekFrom::Start(b.offset + b.header_len))?;
+            f.read_exact(&mut body)?;
+            if body.starts_with(USER_TYPE) {
+                return Ok(Some(Embedded { blob: body[16..].to_vec(), offset: b.offset, len: b.size }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+mod mkv {
+    use super::*;
+
+    const EBML_HEADER: u32 = 0x1A45DFA3;
+    const SEGMENT: u32 = 0x18538067;
+    const TAGS: u32 = 0x1254C367;
+    const TAG: u32 = 0x7373;
+    const TARGETS: u32 = 0x63C0;
+    const SIMPLE_TAG: u32 = 0x67C8;
+    const TAG_NAME: u32 = 0x45A3;
+    const TAG_BINARY: u32 = 0x4485;
+    const NAME: &[u8] = b"ENDECODE_WATERMARK";
+    /// Largest Tags element we are willing to read into memory.
+    const MAX_TAGS_LEN: u64 = 1 << 20;
+
+    pub struct Segment {
+        pub size_offset: u64,
+        pub size_width: usize,
+        /// `None` for unknown-size (live) segments.
+        pub size: Option<u64>,
+        data_start: u64,
+    }
+
+    fn read_id(f: &mut impl Read) -> std::io::Result<u32> {
+        let mut first = [0u8; 1];
+        f.read_exact(&mut first)?;
+        let width = first[0].leading_zeros() as usize + 1;
+        if width > 4 { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad EBML id")); }
+        let mut id = first[0] as u32;
+        for _ in 1..width {
+            f.read_exact(&mut first)?;
+            id = (id << 8) | first[0] as u32;
+        }
+        Ok(id)
+    }
+
+    /// Returns (value, width); value is `None` when all bits are set (unknown size).
+    fn read_size(f: &mut impl Read) -> std::io::Result<(Option<u64>, usize)> {
+        let mut first = [0u8; 1];
+        f.read_exact(&mut first)?;
+        let width = first[0].leading_zeros() as usize + 1;
+        if width > 8 { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad EBML size")); }
+        let mut value = (first[0] as u64) & (0xFF >> width);
+        for _ in 1..width {
+            f.read_exact(&mut first)?;
+            value = (value << 8) | first[0] as u64;
+        }
+        let unknown = value == (1u64 << (7 * width)) - 1;
+        Ok((if unknown { None } else { Some(value) }, width))
+    }
+
+    pub fn encode_size(value: u64, width: usize) -> anyhow::Result<Vec<u8>> {
+        if value >= (1u64 << (7 * width)) - 1 {
+            return Err(anyhow!("Segment size {} does not fit in {} bytes", value, width));
+        }
+        let mut out = value.to_be_bytes()[8 - width..].to_vec();
+        out[0] |= 0x80 >> (width - 1);
+        Ok(out)
+    }
+
+    pub fn segment(f: &mut File) -> anyhow::Result<Segment> {
+        let file_len = f.metadata()?.len();
+        f.seek(SeekFrom::Start(0))?;
+        if read_id(f)? != EBML_HEADER { return Err(anyhow!("Not a Matroska file")); }
+        let (header_size, _) = read_size(f)?;
+        f.seek(SeekFrom::Current(header_size.ok_or_else(|| anyhow!("Unknown EBML header size"))? as i64))?;
+        if read_id(f)? != SEGMENT { return Err(anyhow!("Matroska segment not found")); }
+        let size_offset = f.stream_position()?;
+        let (size, size_width) = read_size(f)?;
+        let data_start = f.stream_position()?;
+        if let Some(size) = size {
+            if data_start + size != file_len {
+                return Err(anyhow!("Matroska segment does not end at end of file"));
+            }
+        }
+        Ok(Segment { size_offset, size_width, size, data_start })
+    }
+
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let skip = id_bytes.iter().take_while(|b| **b == 0).count();
+        let mut out = id_bytes[skip..].to_vec();
+        out.extend_from_slice(&encode_size(data.len() as u64, 8).expect("EBML element too large"));
+        out.extend_from_slice(data);
+        out
+    }
+
+    pub fn tags_element(blob: &[u8]) -> Vec<u8> {
+        let simple = [element(TAG_NAME, NAME), element(TAG_BINARY, blob)].concat();
+        let tag = [element(TARGETS, &[]), element(SIMPLE_TAG, &simple)].concat();
+        element(TAGS, &element(TAG, &tag))
+    }
+
+    /// Children of an in-memory master element as (id, data).
+    fn children(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let mut cursor = std::io::Cursor::new(data);
+            let (Ok(id), Ok((Some(size), _))) = (read_id(&mut cursor), read_size(&mut cursor)) else { break };
+            let start = cursor.position() as usize;
+            let Some(end) = start.checked_add(size as usize).filter(|e| *e <= data.len()) else { break };
+            out.push((id, &data[start..end]));
+            data = &data[end..];
+        }
+        out
+    }
+
+    fn our_blob(tags: &[u8]) -> Option<Vec<u8>> {
+        for (_, tag) in children(tags).into_iter().filter(|c| c.0 == TAG) {
+            for (_, simple) in children(tag).into_iter().filter(|c| c.0 == SIMPLE_TAG) {
+                let fields = children(simple);
+                if fields.iter().any(|&(id, v)| id == TAG_NAME && v == NAME) {
+                    return fields.into_iter().find(|c| c.0 == TAG_BINARY).map(|c| c.1.to_vec());
+                }
+            }
+        }
+        None
+    }
+
+    /// Fails when an element of the segment has unknown size (live or streamed
+    /// clusters): the elements after it can't be found, so neither could Tags
+    /// appended there.
+    pub fn check_appendable(f: &mut File) -> anyhow::Result<()> {
+        let seg = segment(f)?;
+        let end = f.metadata()?.len();
+        let mut pos = seg.data_start;
+        while pos < end {
+            f.seek(SeekFrom::Start(pos))?;
+            let id = read_id(f)?;
+            let Some(size) = read_size(f)?.0 else {
+                return Err(anyhow!("Matroska element {:X} has unknown size, cannot append", id));
+            };
+            pos = f.stream_position()? + size;
+        }
+        Ok(())
+    }
+
+    pub fn find(f: &mut File) -> anyhow::Result<Option<Embedded>> {
+        let seg = segment(f)?;
+        let end = f.metadata()?.len();
+        let mut pos = seg.data_start;
+        while pos < end {
+            f.seek(SeekFrom::Start(pos))?;
+            let id = read_id(f)?;
+            // Unknown-size children (live clusters) can't be skipped
+            let Some(size) = read_size(f)?.0 else { return Ok(None) };
+            let data_start = f.stream_position()?;
+            if id == TAGS && size <= MAX_TAGS_LEN {
+                let mut data = vec![0u8; size as usize];
+                f.read_exact(&mut data)?;
+                if let Some(blob) = our_blob(&data) {
+                    return Ok(Some(Embedded { blob, offset: pos, len: data_start + size - pos }));
+                }
+            }
+            pos = data_start + size;
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,239 @@
+// Reed-Solomon error correction over GF(2^8) for watermark payloads, so a marker
+// that comes back with a few wrong bytes can be repaired instead of rejected.
+//
+// Data is split into chunks of at most `CHUNK` bytes; every chunk gets `PARITY`
+// parity bytes (systematic code, generator roots alpha^0 .. alpha^(PARITY-1)) and
+// can lose up to `PARITY / 2` bytes anywhere in data or parity. Decoding is the
+// textbook syndromes / Berlekamp-Massey / Chien search / Forney sequence.
+
+pub const PARITY: usize = 16;
+/// Data bytes per chunk, so a chunk plus parity fits the 255-symbol code.
+pub const CHUNK: usize = 255 - PARITY;
+
+const PRIMITIVE: u16 = 0x11d;
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 { x ^= PRIMITIVE; }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+const EXP: [u8; 512] = tables().0;
+const LOG: [u8; 256] = tables().1;
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 { return 0; }
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 { return 0; }
+    EXP[(LOG[a as usize] as usize + 255 - LOG[b as usize] as usize) % 255]
+}
+
+fn alpha_pow(e: usize) -> u8 {
+    EXP[e % 255]
+}
+
+/// Evaluate a polynomial stored highest coefficient first.
+fn eval_high_first(poly: &[u8], x: u8) -> u8 {
+    poly.iter().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// Evaluate a polynomial stored lowest coefficient first.
+fn eval_low_first(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// Generator polynomial (x - alpha^0)..(x - alpha^(PARITY-1)), highest first.
+fn generator() -> [u8; PARITY + 1] {
+    let mut g = [0u8; PARITY + 1];
+    g[0] = 1;
+    for i in 0..PARITY {
+        let root = alpha_pow(i);
+        // Multiply by (x + root): degree grows from i to i + 1
+        for j in (1..=i + 1).rev() {
+            g[j] ^= mul(g[j - 1], root);
+        }
+    }
+    g
+}
+
+fn chunk_parity(data: &[u8], gen: &[u8; PARITY + 1]) -> [u8; PARITY] {
+    let mut rem = [0u8; PARITY];
+    for &byte in data {
+        let factor = byte ^ rem[0];
+        rem.copy_within(1.., 0);
+        rem[PARITY - 1] = 0;
+        for j in 0..PARITY { rem[j] ^= mul(gen[j + 1], factor); }
+    }
+    rem
+}
+
+pub fn parity_len(data_len: usize) -> usize {
+    data_len.div_ceil(CHUNK) * PARITY
+}
+
+/// Parity bytes for `data`, `parity_len(data.len())` long.
+pub fn parity(data: &[u8]) -> Vec<u8> {
+    let gen = generator();
+    data.chunks(CHUNK).flat_map(|c| chunk_parity(c, &gen)).collect()
+}
+
+/// Repair one codeword (data followed by its parity) in place; the number of
+/// corrected bytes, or `None` if there are more errors than the code can fix.
+fn correct_codeword(cw: &mut [u8]) -> Option<usize> {
+    let n = cw.len();
+    let synd: Vec<u8> = (0..PARITY).map(|j| eval_high_first(cw, alpha_pow(j))).collect();
+    if synd.iter().all(|&s| s == 0) { return Some(0); }
+
+    // Berlekamp-Massey: error locator, lowest coefficient first
+    let mut lambda = vec![1u8];
+    let mut prev = vec![1u8];
+    let (mut len, mut shift, mut prev_d) = (0usize, 1usize, 1u8);
+    for r in 0..PARITY {
+        let mut d = synd[r];
+        for (i, &l) in lambda.iter().enumerate().take(r + 1).skip(1) { d ^= mul(l, synd[r - i]); }
+        if d == 0 {
+            shift += 1;
+            continue;
+        }
+        let scale = div(d, prev_d);
+        let mut next = lambda.clone();
+        next.resize(next.len().max(prev.len() + shift), 0);
+        for (i, &p) in prev.iter().enumerate() { next[i + shift] ^= mul(scale, p); }
+        if 2 * len <= r {
+            prev = std::mem::replace(&mut lambda, next);
+            len = r + 1 - len;
+            prev_d = d;
+            shift = 1;
+        } else {
+            lambda = next;
+            shift += 1;
+        }
+    }
+    lambda.truncate(len + 1);
+    if 2 * len > PARITY { return None; }
+
+    // Chien search: position k holds the coefficient of x^(n-1-k), locator alpha^(n-1-k)
+    let positions: Vec<usize> = (0..n)
+        .filter(|&k| eval_low_first(&lambda, alpha_pow(255 - (n - 1 - k) % 255)) == 0)
+        .collect();
+    if positions.len() != len { return None; }
+
+    // Forney: e = X * omega(X^-1) / lambda'(X^-1), omega = S * lambda mod x^PARITY
+    let mut omega = vec![0u8; PARITY];
+    for (i, &l) in lambda.iter().enumerate() {
+        for (j, &s) in synd.iter().enumerate() {
+            if i + j < PARITY { omega[i + j] ^= mul(l, s); }
+        }
+    }
+    let derivative: Vec<u8> = lambda.iter().enumerate().skip(1)
+        .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+        .collect();
+    for &k in &positions {
+        let x = alpha_pow(n - 1 - k);
+        let x_inv = alpha_pow(255 - (n - 1 - k) % 255);
+        let denom = eval_low_first(&derivative, x_inv);
+        if denom == 0 { return None; }
+        cw[k] ^= mul(x, div(eval_low_first(&omega, x_inv), denom));
+    }
+
+    let clean = (0..PARITY).all(|j| eval_high_first(cw, alpha_pow(j)) == 0);
+    clean.then_some(positions.len())
+}
+
+/// Repair `data` using `parity` from [`parity`]. Returns how many bytes were
+/// wrong, or `None` if some chunk is beyond repair (`data` may then be partly fixed).
+pub fn correct(data: &mut [u8], parity: &[u8]) -> Option<usize> {
+    if parity.len() != parity_len(data.len()) { return None; }
+    let mut corrected = 0;
+    for (chunk, par) in data.chunks_mut(CHUNK).zip(parity.chunks(PARITY)) {
+        let mut cw = chunk.to_vec();
+        cw.extend_from_slice(par);
+        corrected += correct_codeword(&mut cw)?;
+        chunk.copy_from_slice(&cw[..chunk.len()]);
+    }
+    Some(corrected)
+}
+
+/// How many wrong bytes `correct` can repair in the worst case for `data_len` bytes.
+pub fn correctable(data_len: usize) -> usize {
+    data_len.div_ceil(CHUNK) * (PARITY / 2)
+}
+
+/// 1 for data that needed no repair, falling to 0 as `corrected` approaches the
+/// `correctable` bytes its parity can fix.
+pub fn confidence(corrected: usize, correctable: usize) -> f32 {
+    1.0 - corrected as f32 / correctable as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + 7) as u8).collect()
+    }
+
+    #[test]
+    fn every_chunk_gets_its_parity() {
+        for (len, chunks) in [(1, 1), (CHUNK, 1), (CHUNK + 1, 2), (600, 3)] {
+            assert_eq!(parity(&sample(len)).len(), chunks * PARITY, "{} bytes", len);
+            assert_eq!(parity_len(len), chunks * PARITY);
+            assert_eq!(correctable(len), chunks * PARITY / 2);
+        }
+        let mut data = sample(600);
+        let par = parity(&data);
+        assert_eq!(correct(&mut data, &par), Some(0));
+        assert_eq!(confidence(0, correctable(600)), 1.0);
+        assert_eq!(correct(&mut data, &par[..PARITY]), None, "parity of another length");
+    }
+
+    #[test]
+    fn errors_in_data_and_parity_are_repaired_and_counted() {
+        let original = sample(600);
+        let mut par = parity(&original);
+        let mut data = original.clone();
+        // Five in the first chunk's data and three in its parity, two in the last chunk
+        for i in [0, 17, 100, 200, CHUNK - 1] { data[i] ^= 0xFF; }
+        for i in [0, 7, PARITY - 1] { par[i] ^= 0x33; }
+        for i in [500, 599] { data[i] ^= 0x01; }
+        assert_eq!(correct(&mut data, &par), Some(10));
+        assert_eq!(data, original);
+        let c = confidence(10, correctable(600));
+        assert!(c > 0.0 && c < 1.0, "{}", c);
+    }
+
+    #[test]
+    fn each_chunk_repairs_up_to_half_its_parity() {
+        let original = sample(600);
+        let par = parity(&original);
+        // The worst case `correctable` allows for: PARITY / 2 errors in every chunk
+        let mut data = original.clone();
+        for chunk in 0..3 {
+            for k in 0..PARITY / 2 { data[chunk * CHUNK + k * 9] ^= 0x5A; }
+        }
+        assert_eq!(correct(&mut data, &par), Some(correctable(600)));
+        assert_eq!(data, original);
+        assert_eq!(confidence(correctable(600), correctable(600)), 0.0);
+
+        // One more in a chunk is beyond repair, however few the others have
+        let mut data = original.clone();
+        for k in 0..=PARITY / 2 { data[CHUNK + k * 9] ^= 0x5A; }
+        assert_eq!(correct(&mut data, &par), None);
+    }
+}
@@ -0,0 +1,401 @@
+// Invisible pixel-domain watermark that survives re-compression, resizing and
+// mild crops, for leaks where the file itself (and with it any tail marker or
+// metadata) is gone.
+//
+// The image is split into a CHIPS x CHIPS grid of chips, made of a TILE x TILE
+// tile repeated REPEAT times along each axis. Every tile chip carries one payload
+// bit (order number plus a check value) under a keyed +-1 spreading sign, and the
+// mark is a smooth bilinear surface through the chip amplitudes added to the
+// pixels. What is read is the mid-frequency band around chip size: luma minus its
+// local mean. Embedding is informed: amplitudes are raised, chip by chip, until
+// that band has the wanted sign with some margin, so busy areas get a stronger
+// (and masked) mark while flat ones stay untouched beyond the minimum.
+//
+// Because the tile repeats, a cropped or rescaled copy still shows the tile
+// period, which the detector finds by autocorrelation. Folding the picture onto
+// one period averages all repeats into a single tile, and the crop offset is then
+// a cyclic shift found by trying every phase. The check value rejects wrong guesses.
+use anyhow::anyhow;
+use image::{DynamicImage, ImageBuffer, Pixel, RgbaImage};
+use sha2::{Digest, Sha256};
+
+use crate::keyed::WatermarkKey;
+use crate::visible::{Canvas, Channel};
+
+const TILE: usize = 16;
+const REPEAT: usize = 4;
+const CHIPS: usize = TILE * REPEAT;
+const TILE_CHIPS: usize = TILE * TILE;
+/// Fold resolution per chip, the step of the phase search.
+const CELLS: usize = 4;
+const FOLD: usize = TILE * CELLS;
+const VALUE_BITS: usize = 20;
+const CHECK_BITS: usize = 12;
+const BITS: usize = VALUE_BITS + CHECK_BITS;
+pub const MAX_VALUE: u32 = (1 << VALUE_BITS) - 1;
+/// Minimum reading of every chip at strength 1.0, in luma levels.
+const TARGET: f32 = 2.0;
+/// Largest chip amplitude at strength 1.0.
+const CAP: f32 = 8.0;
+const PASSES: usize = 6;
+/// Longest side of the luma plane analysis runs on.
+const PREPARED_MAX: usize = 512;
+/// Smallest share of each side a crop may keep and still be searched for.
+const MIN_SCALE: f32 = 0.85;
+/// Smallest image side that holds a readable mark.
+pub const MIN_SIDE: u32 = 200;
+/// Mean per-bit z-score a reading needs; unmarked images stay around 1.2.
+const MIN_ENERGY: f32 = 1.5;
+
+pub struct Detection {
+    pub value: u32,
+    /// Share of payload bits read with a clear margin: 1 for an untouched mark,
+    /// falling as it degrades.
+    pub confidence: f32,
+}
+
+/// Spreading seed for a watermark key, so marks can only be read with the key
+/// that made them.
+pub fn seed(key: &WatermarkKey) -> u64 {
+    let digest = Sha256::new().chain_update(b"endecode-invisible-v1").chain_update(key).finalize();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Keyed layout: which bit each tile chip carries and its spreading sign.
+struct Layout {
+    bit: Vec<usize>,
+    chip: Vec<f32>,
+}
+
+impl Layout {
+    fn new(seed: u64) -> Self {
+        let mut rng = SplitMix(seed);
+        let mut order: Vec<usize> = (0..TILE_CHIPS).collect();
+        for i in (1..TILE_CHIPS).rev() {
+            let j = (rng.next() % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+        let mut bit = vec![0; TILE_CHIPS];
+        for (i, &k) in order.iter().enumerate() { bit[k] = i % BITS; }
+        let chip = (0..TILE_CHIPS).map(|_| if rng.next() & 1 == 0 { 1.0 } else { -1.0 }).collect();
+        Layout { bit, chip }
+    }
+}
+
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn payload_bits(value: u32) -> [bool; BITS] {
+    let check = crc32fast::hash(&value.to_le_bytes()) & ((1 << CHECK_BITS) - 1);
+    let mut bits = [false; BITS];
+    for (i, b) in bits.iter_mut().enumerate() {
+        *b = if i < VALUE_BITS { value >> i & 1 == 1 } else { check >> (i - VALUE_BITS) & 1 == 1 };
+    }
+    bits
+}
+
+struct Plane {
+    w: usize,
+    h: usize,
+    data: Vec<f32>,
+}
+
+fn luma(p: &image::Rgba<u8>) -> f32 {
+    0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+}
+
+/// Luma box-averaged down to at most `PREPARED_MAX` on the long side.
+fn prepare(img: &RgbaImage) -> Plane {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let f = w.max(h).div_ceil(PREPARED_MAX).max(1);
+    let (pw, ph) = (w / f, h / f);
+    let mut data = vec![0f32; pw * ph];
+    for py in 0..ph {
+        for px in 0..pw {
+            let mut sum = 0.0;
+            for y in py * f..py * f + f {
+                for x in px * f..px * f + f { sum += luma(img.get_pixel(x as u32, y as u32)); }
+            }
+            data[py * pw + px] = sum / (f * f) as f32;
+        }
+    }
+    Plane { w: pw, h: ph, data }
+}
+
+fn box_blur(p: &Plane, r: usize) -> Vec<f32> {
+    let (w, h) = (p.w, p.h);
+    let mut tmp = vec![0f32; w * h];
+    let mut pre = vec![0f32; w.max(h) + 1];
+    for y in 0..h {
+        for x in 0..w { pre[x + 1] = pre[x] + p.data[y * w + x]; }
+        for x in 0..w {
+            let (a, b) = (x.saturating_sub(r), (x + r + 1).min(w));
+            tmp[y * w + x] = (pre[b] - pre[a]) / (b - a) as f32;
+        }
+    }
+    let mut out = vec![0f32; w * h];
+    for x in 0..w {
+        for y in 0..h { pre[y + 1] = pre[y] + tmp[y * w + x]; }
+        for y in 0..h {
+            let (a, b) = (y.saturating_sub(r), (y + r + 1).min(h));
+            out[y * w + x] = (pre[b] - pre[a]) / (b - a) as f32;
+        }
+    }
+    out
+}
+
+/// The band the mark lives in: luma minus its local mean over about one chip.
+fn band(plane: &Plane) -> Vec<f32> {
+    let blur = box_blur(plane, (plane.w.max(plane.h) / CHIPS).max(1));
+    plane.data.iter().zip(&blur).map(|(a, b)| a - b).collect()
+}
+
+/// Mark value at a position in 0..1 units, interpolated between chip centres.
+fn surface(amp: &[f32], fx: f32, fy: f32) -> f32 {
+    let fx = fx * CHIPS as f32 - 0.5;
+    let fy = fy * CHIPS as f32 - 0.5;
+    let (i0, j0) = (fx.floor() as isize, fy.floor() as isize);
+    let (tx, ty) = (fx - i0 as f32, fy - j0 as f32);
+    let last = CHIPS as isize - 1;
+    let at = |i: isize, j: isize| amp[j.clamp(0, last) as usize * CHIPS + i.clamp(0, last) as usize];
+    (at(i0, j0) * (1.0 - tx) + at(i0 + 1, j0) * tx) * (1.0 - ty)
+        + (at(i0, j0 + 1) * (1.0 - tx) + at(i0 + 1, j0 + 1) * tx) * ty
+}
+
+/// Embed `value` into `img` in place, in the picture's own depth. `strength`
+/// scales the mark, 1.0 is the default.
+pub fn embed(img: &mut Canvas, value: u32, seed: u64, strength: f32) -> anyhow::Result<()> {
+    let amp = match img {
+        Canvas::Rgba8(img) => amplitudes(img, value, seed, strength)?,
+        Canvas::Rgba16(img) => amplitudes(&DynamicImage::ImageRgba16(img.clone()).into_rgba8(), value, seed, strength)?,
+        Canvas::Rgba32F(img) => amplitudes(&DynamicImage::ImageRgba32F(img.clone()).into_rgba8(), value, seed, strength)?,
+    };
+    match img {
+        Canvas::Rgba8(img) => add_surface(img, &amp),
+        Canvas::Rgba16(img) => add_surface(img, &amp),
+        Canvas::Rgba32F(img) => add_surface(img, &amp),
+    }
+    Ok(())
+}
+
+/// Chip amplitudes that carry `value` in `img`, analysed at 8 bits.
+fn amplitudes(img: &RgbaImage, value: u32, seed: u64, strength: f32) -> anyhow::Result<Vec<f32>> {
+    if value > MAX_VALUE {
+        return Err(anyhow!("Order number {} too large for the invisible watermark (max {})", value, MAX_VALUE));
+    }
+    if img.width().min(img.height()) < MIN_SIDE {
+        return Err(anyhow!("Image too small for the invisible watermark (min {} px per side)", MIN_SIDE));
+    }
+    let layout = Layout::new(seed);
+    let bits = payload_bits(value);
+    let (target, cap) = (TARGET * strength, CAP * strength);
+    let sign: Vec<f32> = (0..CHIPS * CHIPS).map(|n| {
+        let k = (n / CHIPS % TILE) * TILE + n % CHIPS % TILE;
+        if bits[layout.bit[k]] { layout.chip[k] } else { -layout.chip[k] }
+    }).collect();
+
+    // Raise each chip until the band under its centre reads right
+    let plane = prepare(img);
+    let (pw, ph) = (plane.w, plane.h);
+    let mut amp: Vec<f32> = sign.iter().map(|s| s * target).collect();
+    for _ in 0..PASSES {
+        let mut marked = Plane { w: pw, h: ph, data: plane.data.clone() };
+        for y in 0..ph {
+            for x in 0..pw {
+                marked.data[y * pw + x] += surface(&amp, (x as f32 + 0.5) / pw as f32, (y as f32 + 0.5) / ph as f32);
+            }
+        }
+        let r = band(&marked);
+        let mut sum = vec![0f32; CHIPS * CHIPS];
+        let mut count = vec![0f32; CHIPS * CHIPS];
+        for y in 0..ph {
+            let fy = (y as f32 + 0.5) / ph as f32 * CHIPS as f32;
+            if (fy.fract() - 0.5).abs() > 0.25 { continue; }
+            for x in 0..pw {
+                let fx = (x as f32 + 0.5) / pw as f32 * CHIPS as f32;
+                if (fx.fract() - 0.5).abs() > 0.25 { continue; }
+                let n = (fy as usize).min(CHIPS - 1) * CHIPS + (fx as usize).min(CHIPS - 1);
+                sum[n] += r[y * pw + x];
+                count[n] += 1.0;
+            }
+        }
+        for n in 0..CHIPS * CHIPS {
+            if count[n] == 0.0 { continue; }
+            let got = sign[n] * sum[n] / count[n];
+            if got < target { amp[n] = (amp[n] + sign[n] * (target - got) * 0.8).clamp(-cap, cap); }
+        }
+    }
+
+    Ok(amp)
+}
+
+/// Add the mark surface to the colour channels; `amp` is in 8-bit levels.
+fn add_surface<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, amp: &[f32])
+where
+    P::Subpixel: Channel,
+{
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    for (x, y, p) in img.enumerate_pixels_mut() {
+        let d = surface(amp, (x as f32 + 0.5) / w, (y as f32 + 0.5) / h) / 255.0;
+        for c in &mut p.channels_mut()[..3] {
+            *c = P::Subpixel::from_unit(c.to_unit() + d);
+        }
+    }
+}
+
+/// Autocorrelation of the band at `lag` along one axis.
+fn autocorr(r: &[f32], w: usize, h: usize, horizontal: bool, lag: usize) -> f32 {
+    let mut s = 0.0;
+    let mut n = 0.0;
+    if horizontal {
+        for y in 0..h {
+            for x in 0..w.saturating_sub(lag) { s += r[y * w + x] * r[y * w + x + lag]; n += 1.0; }
+        }
+    } else {
+        for y in 0..h.saturating_sub(lag) {
+            for x in 0..w { s += r[y * w + x] * r[(y + lag) * w + x]; n += 1.0; }
+        }
+    }
+    if n > 0.0 { s / n } else { 0.0 }
+}
+
+/// Candidate tile periods along one axis, in plane pixels: the uncropped period
+/// first, then the strongest autocorrelation peaks a crop could explain.
+fn periods(r: &[f32], w: usize, h: usize, horizontal: bool) -> Vec<f32> {
+    let len = if horizontal { w } else { h };
+    let base = len as f32 / REPEAT as f32;
+    let lo = ((base * 0.98).floor() as usize).max(2);
+    let hi = (base / MIN_SCALE).ceil() as usize;
+    // Every repeat of the tile adds to the peak, the host's self-similarity mostly doesn't
+    let score: Vec<f32> = (0..=hi + 1)
+        .map(|lag| if lag + 1 < lo { 0.0 } else { (1..REPEAT).map(|m| autocorr(r, w, h, horizontal, lag * m)).sum() })
+        .collect();
+    let mut peaks: Vec<(f32, f32)> = Vec::new();
+    for lag in lo..=hi {
+        let (a, b, c) = (score[lag - 1], score[lag], score[lag + 1]);
+        if b >= a && b >= c && b > 0.0 {
+            let den = a - 2.0 * b + c;
+            let off = if den < 0.0 { 0.5 * (a - c) / den } else { 0.0 };
+            peaks.push((b, lag as f32 + off));
+        }
+    }
+    peaks.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut out = vec![base];
+    out.extend(peaks.into_iter().take(3).map(|p| p.1).filter(|p| (p - base).abs() > 0.5));
+    out
+}
+
+/// Average the band onto one tile period, FOLD x FOLD cells.
+fn fold(r: &[f32], w: usize, h: usize, px: f32, py: f32) -> Vec<f32> {
+    let mut sum = vec![0f32; FOLD * FOLD];
+    let mut count = vec![0f32; FOLD * FOLD];
+    for y in 0..h {
+        let cy = (((y as f32 + 0.5) / py).fract() * FOLD as f32) as usize % FOLD;
+        for x in 0..w {
+            let cx = (((x as f32 + 0.5) / px).fract() * FOLD as f32) as usize % FOLD;
+            sum[cy * FOLD + cx] += r[y * w + x];
+            count[cy * FOLD + cx] += 1.0;
+        }
+    }
+    sum.iter().zip(&count).map(|(s, n)| if *n > 0.0 { s / n } else { 0.0 }).collect()
+}
+
+struct Reading {
+    bits: [bool; BITS],
+    z: [f32; BITS],
+    /// Mean |z| over the bits.
+    energy: f32,
+}
+
+/// Read the folded tile assuming chip (0, 0) starts at cell (`ax`, `ay`).
+fn read(cells: &[f32], ax: usize, ay: usize, layout: &Layout) -> Reading {
+    let mut chips = [0f32; TILE_CHIPS];
+    for j in 0..TILE {
+        for i in 0..TILE {
+            // Centre cells only, the edges blend into the neighbours
+            let mut s = 0.0;
+            for dy in 1..CELLS - 1 {
+                for dx in 1..CELLS - 1 {
+                    s += cells[((j * CELLS + dy + ay) % FOLD) * FOLD + (i * CELLS + dx + ax) % FOLD];
+                }
+            }
+            chips[j * TILE + i] = s;
+        }
+    }
+    let mean = chips.iter().sum::<f32>() / TILE_CHIPS as f32;
+    let sd = (chips.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / TILE_CHIPS as f32).sqrt().max(1e-6);
+    let mut sum = [0f32; BITS];
+    let mut n = [0f32; BITS];
+    for k in 0..TILE_CHIPS {
+        sum[layout.bit[k]] += layout.chip[k] * (chips[k] - mean) / sd;
+        n[layout.bit[k]] += 1.0;
+    }
+    let mut reading = Reading { bits: [false; BITS], z: [0.0; BITS], energy: 0.0 };
+    for b in 0..BITS {
+        reading.z[b] = sum[b] / n[b].sqrt();
+        reading.bits[b] = sum[b] > 0.0;
+        reading.energy += reading.z[b].abs() / BITS as f32;
+    }
+    reading
+}
+
+fn decode(reading: &Reading) -> Option<Detection> {
+    if reading.energy < MIN_ENERGY { return None; }
+    let mut value = 0u32;
+    let mut check = 0u32;
+    for (b, &set) in reading.bits.iter().enumerate() {
+        if !set { continue; }
+        if b < VALUE_BITS { value |= 1 << b } else { check |= 1 << (b - VALUE_BITS) }
+    }
+    if crc32fast::hash(&value.to_le_bytes()) & ((1 << CHECK_BITS) - 1) != check { return None; }
+    let clear = reading.z.iter().filter(|z| z.abs() >= 2.0).count();
+    Some(Detection { value, confidence: clear as f32 / BITS as f32 })
+}
+
+/// Look for a watermark made with `seed`.
+pub fn detect(img: &RgbaImage, seed: u64) -> Option<Detection> {
+    let layout = Layout::new(seed);
+    let plane = prepare(img);
+    if plane.w < CHIPS || plane.h < CHIPS { return None; }
+    // Signs only: the mark sets the sign of the band under every chip, while the
+    // host's strongest edges would otherwise dominate the statistics
+    let r: Vec<f32> = band(&plane).iter().map(|v| v.signum()).collect();
+
+    let mut best: Option<(f32, f32, f32, usize, usize)> = None;
+    for &px in &periods(&r, plane.w, plane.h, true) {
+        for &py in &periods(&r, plane.w, plane.h, false) {
+            let cells = fold(&r, plane.w, plane.h, px, py);
+            for ay in 0..FOLD {
+                for ax in 0..FOLD {
+                    let e = read(&cells, ax, ay, &layout).energy;
+                    if best.is_none_or(|b| e > b.0) { best = Some((e, px, py, ax, ay)); }
+                }
+            }
+        }
+    }
+
+    // Polish the period, which the autocorrelation only finds to about a pixel
+    let (_, px, py, ax, ay) = best?;
+    let mut polished: Option<Reading> = None;
+    for sx in [-0.004f32, -0.002, 0.0, 0.002, 0.004] {
+        for sy in [-0.004f32, -0.002, 0.0, 0.002, 0.004] {
+            let cells = fold(&r, plane.w, plane.h, px * (1.0 + sx), py * (1.0 + sy));
+            for day in [FOLD - 1, 0, 1] {
+                for dax in [FOLD - 1, 0, 1] {
+                    let reading = read(&cells, (ax + dax) % FOLD, (ay + day) % FOLD, &layout);
+                    if polished.as_ref().is_none_or(|p| reading.energy > p.energy) { polished = Some(reading); }
+                }
+            }
+        }
+    }
+    decode(&polished?)
+}
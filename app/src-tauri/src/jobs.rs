@@ -0,0 +1,126 @@
+// Registry of background batch jobs. A job runs on its own thread under an ID;
+// the registry keeps its status (fed from the events the job emits) after it has
+// finished, and holds the flag `cancel_job` raises to make it stop.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{BatchEvent, BatchPhase};
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct JobStatus {
+    pub id: u64,
+    pub source: String,
+    pub state: JobState,
+    pub copies: usize,
+    /// Copy (from 1) the job is working on or stopped in; 0 before the first.
+    pub copy: usize,
+    pub phase: Option<BatchPhase>,
+    pub error: Option<String>,
+}
+
+pub struct Job {
+    pub id: u64,
+    cancel: AtomicBool,
+    status: Mutex<JobStatus>,
+}
+
+impl Job {
+    /// Raised by `cancel`; the job checks it between files.
+    pub fn cancel_flag(&self) -> &AtomicBool {
+        &self.cancel
+    }
+
+    /// Ask the job to stop. False if it has already finished.
+    pub fn cancel(&self) -> bool {
+        let running = self.status().state == JobState::Running;
+        if running { self.cancel.store(true, Ordering::Relaxed); }
+        running
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Follow the job's progress from an event it emitted.
+    pub fn record(&self, event: &BatchEvent) {
+        let mut status = self.status.lock().unwrap();
+        match event {
+            BatchEvent::Progress { phase, copy, .. } | BatchEvent::Log { phase, copy, .. } => {
+                status.phase = Some(*phase);
+                status.copy = *copy;
+            }
+            BatchEvent::Error { phase, copy, message, .. } => {
+                (status.phase, status.copy) = (Some(*phase), *copy);
+                status.state = JobState::Failed;
+                status.error = Some(message.clone());
+            }
+            BatchEvent::Cancelled { copy, .. } => {
+                status.copy = *copy;
+                status.state = JobState::Cancelled;
+            }
+            BatchEvent::Skipped { .. } => {}
+            BatchEvent::Done { .. } => status.state = JobState::Done,
+        }
+    }
+
+    /// Settle the state of a job whose run ended without a final event, as when
+    /// the `-Copies` folder can't be created.
+    pub fn finish(&self, result: &anyhow::Result<()>) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != JobState::Running { return; }
+        match result {
+            Ok(()) => status.state = JobState::Done,
+            Err(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    last_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl Jobs {
+    /// Register a running job for a batch over `source`.
+    pub fn start(&self, source: &str, copies: usize) -> Arc<Job> {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Arc::new(Job {
+            id,
+            cancel: AtomicBool::new(false),
+            status: Mutex::new(JobStatus {
+                id,
+                source: source.to_string(),
+                state: JobState::Running,
+                copies,
+                copy: 0,
+                phase: None,
+                error: None,
+            }),
+        });
+        self.jobs.lock().unwrap().insert(id, job.clone());
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Status of every job since the app started, oldest first.
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().values().map(|job| job.status()).collect()
+    }
+}
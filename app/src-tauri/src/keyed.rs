@@ -0,0 +1,165 @@
+// Keyed watermark encoding: XChaCha20-Poly1305 under a secret key that lives
+// outside the source tree (per installation by default, per project if the
+// preferences point at another key file).
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use anyhow::anyhow;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use dirs::config_dir;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+pub type WatermarkKey = [u8; KEY_LEN];
+
+/// Key file named `name` in the app config dir.
+pub fn config_key_path(name: &str) -> anyhow::Result<PathBuf> {
+    let base = config_dir().ok_or_else(|| anyhow!("no config dir"))?.join("endecode");
+    if !base.exists() { fs::create_dir_all(&base)?; }
+    Ok(base.join(name))
+}
+
+/// Key file in use: the project key from preferences if set, else the installation key.
+pub fn key_path(project_key: Option<&str>) -> anyhow::Result<PathBuf> {
+    match project_key {
+        Some(p) if !p.trim().is_empty() => Ok(PathBuf::from(p)),
+        _ => config_key_path("watermark.key"),
+    }
+}
+
+pub fn read_key(path: &Path) -> anyhow::Result<WatermarkKey> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read key {}: {}", path.display(), e))?;
+    let bytes = STANDARD.decode(text.trim())
+        .map_err(|e| anyhow!("Key {} is not valid base64: {}", path.display(), e))?;
+    bytes.try_into().map_err(|_| anyhow!("Key {} must be {} bytes", path.display(), KEY_LEN))
+}
+
+/// Write a fresh random key to `path`, failing with `AlreadyExists` rather than
+/// overwriting a key that is there, even one created a moment ago.
+fn write_new_key(path: &Path) -> std::io::Result<WatermarkKey> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() { fs::create_dir_all(parent)?; }
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Readable by the owner only; elsewhere the file gets the folder's ACL
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    let key: WatermarkKey = XChaCha20Poly1305::generate_key(&mut OsRng).into();
+    file.write_all(STANDARD.encode(key).as_bytes())?;
+    file.sync_all()?;
+    Ok(key)
+}
+
+/// Write a fresh random key to `path`. Refuses to overwrite an existing key,
+/// since that would make every delivery marked with it undecodable.
+pub fn create_key(path: &Path) -> anyhow::Result<WatermarkKey> {
+    write_new_key(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => anyhow!("Key already exists: {}", path.display()),
+        _ => anyhow!("Failed to create key {}: {}", path.display(), e),
+    })
+}
+
+/// The key at `path`, created if there is none. When another thread or process
+/// creates it at the same time, its key is the one used.
+pub fn read_or_create_key(path: &Path) -> anyhow::Result<WatermarkKey> {
+    match write_new_key(path) {
+        Ok(key) => Ok(key),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            // The winner of a race may still be writing it
+            for _ in 0..50 {
+                if let Ok(key) = read_key(path) { return Ok(key); }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            read_key(path)
+        }
+        Err(e) => Err(anyhow!("Failed to create key {}: {}", path.display(), e)),
+    }
+}
+
+/// Key for encoding: created on first use so a fresh install can mark files right away.
+pub fn load_or_create_key(project_key: Option<&str>) -> anyhow::Result<WatermarkKey> {
+    read_or_create_key(&key_path(project_key)?)
+}
+
+/// Key for decoding: never generated, a new key could not open old markers anyway.
+pub fn load_key(project_key: Option<&str>) -> anyhow::Result<WatermarkKey> {
+    let path = key_path(project_key)?;
+    if !path.exists() {
+        return Err(anyhow!("No watermark key found at {}", path.display()));
+    }
+    read_key(&path)
+}
+
+/// Encrypt and authenticate `plain`, returning `base64url(nonce || ciphertext)`.
+pub fn seal(plain: &str, key: &WatermarkKey) -> anyhow::Result<String> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ct = cipher.encrypt(&nonce, plain.as_bytes()).map_err(|_| anyhow!("Encryption failed"))?;
+    let mut out = Vec::with_capacity(NONCE_LEN + ct.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ct);
+    Ok(URL_SAFE_NO_PAD.encode(out))
+}
+
+/// Reverse of [`seal`]. Fails if the token was forged, truncated or made with another key.
+pub fn open(token: &str, key: &WatermarkKey) -> anyhow::Result<String> {
+    let raw = URL_SAFE_NO_PAD.decode(token.trim()).map_err(|e| anyhow!("Malformed keyed marker: {}", e))?;
+    if raw.len() < NONCE_LEN { return Err(anyhow!("Malformed keyed marker: too short")); }
+    let (nonce, ct) = raw.split_at(NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let plain = cipher.decrypt(XNonce::from_slice(nonce), ct)
+        .map_err(|_| anyhow!("Keyed marker failed authentication (wrong key or tampered)"))?;
+    String::from_utf8(plain).map_err(|e| anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn key_files_are_private_to_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watermark.key");
+        create_key(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn keyed_markers_open_only_with_their_key() {
+        let (key, other) = ([7u8; 32], [8u8; 32]);
+        let token = seal("Order 042", &key).unwrap();
+        assert!(!token.contains("Order"));
+        assert_ne!(seal("Order 042", &key).unwrap(), token, "nonces must differ");
+        assert_eq!(open(&token, &key).unwrap(), "Order 042");
+
+        let err = open(&token, &other).unwrap_err();
+        assert!(err.to_string().contains("authentication"), "{}", err);
+        let mut tampered = token.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert!(open(std::str::from_utf8(&tampered).unwrap(), &key).is_err());
+        assert!(open(&token[..20], &key).is_err(), "truncated");
+    }
+
+    #[test]
+    fn racing_key_creation_agrees_on_one_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys/watermark.key");
+        let keys: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| read_or_create_key(&path).unwrap())).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(keys.iter().all(|k| *k == keys[0]));
+        assert_eq!(read_key(&path).unwrap(), keys[0]);
+        assert!(create_key(&path).unwrap_err().to_string().starts_with("Key already exists"));
+    }
+}
@@ -1,25 +1,40 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-use image::{DynamicImage, Rgba, ImageOutputFormat};
-use image::GenericImageView;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
 use anyhow::anyhow;
 use regex::Regex;
 use std::fs;
 use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 use zip::write::SimpleFileOptions;
 use zip::CompressionMethod;
-use imageproc::drawing::draw_text_mut;
-use rusttype::{Font, Scale};
+use rusttype::Font;
 use dirs::config_dir;
 
+mod atomic;
+mod batch_state;
+mod container;
+mod ecc;
+mod invisible;
+mod jobs;
+mod keyed;
+mod lsb;
+mod marker;
+mod metadata;
+mod signing;
+mod visible;
+
+use visible::{TextPosition, WatermarkStyle};
+
 const SHIFT: i32 = 7;
 const WATERMARK_PREFIX: &str = "<<==";
 const WATERMARK_SUFFIX: &str = "==>>";
 const OLD_WATERMARK_PREFIX: &str = "*/";
-const TAIL_SCAN_SIZE: usize = 100;
+const TAIL_SCAN_SIZE: usize = 256;
 
 fn shift_char(c: char, shift: i32) -> char {
     if c.is_ascii_uppercase() {
@@ -42,27 +57,124 @@ fn shift_char(c: char, shift: i32) -> char {
     }
 }
 
-fn encode_text_impl(input: &str) -> String {
+/// How the text inside a marker is encoded.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum CipherScheme {
+    /// Fixed `SHIFT` Caesar. Anyone with the source can decode it; kept for old deliveries.
+    #[default]
+    Caesar,
+    /// XChaCha20-Poly1305 under the installation (or project) key, see `keyed.rs`.
+    Keyed,
+}
+
+impl CipherScheme {
+    fn tag(self) -> char {
+        match self {
+            CipherScheme::Caesar => 'c',
+            CipherScheme::Keyed => 'k',
+        }
+    }
+
+    fn from_tag(tag: char) -> Option<Self> {
+        match tag {
+            'c' => Some(CipherScheme::Caesar),
+            'k' => Some(CipherScheme::Keyed),
+            _ => None,
+        }
+    }
+
+    /// Scheme byte in the binary trailer.
+    fn id(self) -> u8 {
+        match self {
+            CipherScheme::Caesar => 0,
+            CipherScheme::Keyed => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CipherScheme::Caesar),
+            1 => Some(CipherScheme::Keyed),
+            _ => None,
+        }
+    }
+}
+
+fn caesar_encode(input: &str) -> String {
     input.chars().map(|c| shift_char(c, SHIFT)).collect()
 }
 
-fn decode_text_impl(input: &str) -> String {
+fn caesar_decode(input: &str) -> String {
     input.chars().map(|c| shift_char(c, -SHIFT)).collect()
 }
 
+fn encode_text_impl(input: &str, scheme: CipherScheme, project_key: Option<&str>) -> anyhow::Result<String> {
+    match scheme {
+        CipherScheme::Caesar => Ok(caesar_encode(input)),
+        CipherScheme::Keyed => {
+            let key = keyed::load_or_create_key(project_key)?;
+            keyed::seal(input, &key)
+        }
+    }
+}
+
+fn decode_text_impl(input: &str, scheme: CipherScheme, project_key: Option<&str>) -> anyhow::Result<String> {
+    match scheme {
+        CipherScheme::Caesar => Ok(caesar_decode(input)),
+        CipherScheme::Keyed => {
+            let key = keyed::load_key(project_key)?;
+            keyed::open(input, &key)
+        }
+    }
+}
+
+/// Marker body: `!<tag>!<encoded>`. Bodies without the tag were written before
+/// schemes existed and are always Caesar.
+fn marker_body(text: &str, scheme: CipherScheme, project_key: Option<&str>) -> anyhow::Result<String> {
+    Ok(format!("!{}!{}", scheme.tag(), encode_text_impl(text, scheme, project_key)?))
+}
+
+fn parse_marker_body(body: &str) -> (CipherScheme, &str) {
+    let mut chars = body.chars();
+    if let (Some('!'), Some(tag), Some('!')) = (chars.next(), chars.next(), chars.next()) {
+        if let Some(scheme) = CipherScheme::from_tag(tag) {
+            return (scheme, &body[3..]);
+        }
+    }
+    (CipherScheme::Caesar, body)
+}
+
+fn decode_marker_body(body: &str, project_key: Option<&str>) -> anyhow::Result<String> {
+    let (scheme, encoded) = parse_marker_body(body);
+    decode_text_impl(encoded, scheme, project_key)
+}
+
+fn build_marker(text: &str, scheme: CipherScheme, project_key: Option<&str>) -> anyhow::Result<String> {
+    Ok(format!("{}{}{}", WATERMARK_PREFIX, marker_body(text, scheme, project_key)?, WATERMARK_SUFFIX))
+}
+
 #[tauri::command]
-fn encode_text(text: &str) -> String {
-    encode_text_impl(text)
+fn encode_text(text: &str, scheme: Option<CipherScheme>) -> tauri::Result<String> {
+    Ok(encode_text_impl(text, scheme.unwrap_or_default(), Preferences::load().key_path())?)
 }
 
 #[tauri::command]
-fn decode_text(text: &str) -> String {
-    decode_text_impl(text)
+fn decode_text(text: &str, scheme: Option<CipherScheme>) -> tauri::Result<String> {
+    Ok(decode_text_impl(text, scheme.unwrap_or_default(), Preferences::load().key_path())?)
 }
 
 #[tauri::command]
-fn add_watermark_marker(text: &str) -> String {
-    format!("{}{}{}", WATERMARK_PREFIX, encode_text_impl(text), WATERMARK_SUFFIX)
+fn add_watermark_marker(text: &str, scheme: Option<CipherScheme>) -> tauri::Result<String> {
+    Ok(build_marker(text, scheme.unwrap_or_default(), Preferences::load().key_path())?)
+}
+
+/// Create a new key file for the keyed scheme, e.g. a per-project key to set in preferences.
+#[tauri::command]
+fn create_watermark_key(path: Option<String>) -> tauri::Result<String> {
+    let p = keyed::key_path(path.as_deref())?;
+    keyed::create_key(&p)?;
+    Ok(p.to_string_lossy().to_string())
 }
 
 const MAX_WATERMARK_LENGTH: usize = 100;
@@ -70,12 +182,16 @@ const MAX_WATERMARK_LENGTH: usize = 100;
 fn read_tail(path: &PathBuf, max_len: usize) -> tauri::Result<(Vec<u8>, u64)> {
     let mut f = OpenOptions::new().read(true).open(path)?;
     let file_len = f.metadata()?.len();
-    if file_len == 0 { return Ok((Vec::new(), 0)); }
-    let read_len = std::cmp::min(max_len as u64, file_len) as usize;
+    Ok((read_window_before(&mut f, file_len, max_len)?, file_len))
+}
+
+/// Up to `max_len` bytes ending at offset `end`.
+fn read_window_before(f: &mut fs::File, end: u64, max_len: usize) -> std::io::Result<Vec<u8>> {
+    let read_len = std::cmp::min(max_len as u64, end) as usize;
     let mut buf = vec![0u8; read_len];
-    f.seek(SeekFrom::End(-(read_len as i64)))?;
+    f.seek(SeekFrom::Start(end - read_len as u64))?;
     f.read_exact(&mut buf)?;
-    Ok((buf, file_len))
+    Ok(buf)
 }
 
 fn find_bytes(data: &[u8], pattern: &[u8], start_from: usize, reverse: bool) -> Option<usize> {
@@ -90,83 +206,446 @@ fn find_bytes(data: &[u8], pattern: &[u8], start_from: usize, reverse: bool) ->
     }
 }
 
-fn find_watermark(data: &[u8], include_content: bool) -> Option<(usize, usize, Option<Vec<u8>>)> {
-    let start = find_bytes(data, WATERMARK_PREFIX.as_bytes(), 0, true)?;
-    let end = find_bytes(data, WATERMARK_SUFFIX.as_bytes(), start, false)?;
-    if end <= start { return None; }
-    let content = if include_content {
-        Some(data[start + WATERMARK_PREFIX.len()..end].to_vec())
-    } else { None };
-    Some((start, end, content))
+/// Text marker written before the binary trailer existed.
+struct LegacyMarker {
+    /// Offset of the marker in the scanned data.
+    start: usize,
+    body: String,
+    /// `*/ENCODED` rather than `<<==ENCODED==>>`.
+    old_format: bool,
 }
 
+/// Find a legacy marker that ends the data (up to trailing whitespace). Markers were
+/// always appended last, so a prefix somewhere in the middle is just payload.
+fn find_legacy_marker(data: &[u8]) -> Option<LegacyMarker> {
+    let trimmed_len = data.len() - data.iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+    let data = &data[..trimmed_len];
 
-#[derive(serde::Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum TextPosition {
-    TopLeft,
-    TopRight,
-    Center,
-    BottomLeft,
-    BottomRight,
+    if data.ends_with(WATERMARK_SUFFIX.as_bytes()) {
+        let end = data.len() - WATERMARK_SUFFIX.len();
+        let start = find_bytes(&data[..end], WATERMARK_PREFIX.as_bytes(), 0, true)?;
+        let body = std::str::from_utf8(&data[start + WATERMARK_PREFIX.len()..end]).ok()?;
+        if body.is_empty() || body.len() > TAIL_SCAN_SIZE { return None; }
+        return Some(LegacyMarker { start, body: body.to_string(), old_format: false });
+    }
+
+    let start = find_bytes(data, OLD_WATERMARK_PREFIX.as_bytes(), 0, true)?;
+    let body = &data[start + OLD_WATERMARK_PREFIX.len()..];
+    if body.is_empty() || body.len() > MAX_WATERMARK_LENGTH || !body.iter().all(|b| (0x20..0x7f).contains(b)) {
+        return None;
+    }
+    Some(LegacyMarker { start, body: String::from_utf8_lossy(body).trim().to_string(), old_format: true })
+}
+
+enum FoundMarker {
+    Trailer(marker::Trailer),
+    /// Trailer stored inside the container structure (structured mode).
+    Structured(container::Container, container::Embedded, marker::Trailer),
+    Legacy(LegacyMarker),
+    /// Trailer hidden in the pixel LSBs of a lossless image (LSB mode).
+    Lsb(marker::Trailer),
+    Corrupt(String),
+    None,
+}
+
+/// Look for a structured marker, then a binary trailer, then a legacy text marker,
+/// then, if `lsb` is set, an LSB marker (in lossless images, if there is a
+/// watermark key at `project_key`). The last means decoding the whole image.
+fn read_marker(path: &PathBuf, lsb: bool, project_key: Option<&str>) -> anyhow::Result<FoundMarker> {
+    read_marker_as(path, image_format(path), lsb, project_key)
+}
+
+/// `read_marker` for a file whose image format was already sniffed.
+fn read_marker_as(path: &PathBuf, format: Option<ImageFormat>, lsb: bool, project_key: Option<&str>) -> anyhow::Result<FoundMarker> {
+    if let Some(c) = container_for_format(path, format) {
+        // A file that doesn't parse as its extension claims can still carry a tail marker
+        if let Ok(Some(embedded)) = container::find(path, c) {
+            return Ok(match marker::parse(&embedded.blob) {
+                marker::Detected::Found(t) => FoundMarker::Structured(c, embedded, t),
+                marker::Detected::Corrupt(msg) => FoundMarker::Corrupt(msg),
+                marker::Detected::None => FoundMarker::Corrupt("structured marker without trailer".to_string()),
+            });
+        }
+    }
+
+    let mut f = fs::File::open(path)
+        .map_err(|e| anyhow!("Failed to open file {}: {}", path.display(), e))?;
+    match marker::read(&mut f).map_err(|e| anyhow!("Failed to read file {}: {}", path.display(), e))? {
+        marker::Detected::Found(t) => return Ok(FoundMarker::Trailer(t)),
+        marker::Detected::Corrupt(msg) => return Ok(FoundMarker::Corrupt(msg)),
+        marker::Detected::None => {}
+    }
+    let (tail, _) = read_tail(path, TAIL_SCAN_SIZE)?;
+    if let Some(l) = find_legacy_marker(&tail) {
+        return Ok(FoundMarker::Legacy(l));
+    }
+    if lsb && is_lossless(path, format) {
+        // Without a key there is nothing an LSB marker could be read with
+        if let Ok(key) = keyed::load_key(project_key) {
+            return Ok(find_lsb_marker(path, lsb::seed(&key)));
+        }
+    }
+    Ok(FoundMarker::None)
+}
+
+/// Interleaved 8-bit samples of an image and its channel count.
+fn lsb_samples(img: &mut DynamicImage) -> anyhow::Result<(&mut [u8], usize)> {
+    Ok(match img {
+        DynamicImage::ImageLuma8(b) => (b.as_mut(), 1),
+        DynamicImage::ImageLumaA8(b) => (b.as_mut(), 2),
+        DynamicImage::ImageRgb8(b) => (b.as_mut(), 3),
+        DynamicImage::ImageRgba8(b) => (b.as_mut(), 4),
+        _ => return Err(anyhow!("LSB watermarks need an 8-bit image")),
+    })
+}
+
+fn find_lsb_marker(path: &Path, seed: u64) -> FoundMarker {
+    // Anything that doesn't decode as an 8-bit image simply has no LSB marker
+    let Ok(mut img) = open_image(path) else { return FoundMarker::None };
+    let Ok((data, channels)) = lsb_samples(&mut img) else { return FoundMarker::None };
+    let Some(blob) = lsb::extract(data, channels, seed, marker::MAX_TRAILER_LEN) else {
+        return FoundMarker::None;
+    };
+    match marker::parse(&blob) {
+        marker::Detected::Found(t) if t.total_len == blob.len() => FoundMarker::Lsb(t),
+        marker::Detected::Found(_) => FoundMarker::Corrupt("LSB marker length mismatch".to_string()),
+        marker::Detected::Corrupt(msg) => FoundMarker::Corrupt(msg),
+        marker::Detected::None => FoundMarker::None,
+    }
 }
 
+/// What `embed_lsb_marker` would fail on for a marker of `len` bytes in the
+/// lossless image at `path`, going by the image's header alone.
+fn check_lsb_capacity(path: &Path, format: ImageFormat, len: usize) -> anyhow::Result<()> {
+    use image::{codecs, ColorType, ImageDecoder};
+    fn header<'a>(d: impl ImageDecoder<'a>) -> (ColorType, (u32, u32)) {
+        (d.color_type(), d.dimensions())
+    }
+    let f = std::io::BufReader::new(fs::File::open(path)?);
+    let (color, (width, height)) = match format {
+        ImageFormat::Png => header(codecs::png::PngDecoder::new(f)?),
+        ImageFormat::Bmp => header(codecs::bmp::BmpDecoder::new(f)?),
+        ImageFormat::Tiff => header(codecs::tiff::TiffDecoder::new(f)?),
+        ImageFormat::WebP => header(codecs::webp::WebPDecoder::new(f)?),
+        other => return Err(anyhow!("{:?} images can't take LSB watermarks", other)),
+    };
+    let channels = match color {
+        ColorType::L8 => 1,
+        ColorType::La8 => 2,
+        ColorType::Rgb8 => 3,
+        ColorType::Rgba8 => 4,
+        _ => return Err(anyhow!("LSB watermarks need an 8-bit image")),
+    };
+    lsb::check_capacity(width as usize * height as usize, channels, len)
+}
+
+/// Length of the LSB marker holding `text`. LSB markers are never signed, and
+/// keyed text seals to the same length with any key.
+fn lsb_marker_len(text: &str, scheme: CipherScheme) -> anyhow::Result<usize> {
+    let encoded = match scheme {
+        CipherScheme::Caesar => caesar_encode(text),
+        CipherScheme::Keyed => keyed::seal(text, &[0; 32])?,
+    };
+    Ok(marker::encode(scheme.id(), &[(marker::FIELD_TEXT, encoded.as_bytes())])?.len())
+}
+
+/// Hide `trailer` in the pixel LSBs of the lossless image at `path`.
+fn embed_lsb_marker(path: &Path, trailer: &[u8], seed: u64, save: SaveOptions) -> anyhow::Result<()> {
+    let mut img = open_image(path)?;
+    let (data, channels) = lsb_samples(&mut img)?;
+    lsb::embed(data, channels, trailer, seed)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    save_image(path, img, save)
+}
+
+fn decode_marker(found: FoundMarker, path: &PathBuf, project_key: Option<&str>) -> anyhow::Result<Option<String>> {
+    match found {
+        FoundMarker::Trailer(t) | FoundMarker::Structured(_, _, t) | FoundMarker::Lsb(t) => {
+            let scheme = CipherScheme::from_id(t.scheme)
+                .ok_or_else(|| anyhow!("Unknown cipher scheme {} in {}", t.scheme, path.display()))?;
+            let encoded = t.field(marker::FIELD_TEXT)
+                .ok_or_else(|| anyhow!("Watermark trailer in {} has no text", path.display()))?;
+            let encoded = std::str::from_utf8(encoded).map_err(|e| anyhow!(e))?;
+            Ok(Some(decode_text_impl(encoded, scheme, project_key)?))
+        }
+        FoundMarker::Legacy(l) if l.old_format => Ok(Some(caesar_decode(&l.body))),
+        FoundMarker::Legacy(l) => Ok(Some(decode_marker_body(&l.body, project_key)?)),
+        FoundMarker::Corrupt(msg) => Err(anyhow!("Corrupted watermark in {}: {}", path.display(), msg)),
+        FoundMarker::None => Ok(None),
+    }
+}
+
+
 #[tauri::command]
-fn add_text_to_image(path: String, text: String, position: Option<TextPosition>) -> tauri::Result<bool> {
-    // Load image
-    let p = PathBuf::from(&path);
-    let img = image::open(&p).map_err(|e| anyhow!(e))?;
-
-    // TODO: replace with embedded TTF. For now, return Ok(false) if not provided.
-    // Using rusttype requires a TTF; skip gracefully if missing.
-    let font_bytes: Option<&[u8]> = None;
-    let Some(bytes) = font_bytes else { return Ok(false) };
-    let font = Font::try_from_bytes(bytes).ok_or_else(|| anyhow!("Font load failed"))?;
-
-    // Parameters similar to Kotlin: small font, semi-transparent white, padding
-    let (w, h) = img.dimensions();
-    let scale = Scale { x: (w as f32 * 0.02).max(10.0), y: (w as f32 * 0.02).max(10.0) };
-    let color = Rgba([255u8, 255u8, 255u8, (0.5 * 255.0) as u8]);
-    let padding = 5i32;
-
-    // Measure text size (approx by rusttype)
-    let v_metrics = font.v_metrics(scale);
-    let glyphs: Vec<_> = font.layout(&text, scale, rusttype::point(0.0, 0.0 + v_metrics.ascent)).collect();
-    let width: i32 = glyphs.iter().rev().find_map(|g| {
-        g.pixel_bounding_box().map(|bb| bb.max.x as i32)
-    }).unwrap_or(0);
-    let height: i32 = (v_metrics.ascent - v_metrics.descent).ceil() as i32;
-
-    let pos = match position.unwrap_or(TextPosition::BottomRight) {
-        TextPosition::BottomRight => (w as i32 - width - padding, h as i32 - padding),
-        TextPosition::BottomLeft => (padding, h as i32 - padding),
-        TextPosition::TopRight => (w as i32 - width - padding, height + padding),
-        TextPosition::TopLeft => (padding, height + padding),
-        TextPosition::Center => (((w as i32 - width) / 2).max(0), ((h as i32 + height) / 2).max(0)),
+fn add_text_to_image(
+    path: String,
+    text: String,
+    position: Option<TextPosition>,
+    font_path: Option<String>,
+    style: Option<WatermarkStyle>
+) -> tauri::Result<bool> {
+    let prefs = Preferences::load();
+    let font = load_font(font_path.or(prefs.watermark_font_path.clone()).as_deref())?;
+    let style = style.unwrap_or_default();
+    draw_text_on_image(&PathBuf::from(&path), &text, position.unwrap_or_default(), &style, &font, prefs.save_options())?;
+    Ok(true)
+}
+
+/// Font shipped with the app for visible watermarks, see `fonts/LICENSE-DejaVu.txt`.
+const BUNDLED_FONT: &[u8] = include_bytes!("../fonts/DejaVuSans.ttf");
+
+/// The TrueType/OpenType font at `path`, or the bundled one when `path` is `None`.
+fn load_font(path: Option<&str>) -> anyhow::Result<Font<'static>> {
+    let Some(path) = path else {
+        return Font::try_from_bytes(BUNDLED_FONT).ok_or_else(|| anyhow!("Bundled font is damaged"));
+    };
+    let data = fs::read(path).map_err(|e| anyhow!("Failed to read font {}: {}", path, e))?;
+    Font::try_from_vec(data).ok_or_else(|| anyhow!("Not a usable TrueType/OpenType font: {}", path))
+}
+
+fn draw_text_on_image(p: &Path, text: &str, position: TextPosition, style: &WatermarkStyle, font: &Font, save: SaveOptions) -> anyhow::Result<()> {
+    edit_image(p, save, |img| visible::draw(img, text, position, style, font))
+}
+
+/// Composite a logo (PNG with alpha) onto an image at `position`.
+#[tauri::command]
+fn add_image_overlay(
+    path: String,
+    logo_path: String,
+    position: Option<TextPosition>,
+    style: Option<visible::OverlayStyle>
+) -> tauri::Result<bool> {
+    let logo = load_logo(Path::new(&logo_path))?;
+    let style = style.unwrap_or_default();
+    edit_image(Path::new(&path), Preferences::load().save_options(), |img| visible::overlay(img, &logo, position.unwrap_or_default(), &style))?;
+    Ok(true)
+}
+
+fn load_logo(p: &Path) -> anyhow::Result<image::RgbaImage> {
+    Ok(open_image(p)?.to_rgba8())
+}
+
+/// Load an image, apply `edit` and save it back in place with its original
+/// pixel layout (grey, alpha, 16-bit) where the format allows. `edit` works at
+/// the source's own depth: 16-bit files are edited at 16 bits per channel and
+/// float ones in float, everything else at 8 bits. It sees the picture the way
+/// viewers show it, with EXIF orientation applied; the stored pixels keep their
+/// orientation unless `save` says to turn them upright.
+fn edit_image(p: &Path, save: SaveOptions, edit: impl FnOnce(&mut visible::Canvas) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    use visible::Canvas;
+    let data = fs::read(p).map_err(|e| anyhow!("Failed to read {}: {}", p.display(), e))?;
+    let mut kept = metadata::read(&data);
+    let original = image::load_from_memory(&data).map_err(|e| anyhow!("Failed to open image {}: {}", p.display(), e))?;
+    let color = original.color();
+    let orientation = kept.orientation();
+    let mut canvas = match Canvas::new(original) {
+        Canvas::Rgba8(img) => Canvas::Rgba8(metadata::to_display(img, orientation)),
+        Canvas::Rgba16(img) => Canvas::Rgba16(metadata::to_display(img, orientation)),
+        Canvas::Rgba32F(img) => Canvas::Rgba32F(metadata::to_display(img, orientation)),
+    };
+    edit(&mut canvas).map_err(|e| anyhow!("{}: {}", p.display(), e))?;
+    let stored = if save.upright {
+        kept.reset_orientation();
+        1
+    } else {
+        orientation
+    };
+    let canvas = match canvas {
+        Canvas::Rgba8(img) => Canvas::Rgba8(metadata::from_display(img, stored)),
+        Canvas::Rgba16(img) => Canvas::Rgba16(metadata::from_display(img, stored)),
+        Canvas::Rgba32F(img) => Canvas::Rgba32F(metadata::from_display(img, stored)),
     };
+    write_image(p, &data, &kept, with_color(canvas.into_image(), color), save)
+}
+
+fn with_color(img: DynamicImage, color: image::ColorType) -> DynamicImage {
+    use image::ColorType;
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        _ => img,
+    }
+}
 
-    // Draw text
-    let mut rgba_img = img.to_rgba8();
-    draw_text_mut(&mut rgba_img, color, pos.0, pos.1, scale, &font, &text);
+/// Decode an image, going by its content rather than its extension.
+fn open_image(p: &Path) -> anyhow::Result<DynamicImage> {
+    let decode = || image::io::Reader::open(p)?.with_guessed_format()?.decode().map_err(std::io::Error::other);
+    decode().map_err(|e| anyhow!("Failed to open image {}: {}", p.display(), e))
+}
+
+/// Used when a JPEG's quality can't be estimated and none is set.
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// Write an edited image back over `p` in the format the file already has,
+/// keeping its EXIF, ICC profile and other metadata (see `metadata.rs`). JPEG
+/// quality comes from `save`, else from the original's quantization.
+/// Lossy WebP is refused: the only WebP encoder at hand is lossless, which would
+/// make the file several times larger. GIF is written with a palette quantized
+/// afresh.
+fn save_image(p: &Path, img: DynamicImage, save: SaveOptions) -> anyhow::Result<()> {
+    let original = fs::read(p).map_err(|e| anyhow!("Failed to read {}: {}", p.display(), e))?;
+    write_image(p, &original, &metadata::read(&original), img, save)
+}
 
-    // Save back
-    let fmt = match p.extension().and_then(|s| s.to_str()).map(|s| s.to_lowercase()) {
-        Some(ref e) if e == "png" => ImageOutputFormat::Png,
-        _ => ImageOutputFormat::Jpeg(80),
+/// `save_image` for callers that already hold the file's bytes and metadata.
+fn write_image(p: &Path, original: &[u8], kept: &metadata::Preserved, img: DynamicImage, save: SaveOptions) -> anyhow::Result<()> {
+    let format = sniff_image(original).or_else(|| ImageFormat::from_path(p).ok())
+        .ok_or_else(|| anyhow!("Unknown image format {}", p.display()))?;
+    if is_animated(format, original) {
+        // Only the first frame is decoded, writing it back would drop the rest
+        return Err(anyhow!("Can't edit animated images: {}", p.display()));
+    }
+    let (img, fmt) = match format {
+        ImageFormat::Png => (img, ImageOutputFormat::Png),
+        ImageFormat::Jpeg => {
+            let quality = save.jpeg_quality.or(kept.jpeg_quality).unwrap_or(DEFAULT_JPEG_QUALITY);
+            (DynamicImage::ImageRgb8(img.to_rgb8()), ImageOutputFormat::Jpeg(quality))
+        }
+        ImageFormat::Tiff => (sixteen_bit_at_most(img), ImageOutputFormat::Tiff),
+        ImageFormat::WebP if metadata::is_lossy_webp(&mut std::io::Cursor::new(original))? => {
+            return Err(anyhow!("Can't write lossy WebP images, only lossless ones: {}", p.display()));
+        }
+        ImageFormat::WebP => (eight_bit(img), ImageOutputFormat::WebP),
+        ImageFormat::Bmp => (eight_bit(img), ImageOutputFormat::Bmp),
+        ImageFormat::Gif => (DynamicImage::ImageRgba8(img.to_rgba8()), ImageOutputFormat::Gif),
+        other => return Err(anyhow!("Can't write {:?} images: {}", other, p.display())),
     };
-    let mut out = std::fs::File::create(&p).map_err(|e| anyhow!(e))?;
-    let dynimg = DynamicImage::ImageRgba8(rgba_img);
-    dynimg.write_to(&mut out, fmt).map_err(|e| anyhow!(e))?;
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut encoded, fmt).map_err(|e| anyhow!("Failed to encode {}: {}", p.display(), e))?;
+    let data = metadata::restore(encoded.into_inner(), kept)?;
+    atomic::replace(p, save.keep_backup, |out| Ok(out.write_all(&data)?))
+}
+
+fn is_animated(format: ImageFormat, data: &[u8]) -> bool {
+    match format {
+        ImageFormat::Gif => image::codecs::gif::GifDecoder::new(std::io::Cursor::new(data))
+            .is_ok_and(|d| image::AnimationDecoder::into_frames(d).take(2).count() > 1),
+        // VP8X animation flag
+        ImageFormat::WebP => data.get(12..16) == Some(b"VP8X") && data.get(20).is_some_and(|f| f & 0x02 != 0),
+        _ => false,
+    }
+}
+
+/// What the WebP and BMP encoders take: 8-bit grey or colour, with or without alpha.
+fn eight_bit(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img,
+        _ if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+/// What the TIFF encoder takes: 8 or 16-bit grey, RGB or RGBA.
+fn sixteen_bit_at_most(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(img.to_rgba8()),
+        DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba16(img.to_rgba16()),
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb16(img.to_rgb16()),
+        _ => img,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct InvisibleReport {
+    found: bool,
+    order: Option<u32>,
+    /// Share of payload bits read with a clear margin, 0 when nothing was found.
+    confidence: f32,
+}
+
+/// Hide `order` in the pixels of an image, see `invisible.rs`. The mark is keyed
+/// with the watermark key, so only installations holding that key can find it.
+#[tauri::command]
+fn add_invisible_watermark(path: String, order: u32, strength: Option<f32>) -> tauri::Result<bool> {
+    let p = PathBuf::from(&path);
+    let prefs = Preferences::load();
+    let key = keyed::load_or_create_key(prefs.key_path())?;
+    embed_invisible(&p, order, invisible::seed(&key), strength.unwrap_or(1.0), prefs.save_options())?;
     Ok(true)
 }
 
+fn embed_invisible(p: &Path, order: u32, seed: u64, strength: f32, save: SaveOptions) -> anyhow::Result<()> {
+    if strength.is_nan() || strength <= 0.0 { return Err(anyhow!("Strength must be positive")); }
+    let img = open_image(p)?;
+    let color = img.color();
+    // The stored pixels, as the detector reads them, in the file's own depth
+    let mut canvas = visible::Canvas::new(img);
+    invisible::embed(&mut canvas, order, seed, strength)?;
+    save_image(p, with_color(canvas.into_image(), color), save)
+}
+
+/// Look for an invisible watermark, also in re-compressed, resized or cropped copies.
+#[tauri::command]
+fn detect_invisible_watermark(path: String) -> tauri::Result<InvisibleReport> {
+    let key = keyed::load_key(Preferences::load().key_path())?;
+    Ok(detect_invisible(&PathBuf::from(&path), invisible::seed(&key))?)
+}
+
+fn detect_invisible(p: &Path, seed: u64) -> anyhow::Result<InvisibleReport> {
+    let img = open_image(p)?.to_rgba8();
+    Ok(match invisible::detect(&img, seed) {
+        Some(d) => InvisibleReport { found: true, order: Some(d.value), confidence: d.confidence },
+        None => InvisibleReport { found: false, order: None, confidence: 0.0 },
+    })
+}
+
 fn supported_extensions() -> &'static [&'static str] {
-    &["txt", "jpg", "jpeg", "png", "mp4", "avi", "mov", "mkv"]
+    &["txt", "jpg", "jpeg", "png", "webp", "tif", "tiff", "gif", "bmp", "mp4", "avi", "mov", "mkv"]
 }
 
-fn is_image_file(path: &PathBuf) -> bool {
-    matches!(path.extension().and_then(|s| s.to_str()).map(|s| s.to_lowercase()),
-        Some(ref e) if ["jpg","jpeg","png"].contains(&e.as_str()))
+/// Image formats that are read, edited and written back in the same format.
+const IMAGE_FORMATS: [ImageFormat; 6] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Tiff, ImageFormat::Gif, ImageFormat::Bmp];
+
+/// Format of an image file by its magic bytes, else by its extension.
+fn image_format(path: &Path) -> Option<ImageFormat> {
+    let mut head = Vec::with_capacity(32);
+    let _ = fs::File::open(path).and_then(|f| f.take(32).read_to_end(&mut head));
+    sniff_image(&head)
+        .or_else(|| ImageFormat::from_path(path).ok().filter(|f| IMAGE_FORMATS.contains(f)))
+}
+
+fn sniff_image(head: &[u8]) -> Option<ImageFormat> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if metadata::is_webp(head) {
+        Some(ImageFormat::WebP)
+    } else if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {
+        Some(ImageFormat::Tiff)
+    } else if head.starts_with(b"BM") {
+        // Two letters are easily met by chance; the DIB header size has to be a known one
+        let dib = head.get(14..18).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        dib.filter(|n| [12, 40, 52, 56, 64, 108, 124].contains(n)).map(|_| ImageFormat::Bmp)
+    } else {
+        None
+    }
+}
+
+fn is_image_file(path: &Path) -> bool {
+    image_format(path).is_some()
+}
+
+/// PNG, BMP, TIFF and lossless WebP keep pixels exactly, so they can carry an
+/// LSB marker.
+fn is_lossless_image(path: &Path) -> bool {
+    is_lossless(path, image_format(path))
+}
+
+fn is_lossless(path: &Path, format: Option<ImageFormat>) -> bool {
+    match format {
+        Some(ImageFormat::Png | ImageFormat::Bmp | ImageFormat::Tiff) => true,
+        Some(ImageFormat::WebP) => fs::File::open(path).and_then(|mut f| metadata::is_lossy_webp(&mut f)).is_ok_and(|lossy| !lossy),
+        _ => false,
+    }
 }
 
 fn is_video_file(path: &PathBuf) -> bool {
@@ -174,6 +653,32 @@ fn is_video_file(path: &PathBuf) -> bool {
         Some(ref e) if ["mp4","avi","mov","mkv"].contains(&e.as_str()))
 }
 
+/// Container used for structured markers; `None` means only the tail marker is possible.
+fn container_for(path: &PathBuf) -> Option<container::Container> {
+    container_for_format(path, image_format(path))
+}
+
+fn container_for_format(path: &PathBuf, format: Option<ImageFormat>) -> Option<container::Container> {
+    if let Some(format) = format {
+        // Other image formats only take the tail marker
+        return match format {
+            ImageFormat::Png => Some(container::Container::Png),
+            ImageFormat::Jpeg => Some(container::Container::Jpeg),
+            _ => None,
+        };
+    }
+    let ext = path.extension().and_then(|s| s.to_str()).map(|s| s.to_lowercase())?;
+    if is_video_file(path) {
+        match ext.as_str() {
+            "mkv" => Some(container::Container::Matroska),
+            "avi" => None,
+            _ => Some(container::Container::IsoBmff),
+        }
+    } else {
+        None
+    }
+}
+
 
 fn extract_trailing_number(text: &str) -> i32 {
     let mut digits = String::new();
@@ -184,7 +689,8 @@ fn extract_trailing_number(text: &str) -> i32 {
 }
 
 fn extract_file_number(name: &str) -> Option<i32> {
-    let re = Regex::new(r".*?(\d+).*").ok()?;
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r".*?(\d+).*").unwrap());
     re.captures(name).and_then(|c| c.get(1)).and_then(|m| m.as_str().parse::<i32>().ok())
 }
 
@@ -197,293 +703,1227 @@ fn swap_files(a: &PathBuf, b: &PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn add_visible_watermark_in_folder(folder: &PathBuf, text: &str, photo_number: i32) -> anyhow::Result<bool> {
-    let mut found = false;
-    for file_str in get_supported_files(folder.to_string_lossy().to_string()).map_err(|e| anyhow!(e))? {
-        let file_path = PathBuf::from(&file_str);
-        if is_image_file(&file_path) {
-            if let Some(n) = extract_file_number(file_path.file_name().unwrap().to_string_lossy().as_ref()) {
-                if n == photo_number {
-                    // Best-effort; if font missing, function returns Ok(false)
-                    let _ = add_text_to_image(file_str, text.to_string(), None)?;
-                    found = true;
-                    break;
-                }
-            }
-        }
+/// Which images of a copy get the visible watermark.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+enum PhotoSelection {
+    All,
+    /// Photo numbers and ranges, e.g. "1-5,12".
+    Numbers(String),
+    /// Wildcard pattern for the path inside the copy, e.g. "*_final.jpg".
+    Glob(String),
+    /// This many images, picked at random for every copy.
+    Random(usize),
+}
+
+/// A `PhotoSelection` checked and parsed once per batch.
+enum Selector {
+    All,
+    Numbers(Vec<std::ops::RangeInclusive<i32>>),
+    Glob(glob::Pattern),
+    Random(usize),
+}
+
+impl PhotoSelection {
+    fn compile(&self) -> anyhow::Result<Selector> {
+        Ok(match self {
+            PhotoSelection::All => Selector::All,
+            PhotoSelection::Numbers(spec) => Selector::Numbers(parse_number_ranges(spec)?),
+            PhotoSelection::Glob(pattern) => Selector::Glob(glob::Pattern::new(pattern)
+                .map_err(|e| anyhow!("Invalid photo pattern \"{}\": {}", pattern, e))?),
+            PhotoSelection::Random(count) => Selector::Random(*count),
+        })
     }
-    Ok(found)
 }
 
-fn create_zip_stored(folder: &PathBuf) -> anyhow::Result<PathBuf> {
+/// "1-5,12" style list of photo numbers.
+fn parse_number_ranges(spec: &str) -> anyhow::Result<Vec<std::ops::RangeInclusive<i32>>> {
+    let invalid = || anyhow!("Invalid photo numbers \"{}\", expected a list like 1-5,12", spec);
+    let ranges = spec.split(',').map(str::trim).filter(|part| !part.is_empty()).map(|part| {
+        let (from, to) = part.split_once('-').unwrap_or((part, part));
+        let (from, to): (i32, i32) = (from.trim().parse().map_err(|_| invalid())?, to.trim().parse().map_err(|_| invalid())?);
+        if from > to { return Err(invalid()); }
+        Ok(from..=to)
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+    if ranges.is_empty() { return Err(invalid()); }
+    Ok(ranges)
+}
+
+/// Images under `folder` picked by `selector`, sorted by path.
+fn select_photos(folder: &Path, selector: &Selector, rng: &mut fastrand::Rng) -> anyhow::Result<Vec<PathBuf>> {
+    let images = get_supported_files(folder.to_string_lossy().to_string()).map_err(|e| anyhow!(e))?
+        .into_iter().map(PathBuf::from).filter(|p| is_image_file(p));
+    let number = |p: &PathBuf| extract_file_number(p.file_name().unwrap().to_string_lossy().as_ref());
+    let mut picked: Vec<PathBuf> = match selector {
+        Selector::All => images.collect(),
+        Selector::Numbers(ranges) => images
+            .filter(|p| number(p).is_some_and(|n| ranges.iter().any(|r| r.contains(&n))))
+            .collect(),
+        Selector::Glob(pattern) => {
+            let options = glob::MatchOptions { case_sensitive: false, require_literal_separator: true, ..Default::default() };
+            images.filter(|p| {
+                let rel = p.strip_prefix(folder).unwrap_or(p).to_string_lossy().replace('\\', "/");
+                pattern.matches_with(&rel, options)
+            }).collect()
+        }
+        Selector::Random(count) => rng.choose_multiple(images, *count),
+    };
+    picked.sort();
+    Ok(picked)
+}
+
+/// Ledger in the `-Copies` folder of which photos got a visible watermark in
+/// which copy, kept outside the copies so it is never delivered.
+const VISIBLE_LEDGER: &str = "visible-watermarks.jsonl";
+
+#[derive(serde::Serialize)]
+struct VisibleRecord<'a> {
+    order: &'a str,
+    /// Paths inside the copy, with `/` separators.
+    files: Vec<String>,
+}
+
+fn record_visible(copies_folder: &Path, copy: &Path, order: &str, files: &[PathBuf]) -> anyhow::Result<()> {
+    let files = files.iter()
+        .map(|f| f.strip_prefix(copy).unwrap_or(f).to_string_lossy().replace('\\', "/"))
+        .collect();
+    let mut line = serde_json::to_vec(&VisibleRecord { order, files })?;
+    line.push(b'\n');
+    let mut ledger = OpenOptions::new().create(true).append(true).open(copies_folder.join(VISIBLE_LEDGER))?;
+    ledger.write_all(&line)?;
+    Ok(())
+}
+
+/// Drop the ledger lines of the copies `drop` picks.
+fn trim_ledger(copies_folder: &Path, drop: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+    let path = copies_folder.join(VISIBLE_LEDGER);
+    let Ok(ledger) = fs::read_to_string(&path) else { return Ok(()) };
+    // Lines of other batches, or that don't parse, are not ours to remove
+    let kept: String = ledger.lines()
+        .filter(|line| !serde_json::from_str::<serde_json::Value>(line).ok()
+            .is_some_and(|record| record["order"].as_str().is_some_and(&drop)))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    atomic::replace(&path, false, |f| Ok(f.write_all(kept.as_bytes())?))
+}
+
+/// Values for the placeholders in batch watermark text.
+struct LabelVars<'a> {
+    order: &'a str,
+    base_text: &'a str,
+    /// 1-based number of the copy within the batch.
+    index: usize,
+    date: &'a str,
+}
+
+/// `template` with `{order}`, `{base_text}`, `{filename}`, `{index}` and `{date}`
+/// filled in; any other braces are kept as written.
+fn expand_label(template: &str, vars: &LabelVars, filename: &str) -> String {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"\{(order|base_text|filename|index|date)\}").unwrap());
+    re.replace_all(template, |c: &regex::Captures| match &c[1] {
+        "order" => vars.order.to_string(),
+        "base_text" => vars.base_text.to_string(),
+        "filename" => filename.to_string(),
+        "index" => vars.index.to_string(),
+        _ => vars.date.to_string(),
+    }).into_owned()
+}
+
+fn create_zip_stored(folder: &Path, report: &Reporter) -> anyhow::Result<PathBuf> {
     let zip_path = folder.parent().unwrap().join(format!("{}.zip", folder.file_name().unwrap().to_string_lossy()));
     let file = fs::File::create(&zip_path)?;
     let mut zip = zip::ZipWriter::new(file);
     let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
 
-    let base = folder.clone();
-    for entry in WalkDir::new(&base).into_iter().filter_map(|e| e.ok()) {
+    let (files, total_bytes) = tree_size(folder);
+    let (mut file_index, mut bytes) = (0, 0);
+    for entry in WalkDir::new(folder).into_iter().filter_map(|e| e.ok()) {
         let p = entry.path();
-        let rel = p.strip_prefix(&base).unwrap();
+        let rel = p.strip_prefix(folder).unwrap();
         let name = rel.to_string_lossy().replace('\\', "/");
         if p.is_dir() {
             if !name.is_empty() {
                 zip.add_directory(format!("{}/", name), options)?;
             }
         } else {
-            let data = fs::read(p)?;
-            let file_options = options;
+            let mut src = fs::File::open(p)?;
+            let file_options = options.large_file(src.metadata()?.len() >= u32::MAX as u64);
             zip.start_file(name, file_options)?;
-            zip.write_all(&data)?;
+            bytes += std::io::copy(&mut src, &mut zip)?;
+            file_index += 1;
+            report.progress(file_index, files, bytes, total_bytes)?;
         }
     }
     zip.finish()?;
     Ok(zip_path)
 }
 
-fn process_files(folder: &PathBuf, base_text_without_number: &str, order_number: &str) -> anyhow::Result<()> {
+/// Number and total size of the files under `folder`.
+fn tree_size(folder: &Path) -> (usize, u64) {
+    WalkDir::new(folder).into_iter().filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .fold((0, 0), |(n, bytes), e| (n + 1, bytes + e.metadata().map_or(0, |m| m.len())))
+}
+
+fn process_files(folder: &Path, base_text_without_number: &str, order_number: &str, opts: &MarkOptions, report: &Reporter) -> anyhow::Result<()> {
     let files = get_supported_files(folder.to_string_lossy().to_string()).map_err(|e| anyhow!(e))?;
     let encoded_text = format!("{} {}", base_text_without_number, order_number);
-    let _encoded_for_tail = encode_text_impl(&encoded_text);
-    let marker = format!("{}{}{}", WATERMARK_PREFIX, encoded_text, WATERMARK_SUFFIX);
+    let sizes: Vec<u64> = files.iter().map(|f| fs::metadata(f).map_or(0, |m| m.len())).collect();
+    let total_bytes = sizes.iter().sum();
 
-    for file_str in files {
-        let file_path = PathBuf::from(&file_str);
-        if is_video_file(&file_path) {
-            let _ = add_tail_watermark(file_str.clone(), encoded_text.clone()).map_err(|e| anyhow!(e))?;
-        } else {
-            // Append marker to file if not present
-            let mut content = String::new();
-            if let Ok(mut f) = fs::File::open(&file_path) {
-                use std::io::Read as _;
-                let _ = f.read_to_string(&mut content);
-            }
-            if !content.contains(&marker) {
-                let mut f = OpenOptions::new().append(true).create(true).open(&file_path)?;
-                f.write_all(marker.as_bytes())?;
+    let mut bytes = 0;
+    for (i, file_str) in files.iter().enumerate() {
+        let path = PathBuf::from(file_str);
+        let fallback = tail_fallback(&path, &encoded_text, opts);
+        let tail = MarkOptions { mode: EmbedMode::Tail, ..opts.clone() };
+        // Files that already carry a marker are left alone
+        if add_tail_marker(&path, &encoded_text, if fallback.is_some() { &tail } else { opts })? {
+            if let Some(reason) = fallback {
+                report.log(format!("{}: {}, added a tail marker instead", file_str, reason));
             }
         }
+        bytes += sizes[i];
+        report.progress(i + 1, files.len(), bytes, total_bytes)?;
     }
+    report.log(format!("Marked {} files", files.len()));
     Ok(())
 }
 
-#[tauri::command]
-fn batch_copy_and_encode(
-    source_folder: String,
-    num_copies: i32,
-    base_text: String,
-    add_swap: bool,
-    add_watermark: bool,
-    create_zip: bool,
-    watermark_text: Option<String>,
-    photo_number: Option<i32>
-) -> tauri::Result<bool> {
-    use fs_extra::dir::{copy as copy_dir, CopyOptions};
+/// Tauri event batch runs report on, with a `BatchEvent` as payload.
+const BATCH_EVENT: &str = "batch";
 
-    let src = PathBuf::from(&source_folder);
-    let copies_folder = src.parent().ok_or_else(|| anyhow!("No parent for source folder"))?.join(format!("{}-Copies", src.file_name().unwrap().to_string_lossy()));
-    if !copies_folder.exists() { fs::create_dir_all(&copies_folder).map_err(|e| anyhow!(e))?; }
+/// Copy-folder progress is sent at least this far apart, besides once per file.
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
-    let start_number = extract_trailing_number(&base_text);
-    let base_text_without_number = base_text.trim_end_matches(|c: char| c.is_ascii_digit()).trim().to_string();
+/// The steps every copy goes through, in order.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BatchPhase {
+    Copy,
+    Watermark,
+    Mark,
+    Swap,
+    Zip,
+}
 
-    let mut folders_to_zip: Vec<(PathBuf, String)> = Vec::new();
+/// What a batch run reports, serialized as `{"type": "progress", "phase": "copy", ...}`.
+/// Copies and files count from 1; `bytes` is how much of the `total_bytes` the
+/// phase handles for this copy is done.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BatchEvent {
+    Progress { phase: BatchPhase, copy: usize, copies: usize, file: usize, files: usize, bytes: u64, total_bytes: u64 },
+    Log { phase: BatchPhase, copy: usize, copies: usize, message: String },
+    /// The batch stops after this.
+    Error { phase: BatchPhase, copy: usize, copies: usize, message: String },
+    /// Finished by an earlier run of a resumed batch, left as it is.
+    Skipped { copy: usize, copies: usize },
+    /// Stopped by `cancel_job`, with `copy` the first copy not finished. Copies
+    /// that were in progress have been removed.
+    Cancelled { copy: usize, copies: usize },
+    Done { copies: usize, output: String },
+}
 
-    for i in 0..num_copies.max(0) {
-        let order = start_number + i;
-        let order_str = format!("{:03}", order);
-        let order_folder = copies_folder.join(&order_str);
-        fs::create_dir_all(&order_folder).map_err(|e| anyhow!(e))?;
-        let destination_folder = order_folder.join(src.file_name().unwrap());
+/// `BatchEvent` as emitted, with the ID of the job it belongs to.
+#[derive(serde::Serialize, Clone)]
+struct JobEvent {
+    job: u64,
+    #[serde(flatten)]
+    event: BatchEvent,
+}
 
-        let mut opts = CopyOptions::new();
-        opts.overwrite = true;
-        opts.copy_inside = true;
-        copy_dir(&src, &destination_folder, &opts).map_err(|e| anyhow!(e))?;
+/// Sends the events of one copy, tagged with the phase it is in.
+struct Reporter<'a> {
+    emit: &'a (dyn Fn(BatchEvent) + Sync),
+    cancel: &'a AtomicBool,
+    /// Raised when another copy has failed.
+    failed: &'a AtomicBool,
+    copy: usize,
+    copies: usize,
+    phase: std::cell::Cell<BatchPhase>,
+}
 
-        process_files(&destination_folder, &base_text_without_number, &order_str).map_err(|e| anyhow!(e))?;
+impl Reporter<'_> {
+    fn enter(&self, phase: BatchPhase) {
+        self.phase.set(phase);
+    }
 
-        if add_watermark {
-            let actual_photo_number = photo_number.unwrap_or(order);
-            let actual_text = watermark_text.clone().unwrap_or(order_str.clone());
-            let _ = add_visible_watermark_in_folder(&destination_folder, &actual_text, actual_photo_number).map_err(|e| anyhow!(e))?;
-        }
+    /// Whether the batch has been cancelled or another copy has failed.
+    fn halted(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed) || self.failed.load(Ordering::Relaxed)
+    }
 
-        if add_swap {
-            // swap base number with +10
-            let base_num = order;
-            let swap_num = base_num + 10;
-            let file_strings = get_supported_files(destination_folder.to_string_lossy().to_string()).map_err(|e| anyhow!(e))?;
-            let images: Vec<PathBuf> = file_strings.into_iter()
-                .map(|s| PathBuf::from(s))
-                .filter(|p| is_image_file(p))
-                .collect();
-            let file_a = images.iter().find(|p| extract_file_number(p.file_name().unwrap().to_string_lossy().as_ref()) == Some(base_num)).cloned();
-            let file_b = images.iter().find(|p| extract_file_number(p.file_name().unwrap().to_string_lossy().as_ref()) == Some(swap_num)).cloned();
-            if let (Some(a), Some(b)) = (file_a, file_b) { let _ = swap_files(&a, &b).map_err(|e| anyhow!(e))?; }
-        }
+    /// Report progress; an error once the batch is halted, to stop at.
+    fn progress(&self, file: usize, files: usize, bytes: u64, total_bytes: u64) -> anyhow::Result<()> {
+        (self.emit)(BatchEvent::Progress { phase: self.phase.get(), copy: self.copy, copies: self.copies, file, files, bytes, total_bytes });
+        if self.halted() { return Err(anyhow!("Batch stopped")); }
+        Ok(())
+    }
 
-        folders_to_zip.push((destination_folder.clone(), order_str));
+    fn log(&self, message: impl Into<String>) {
+        (self.emit)(BatchEvent::Log { phase: self.phase.get(), copy: self.copy, copies: self.copies, message: message.into() });
     }
 
-    if create_zip {
-        for (folder_to_zip, _order_str) in folders_to_zip {
-            let _ = create_zip_stored(&folder_to_zip).map_err(|e| anyhow!(e))?;
-            let _ = fs::remove_dir_all(&folder_to_zip);
-        }
+    /// Report `e` as the error that stopped the batch, and pass it on.
+    fn fail(&self, e: anyhow::Error) -> anyhow::Error {
+        (self.emit)(BatchEvent::Error { phase: self.phase.get(), copy: self.copy, copies: self.copies, message: e.to_string() });
+        e
     }
+}
 
-    Ok(true)
+/// Limits how many copies are in their disk-heavy copy and zip phases at once.
+struct IoSlots {
+    free: std::sync::Mutex<usize>,
+    freed: std::sync::Condvar,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default)]
-struct Preferences {
-    theme_mode: Option<String>,
-    auto_clear_console: Option<bool>,
-    last_selected_path: Option<String>,
+struct IoSlot<'a>(&'a IoSlots);
+
+impl IoSlots {
+    fn new(slots: usize) -> Self {
+        IoSlots { free: std::sync::Mutex::new(slots.max(1)), freed: std::sync::Condvar::new() }
+    }
+
+    fn acquire(&self) -> IoSlot<'_> {
+        let mut free = self.freed.wait_while(self.free.lock().unwrap(), |free| *free == 0).unwrap();
+        *free -= 1;
+        IoSlot(self)
+    }
 }
 
-fn prefs_path() -> anyhow::Result<PathBuf> {
-    let base = config_dir().ok_or_else(|| anyhow!("no config dir"))?.join("endecode");
-    if !base.exists() { fs::create_dir_all(&base)?; }
-    Ok(base.join("preferences.json"))
+impl Drop for IoSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
 }
 
-#[tauri::command]
-fn load_preferences() -> tauri::Result<Preferences> {
-    let p = prefs_path().map_err(|e| anyhow!(e))?;
-    if !p.exists() { return Ok(Preferences::default()); }
-    let data = fs::read(&p).map_err(|e| anyhow!(e))?;
-    let prefs: Preferences = serde_json::from_slice(&data).map_err(|e| anyhow!(e))?;
-    Ok(prefs)
+/// Copies in the copy or zip phase at once when the preference is unset.
+const DEFAULT_BATCH_IO_LIMIT: usize = 2;
+
+/// Arguments of `batch_copy_and_encode` and `plan_batch`, see there. The
+/// frontend passes them as one `args` object with these (snake_case) names.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+struct BatchArgs {
+    source_folder: String,
+    num_copies: i32,
+    base_text: String,
+    add_swap: bool,
+    add_watermark: bool,
+    create_zip: bool,
+    watermark_text: Option<String>,
+    photo_number: Option<i32>,
+    scheme: Option<CipherScheme>,
+    sign: Option<bool>,
+    mode: Option<EmbedMode>,
+    font_path: Option<String>,
+    watermark_position: Option<TextPosition>,
+    watermark_style: Option<WatermarkStyle>,
+    overlay_path: Option<String>,
+    overlay_position: Option<TextPosition>,
+    overlay_style: Option<visible::OverlayStyle>,
+    photo_selection: Option<PhotoSelection>,
+    /// Unset means the `batch_workers` / `batch_io_limit` preferences.
+    workers: Option<usize>,
+    io_limit: Option<usize>,
+    /// Seed of the random photo picks, as reported by `plan_batch`.
+    seed: Option<u64>,
 }
 
-#[tauri::command]
-fn save_preferences(prefs: Preferences) -> tauri::Result<bool> {
-    let p = prefs_path().map_err(|e| anyhow!(e))?;
-    let data = serde_json::to_vec_pretty(&prefs).map_err(|e| anyhow!(e))?;
-    fs::write(&p, data).map_err(|e| anyhow!(e))?;
-    Ok(true)
+/// A batch with its arguments checked and font, logo and photo selection loaded,
+/// so that a bad argument fails it before anything is copied.
+struct Batch {
+    /// As given, for the state file.
+    args: BatchArgs,
+    prefs: Preferences,
+    src: PathBuf,
+    copies_folder: PathBuf,
+    num_copies: usize,
+    start_number: i32,
+    base_text_without_number: String,
+    add_swap: bool,
+    create_zip: bool,
+    mark_opts: MarkOptions,
+    /// How the visible watermark is saved.
+    save_opts: SaveOptions,
+    font: Option<Font<'static>>,
+    style: WatermarkStyle,
+    watermark_text: String,
+    watermark_position: TextPosition,
+    photo_number: Option<i32>,
+    logo: Option<image::RgbaImage>,
+    overlay_position: TextPosition,
+    logo_style: visible::OverlayStyle,
+    /// Without a selection, only the photo numbered like the order (or `photo_number`)
+    selector: Option<Selector>,
+    /// Random photo picks of copy `i` are seeded from this and `i`, so they
+    /// don't depend on which worker makes the copy.
+    seed: u64,
+    date: String,
+    workers: usize,
+    io_limit: usize,
+    /// When resuming, the copies an earlier run finished, checked to be intact.
+    resumed: Option<std::collections::BTreeMap<String, batch_state::CopyOutput>>,
 }
 
-// ==================== Invisible Watermark Functions ====================
+/// Where the copies of `src` go.
+fn copies_folder_of(src: &Path) -> anyhow::Result<PathBuf> {
+    let name = src.file_name().ok_or_else(|| anyhow!("No folder name in {}", src.display()))?;
+    Ok(src.parent().ok_or_else(|| anyhow!("No parent for source folder"))?
+        .join(format!("{}-Copies", name.to_string_lossy())))
+}
 
-/// Add watermark to the tail/end of a file
-#[tauri::command]
-fn add_tail_watermark(path: String, text: String) -> tauri::Result<bool> {
-    let encoded_text = encode_text_impl(&text);
-    let watermark = format!("{}{}{}", WATERMARK_PREFIX, encoded_text, WATERMARK_SUFFIX);
-    
-    // Read existing file content
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&path)
-        .map_err(|e| anyhow!("Failed to open file {}: {}", path, e))?;
-    
-    // Check if watermark already exists
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .map_err(|e| anyhow!("Failed to read file {}: {}", path, e))?;
-    
-    // Check last TAIL_SCAN_SIZE bytes for existing watermark
-    let tail_start = if buffer.len() > TAIL_SCAN_SIZE {
-        buffer.len() - TAIL_SCAN_SIZE
-    } else {
-        0
-    };
-    let tail_slice = &buffer[tail_start..];
-    
-    // Convert to string for searching (lossy is ok for watermark detection)
-    let tail_str = String::from_utf8_lossy(tail_slice);
-    
-    // Don't add if watermark already exists
-    if tail_str.contains(WATERMARK_PREFIX) || tail_str.contains(OLD_WATERMARK_PREFIX) {
-        return Ok(false);
+impl Batch {
+    /// `prefs` fill in what `args` leave unset.
+    fn prepare(args: BatchArgs, prefs: &Preferences) -> anyhow::Result<Batch> {
+        let src = PathBuf::from(&args.source_folder);
+        Ok(Batch {
+            args: args.clone(),
+            prefs: prefs.clone(),
+            copies_folder: copies_folder_of(&src)?,
+            num_copies: args.num_copies.max(0) as usize,
+            start_number: extract_trailing_number(&args.base_text),
+            base_text_without_number: args.base_text.trim_end_matches(|c: char| c.is_ascii_digit()).trim().to_string(),
+            add_swap: args.add_swap,
+            create_zip: args.create_zip,
+            // Copies are disposable, the source folder is the backup
+            mark_opts: MarkOptions {
+                scheme: args.scheme.unwrap_or_default(),
+                mode: args.mode.unwrap_or_default(),
+                sign: args.sign.unwrap_or(false),
+                keep_backup: false,
+                project_key: prefs.watermark_key_path.clone(),
+            },
+            save_opts: SaveOptions { keep_backup: false, ..prefs.save_options() },
+            font: if args.add_watermark { Some(load_font(args.font_path.or(prefs.watermark_font_path.clone()).as_deref())?) } else { None },
+            style: args.watermark_style.unwrap_or_default(),
+            watermark_text: args.watermark_text.unwrap_or_else(|| "{order}".to_string()),
+            watermark_position: args.watermark_position.unwrap_or_default(),
+            photo_number: args.photo_number,
+            // The logo goes on the same photo as the text, with or without it
+            logo: args.overlay_path.map(|p| load_logo(Path::new(&p))).transpose()?,
+            overlay_position: args.overlay_position.unwrap_or_default(),
+            logo_style: args.overlay_style.unwrap_or_default(),
+            selector: args.photo_selection.as_ref().map(PhotoSelection::compile).transpose()?,
+            seed: args.seed.unwrap_or_else(|| fastrand::u64(..)),
+            date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+            workers: args.workers.or(prefs.batch_workers)
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).max(1),
+            io_limit: args.io_limit.or(prefs.batch_io_limit).unwrap_or(DEFAULT_BATCH_IO_LIMIT).max(1),
+            resumed: None,
+            src,
+        })
     }
-    
-    // Append watermark
-    file.write_all(watermark.as_bytes())
-        .map_err(|e| anyhow!("Failed to write watermark to {}: {}", path, e))?;
-    
-    Ok(true)
-}
 
-/// Check if file has a tail watermark
+    /// The batch whose state file is in the `-Copies` folder of `source_folder`,
+    /// with the same arguments, preferences, seed and date. Copies it finished
+    /// are kept if their files still hash to what they did then.
+    fn resume(source_folder: &str) -> anyhow::Result<Batch> {
+        let state = batch_state::BatchState::load(&copies_folder_of(Path::new(source_folder))?)?;
+        let mut batch = Batch::prepare(BatchArgs { seed: Some(state.seed), ..state.args }, &state.prefs)?;
+        batch.date = state.date;
+        let intact = state.finished.into_iter()
+            .filter(|(order, output)| {
+                (0..batch.num_copies).find(|&i| batch.order_str(i) == *order)
+                    .is_some_and(|i| batch.output(i).is_ok_and(|o| o == *output))
+            })
+            .collect();
+        batch.resumed = Some(intact);
+        Ok(batch)
+    }
+
+    /// Make the copies on `workers` threads, at most `io_limit` of them copying or
+    /// zipping at a time. The output is the same for any number of workers:
+    /// photo picks are seeded per copy and the ledger is written in copy order.
+    /// Raising `cancel` stops the batch at the next file and removes the order
+    /// folders of the copies in progress; a failing copy stops the others the same
+    /// way, but is left as it is.
+    ///
+    /// Finished copies go into the state file as they are recorded. A resumed
+    /// batch skips the copies it kept and makes the others from scratch.
+    fn run(&self, cancel: &AtomicBool, emit: &(dyn Fn(BatchEvent) + Sync)) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.copies_folder)?;
+        // Keys made on first use are made here, not by several workers at once
+        if self.mark_opts.scheme == CipherScheme::Keyed || self.mark_opts.mode == EmbedMode::Lsb {
+            keyed::load_or_create_key(self.mark_opts.project_key.as_deref())?;
+        }
+        if self.mark_opts.sign {
+            signing::signing_key()?;
+        }
+        let kept = self.resumed.clone().unwrap_or_default();
+        if self.resumed.is_some() {
+            // Copies made again would be in the ledger twice
+            let ours: std::collections::HashSet<String> = (0..self.num_copies).map(|i| self.order_str(i)).collect();
+            trim_ledger(&self.copies_folder, |order| ours.contains(order) && !kept.contains_key(order))?;
+        }
+        let mut state = batch_state::BatchState { args: self.args.clone(), prefs: self.prefs.clone(), seed: self.seed, date: self.date.clone(), finished: kept };
+        state.save(&self.copies_folder)?;
+
+        let skip: Vec<bool> = (0..self.num_copies).map(|i| state.finished.contains_key(&self.order_str(i))).collect();
+        let (next, failed, io) = (AtomicUsize::new(0), AtomicBool::new(false), IoSlots::new(self.io_limit));
+        let mut finished: Vec<Option<Option<Vec<PathBuf>>>> = skip.iter().map(|&skip| skip.then_some(None)).collect();
+        for i in (0..self.num_copies).filter(|&i| skip[i]) {
+            emit(BatchEvent::Skipped { copy: i + 1, copies: self.num_copies });
+        }
+        let mut outcome = Ok(());
+
+        std::thread::scope(|scope| {
+            let (done_tx, done_rx) = std::sync::mpsc::channel();
+            for _ in 0..self.workers.min(self.num_copies) {
+                let (done_tx, next, failed, io, skip) = (done_tx.clone(), &next, &failed, &io, &skip);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= self.num_copies { break; }
+                    if skip[i] { continue; }
+                    let report = Reporter { emit, cancel, failed, copy: i + 1, copies: self.num_copies, phase: std::cell::Cell::new(BatchPhase::Copy) };
+                    if report.halted() { break; }
+                    if self.resumed.is_some() {
+                        // Whatever an earlier run left of this copy
+                        let _ = fs::remove_dir_all(self.copies_folder.join(self.order_str(i)));
+                    }
+                    let result = match self.make_copy(i, &report, io) {
+                        Err(_) if report.halted() => {
+                            let _ = fs::remove_dir_all(self.copies_folder.join(self.order_str(i)));
+                            continue;
+                        }
+                        Err(e) => {
+                            failed.store(true, Ordering::Relaxed);
+                            Err(report.fail(e))
+                        }
+                        Ok(photos) => Ok(photos),
+                    };
+                    let _ = done_tx.send((i, result));
+                });
+            }
+            drop(done_tx);
+
+            // Ledger lines go in copy order, whatever order the copies finish in
+            let mut written = 0;
+            for (i, result) in done_rx {
+                match result {
+                    Ok(photos) => finished[i] = Some(photos),
+                    Err(e) => if outcome.is_ok() { outcome = Err(e) },
+                }
+                while let Some(Some(photos)) = finished.get(written) {
+                    if !skip[written] {
+                        if let Err(e) = self.record(written, photos.as_deref(), &mut state) {
+                            emit(BatchEvent::Error { phase: BatchPhase::Watermark, copy: written + 1, copies: self.num_copies, message: e.to_string() });
+                            failed.store(true, Ordering::Relaxed);
+                            if outcome.is_ok() { outcome = Err(e) }
+                        }
+                    }
+                    written += 1;
+                }
+            }
+            // Copies finished after one that was stopped
+            for (i, photos) in finished.iter().enumerate().skip(written) {
+                if let (Some(photos), false) = (photos, skip[i]) { let _ = self.record(i, photos.as_deref(), &mut state); }
+            }
+        });
+
+        outcome?;
+        if let Some(first) = finished.iter().position(Option::is_none) {
+            emit(BatchEvent::Cancelled { copy: first + 1, copies: self.num_copies });
+            return Err(anyhow!("Batch cancelled"));
+        }
+        emit(BatchEvent::Done { copies: self.num_copies, output: self.copies_folder.to_string_lossy().to_string() });
+        Ok(())
+    }
+
+    fn order_str(&self, i: usize) -> String {
+        format!("{:03}", self.start_number + i as i32)
+    }
+
+    /// The folder copy `i` is made in.
+    fn destination(&self, i: usize) -> PathBuf {
+        self.copies_folder.join(self.order_str(i)).join(self.src.file_name().unwrap())
+    }
+
+    /// What copy `i` has left on disk.
+    fn output(&self, i: usize) -> anyhow::Result<batch_state::CopyOutput> {
+        let folder = self.destination(i);
+        if self.create_zip {
+            batch_state::CopyOutput::of_file(&folder.with_file_name(format!("{}.zip", folder.file_name().unwrap().to_string_lossy())))
+        } else {
+            batch_state::CopyOutput::of_folder(&folder)
+        }
+    }
+
+    /// Add finished copy `i`'s visibly watermarked photos to the ledger, then
+    /// the copy to the state file.
+    fn record(&self, i: usize, photos: Option<&[PathBuf]>, state: &mut batch_state::BatchState) -> anyhow::Result<()> {
+        let order_str = self.order_str(i);
+        if let Some(photos) = photos {
+            record_visible(&self.copies_folder, &self.destination(i), &order_str, photos)?;
+        }
+        state.finished.insert(order_str, self.output(i)?);
+        state.save(&self.copies_folder)
+    }
+
+    /// Copy, watermark, mark, swap and zip copy number `i` (from 0). Returns the
+    /// photos that got a visible watermark, if that step ran.
+    fn make_copy(&self, i: usize, report: &Reporter, io: &IoSlots) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        use fs_extra::dir::{copy_with_progress, CopyOptions, TransitProcessResult};
+
+        let order = self.start_number + i as i32;
+        let order_str = self.order_str(i);
+        let order_folder = self.copies_folder.join(&order_str);
+        fs::create_dir_all(&order_folder)?;
+        let destination_folder = order_folder.join(self.src.file_name().unwrap());
+
+        let mut opts = CopyOptions::new();
+        opts.overwrite = true;
+        opts.copy_inside = true;
+        let (files, total_bytes) = tree_size(&self.src);
+        let (mut file, mut current, mut last) = (0, String::new(), std::time::Instant::now());
+        let slot = io.acquire();
+        copy_with_progress(&self.src, &destination_folder, &opts, |t| {
+            if report.halted() { return TransitProcessResult::Abort; }
+            let next = t.file_name != current;
+            if next {
+                current = t.file_name;
+                file += 1;
+            }
+            if next || last.elapsed() >= PROGRESS_INTERVAL {
+                let _ = report.progress(file, files, t.copied_bytes, total_bytes);
+                last = std::time::Instant::now();
+            }
+            TransitProcessResult::ContinueOrAbort
+        }).map_err(|e| anyhow!(e))?;
+        drop(slot);
+        report.progress(files, files, total_bytes, total_bytes)?;
+        report.log(format!("Copied {} files to {}", files, order_folder.display()));
+
+        let mut marked_photos = None;
+        if self.font.is_some() || self.logo.is_some() {
+            report.enter(BatchPhase::Watermark);
+            let photos = self.visible_photos(i, &destination_folder)?;
+            let sizes: Vec<u64> = photos.iter().map(|p| fs::metadata(p).map_or(0, |m| m.len())).collect();
+            let total_bytes = sizes.iter().sum();
+            let vars = LabelVars { order: &order_str, base_text: &self.base_text_without_number, index: i + 1, date: &self.date };
+            let mut bytes = 0;
+            for (k, path) in photos.iter().enumerate() {
+                edit_image(path, self.save_opts, |img| {
+                    if let Some(font) = &self.font {
+                        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+                        let text = expand_label(&self.watermark_text, &vars, &name);
+                        visible::draw(img, &text, self.watermark_position, &self.style, font)?;
+                    }
+                    if let Some(logo) = &self.logo {
+                        visible::overlay(img, logo, self.overlay_position, &self.logo_style)?;
+                    }
+                    Ok(())
+                })?;
+                bytes += sizes[k];
+                report.progress(k + 1, photos.len(), bytes, total_bytes)?;
+            }
+            report.log(format!("Visible watermark on {} photos", photos.len()));
+            marked_photos = Some(photos);
+        }
+
+        // Markers go in after the visible watermark, whose re-encode would drop them
+        report.enter(BatchPhase::Mark);
+        process_files(&destination_folder, &self.base_text_without_number, &order_str, &self.mark_opts, report)?;
+
+        if self.add_swap {
+            report.enter(BatchPhase::Swap);
+            let (base_num, swap_num) = (order, order + 10);
+            match self.swap_pair(i, &destination_folder)? {
+                (Some(a), Some(b)) => {
+                    swap_files(&a, &b)?;
+                    report.log(format!("Swapped photos {} and {}", base_num, swap_num));
+                }
+                _ => report.log(format!("No photos {} and {} to swap", base_num, swap_num)),
+            }
+        }
+
+        if self.create_zip {
+            report.enter(BatchPhase::Zip);
+            let _slot = io.acquire();
+            let zip_path = create_zip_stored(&destination_folder, report)?;
+            let _ = fs::remove_dir_all(&destination_folder);
+            report.log(format!("Created {}", zip_path.display()));
+        }
+        Ok(marked_photos)
+    }
+
+    /// Photos under `folder` (a copy, or the source when planning) that copy `i`
+    /// gives the visible watermark.
+    fn visible_photos(&self, i: usize, folder: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut rng = fastrand::Rng::with_seed(self.seed ^ (i as u64).wrapping_mul(0x9E3779B97F4A7C15));
+        Ok(match &self.selector {
+            Some(selector) => select_photos(folder, selector, &mut rng)?,
+            None => {
+                let n = self.photo_number.unwrap_or(self.start_number + i as i32);
+                let mut first = select_photos(folder, &Selector::Numbers(vec![n..=n]), &mut rng)?;
+                first.truncate(1);
+                first
+            }
+        })
+    }
+
+    /// The images under `folder` numbered like copy `i`'s order and 10 higher,
+    /// which the swap step exchanges.
+    fn swap_pair(&self, i: usize, folder: &Path) -> anyhow::Result<(Option<PathBuf>, Option<PathBuf>)> {
+        let base_num = self.start_number + i as i32;
+        let images: Vec<PathBuf> = get_supported_files(folder.to_string_lossy().to_string()).map_err(|e| anyhow!(e))?
+            .into_iter().map(PathBuf::from).filter(|p| is_image_file(p)).collect();
+        let numbered = |n: i32| images.iter()
+            .find(|p| extract_file_number(p.file_name().unwrap().to_string_lossy().as_ref()) == Some(n)).cloned();
+        Ok((numbered(base_num), numbered(base_num + 10)))
+    }
+
+    /// What `run` would do, worked out from the source folder alone. Nothing is
+    /// written. Of the sources, only the ends where markers go are read (and the
+    /// container structure of formats with structured markers, or the header of
+    /// images for LSB ones); pixels aren't decoded, so a file that only has an
+    /// LSB marker is planned as unmarked.
+    fn plan(&self) -> anyhow::Result<BatchPlan> {
+        let mut warnings = Vec::new();
+        let supported: std::collections::HashSet<PathBuf> = get_supported_files(self.src.to_string_lossy().to_string())
+            .map_err(|e| anyhow!(e))?.into_iter().map(PathBuf::from).collect();
+
+        let mut sources = Vec::new();
+        let mut already_marked = 0;
+        // The longest marker text, for whether markers fit
+        let text = format!("{} {}", self.base_text_without_number, self.order_str(self.num_copies.saturating_sub(1)));
+        for entry in WalkDir::new(&self.src).sort_by_file_name().into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+            let path = entry.path().to_path_buf();
+            let rel = path.strip_prefix(&self.src).unwrap().to_string_lossy().replace('\\', "/");
+            // Files that already carry a marker are left alone, like in `process_files`
+            let marker = supported.contains(&path) && match read_marker(&path, false, self.mark_opts.project_key.as_deref())? {
+                FoundMarker::None => true,
+                FoundMarker::Corrupt(msg) => {
+                    warnings.push(format!("{} has a corrupted watermark, marking it will fail: {}", rel, msg));
+                    true
+                }
+                _ => {
+                    already_marked += 1;
+                    false
+                }
+            };
+            if marker && self.mark_opts.sign && self.mark_opts.mode == EmbedMode::Lsb && is_lossless_image(&path) {
+                warnings.push(format!("{} gets an LSB watermark, which can't be signed; marking it will fail", rel));
+            }
+            if let Some(reason) = marker.then(|| tail_fallback(&path, &text, &self.mark_opts)).flatten() {
+                warnings.push(format!("{}: {}, it gets a tail marker instead", rel, reason));
+            }
+            sources.push((path, rel, entry.metadata().map_or(0, |m| m.len()), marker));
+        }
+        if sources.is_empty() {
+            warnings.push(format!("{} has no files to copy", self.src.display()));
+        }
+        if already_marked > 0 {
+            warnings.push(format!("{} files already carry a watermark and keep it", already_marked));
+        }
+        let bytes_per_copy: u64 = sources.iter().map(|(_, _, bytes, _)| bytes).sum();
+
+        let inside = |path: &Path| path.strip_prefix(&self.src).unwrap_or(path).to_string_lossy().replace('\\', "/");
+        let copy_name = self.src.file_name().unwrap();
+        let mut copies = Vec::with_capacity(self.num_copies);
+        for i in 0..self.num_copies {
+            let order = self.order_str(i);
+            let order_folder = self.copies_folder.join(&order);
+            if order_folder.exists() {
+                warnings.push(format!("Copy {}: {} already exists, its files will be overwritten", order, order_folder.display()));
+            }
+
+            let mut visible = Vec::new();
+            if self.font.is_some() || self.logo.is_some() {
+                visible = self.visible_photos(i, &self.src)?.iter().map(|p| inside(p)).collect();
+                match &self.selector {
+                    None if visible.is_empty() => {
+                        let n = self.photo_number.unwrap_or(self.start_number + i as i32);
+                        warnings.push(format!("Copy {}: no photo {} for the visible watermark", order, n));
+                    }
+                    // The same photos for every copy, so one warning will do
+                    Some(Selector::Random(count)) if i == 0 && visible.len() < *count => {
+                        warnings.push(format!("Only {} images to pick {} at random from", visible.len(), count));
+                    }
+                    Some(Selector::All | Selector::Numbers(_) | Selector::Glob(_)) if i == 0 && visible.is_empty() => {
+                        warnings.push("The photo selection matches no images".to_string());
+                    }
+                    _ => {}
+                }
+            }
+
+            let mut swap = None;
+            if self.add_swap {
+                let base_num = self.start_number + i as i32;
+                match self.swap_pair(i, &self.src)? {
+                    (Some(a), Some(b)) => swap = Some((inside(&a), inside(&b))),
+                    (Some(_), None) => warnings.push(format!("Copy {}: no photo {} to swap with photo {}", order, base_num + 10, base_num)),
+                    (None, Some(_)) => warnings.push(format!("Copy {}: no photo {} to swap with photo {}", order, base_num, base_num + 10)),
+                    (None, None) => warnings.push(format!("Copy {}: no photos {} and {} to swap", order, base_num, base_num + 10)),
+                }
+            }
+
+            let files = sources.iter().map(|(_, rel, bytes, marker)| FilePlan {
+                path: rel.clone(),
+                bytes: *bytes,
+                marker: *marker,
+                visible_watermark: visible.contains(rel),
+                swapped_with: swap.as_ref().and_then(|(a, b)| {
+                    if rel == a { Some(b.clone()) } else if rel == b { Some(a.clone()) } else { None }
+                }),
+            }).collect();
+            let folder = order_folder.join(copy_name);
+            copies.push(CopyPlan {
+                order,
+                zip: self.create_zip.then(|| format!("{}.zip", folder.to_string_lossy())),
+                folder: folder.to_string_lossy().to_string(),
+                files,
+            });
+        }
+
+        let disk_bytes = bytes_per_copy * self.num_copies as u64;
+        // A copy being zipped is on disk twice until its folder is removed, and
+        // zipping takes one of the I/O slots
+        let zipping = if self.create_zip { self.workers.min(self.io_limit).min(self.num_copies) as u64 } else { 0 };
+        Ok(BatchPlan {
+            copies_folder: self.copies_folder.to_string_lossy().to_string(),
+            seed: self.seed,
+            bytes_per_copy,
+            disk_bytes,
+            peak_disk_bytes: disk_bytes + zipping * bytes_per_copy,
+            copies,
+            warnings,
+        })
+    }
+}
+
+/// What a batch would do, from `plan_batch`.
+#[derive(serde::Serialize, Debug)]
+struct BatchPlan {
+    copies_folder: String,
+    /// Pass it to `batch_copy_and_encode` to get the same random photo picks.
+    seed: u64,
+    bytes_per_copy: u64,
+    /// Space the copies take in the end, not counting the few bytes of markers.
+    disk_bytes: u64,
+    /// Space needed while the last copies are zipped.
+    peak_disk_bytes: u64,
+    copies: Vec<CopyPlan>,
+    /// Things that will fail or not happen as asked, worth a look before running.
+    warnings: Vec<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct CopyPlan {
+    order: String,
+    folder: String,
+    /// The archive that replaces `folder` when zipping.
+    zip: Option<String>,
+    files: Vec<FilePlan>,
+}
+
+/// One file of a copy and what happens to it, after it is copied.
+#[derive(serde::Serialize, Debug)]
+struct FilePlan {
+    /// Path inside the copy, with `/` separators.
+    path: String,
+    bytes: u64,
+    /// Gets a tail marker.
+    marker: bool,
+    visible_watermark: bool,
+    /// Trades places with this file in the swap step.
+    swapped_with: Option<String>,
+}
+
+/// Make `num_copies` numbered copies of `source_folder` in `<source>-Copies` in
+/// a background job. Returns the job ID; progress comes as `BATCH_EVENT` events.
 #[tauri::command]
-fn has_tail_watermark(path: String) -> tauri::Result<bool> {
-    let mut file = fs::File::open(&path)
-        .map_err(|e| anyhow!("Failed to open file {}: {}", path, e))?;
-    
-    // Read last TAIL_SCAN_SIZE bytes
-    let metadata = file.metadata()
-        .map_err(|e| anyhow!("Failed to get file metadata {}: {}", path, e))?;
-    
-    let file_size = metadata.len() as usize;
-    if file_size == 0 {
-        return Ok(false);
+fn batch_copy_and_encode(app: tauri::AppHandle, jobs: tauri::State<'_, jobs::Jobs>, args: BatchArgs) -> tauri::Result<u64> {
+    // Bad arguments fail the command itself, before there is a job
+    let batch = Batch::prepare(args, &Preferences::load())?;
+    Ok(start_job(app, &jobs, batch))
+}
+
+/// Carry on with the batch of `source_folder` after a crash or cancellation,
+/// from the state file in its `-Copies` folder. Copies that finished and are
+/// still intact are skipped, the others made again. Returns the job ID, like
+/// `batch_copy_and_encode`.
+#[tauri::command]
+fn resume_batch(app: tauri::AppHandle, jobs: tauri::State<'_, jobs::Jobs>, source_folder: String) -> tauri::Result<u64> {
+    let batch = Batch::resume(&source_folder)?;
+    Ok(start_job(app, &jobs, batch))
+}
+
+/// Run `batch` on a thread of its own as a new job.
+fn start_job(app: tauri::AppHandle, jobs: &jobs::Jobs, batch: Batch) -> u64 {
+    use tauri::Emitter;
+
+    let job = jobs.start(&batch.src.to_string_lossy(), batch.num_copies);
+    let id = job.id;
+    std::thread::spawn(move || {
+        let result = batch.run(job.cancel_flag(), &|event| {
+            job.record(&event);
+            let _ = app.emit(BATCH_EVENT, JobEvent { job: job.id, event });
+        });
+        job.finish(&result);
+    });
+    id
+}
+
+/// What `batch_copy_and_encode` would do with the same arguments: the order
+/// folders, what happens to each file, the disk space needed and anything that
+/// won't go as asked. Writes nothing. Walking a large source folder takes a
+/// while, so it runs on a blocking thread rather than the main one.
+#[tauri::command]
+async fn plan_batch(args: BatchArgs) -> tauri::Result<BatchPlan> {
+    Ok(tauri::async_runtime::spawn_blocking(move || Batch::prepare(args, &Preferences::load())?.plan()).await??)
+}
+
+/// Ask a running job to stop; false if it had already finished.
+#[tauri::command]
+fn cancel_job(jobs: tauri::State<'_, jobs::Jobs>, id: u64) -> tauri::Result<bool> {
+    let job = jobs.get(id).ok_or_else(|| anyhow!("No job {}", id))?;
+    Ok(job.cancel())
+}
+
+#[tauri::command]
+fn list_jobs(jobs: tauri::State<'_, jobs::Jobs>) -> Vec<jobs::JobStatus> {
+    jobs.list()
+}
+
+#[tauri::command]
+fn job_status(jobs: tauri::State<'_, jobs::Jobs>, id: u64) -> tauri::Result<jobs::JobStatus> {
+    Ok(jobs.get(id).ok_or_else(|| anyhow!("No job {}", id))?.status())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+struct Preferences {
+    theme_mode: Option<String>,
+    auto_clear_console: Option<bool>,
+    last_selected_path: Option<String>,
+    /// Key file for the keyed scheme; unset means the per-installation key.
+    watermark_key_path: Option<String>,
+    /// Keep `<name>.bak` of files changed in place by the single-file commands.
+    keep_backups: Option<bool>,
+    /// Font file for visible watermarks; unset means the bundled DejaVu Sans.
+    watermark_font_path: Option<String>,
+    /// Quality for re-encoded JPEGs, 1-100; unset means the original's estimated quality.
+    jpeg_quality: Option<u8>,
+    /// When drawing on EXIF-rotated photos, store the pixels upright and reset the
+    /// orientation tag instead of keeping the camera's orientation.
+    upright_images: Option<bool>,
+    /// Copies a batch works on at once; unset means one per CPU core.
+    batch_workers: Option<usize>,
+    /// How many of those may be copying or zipping files at the same time; unset means 2.
+    batch_io_limit: Option<usize>,
+}
+
+fn prefs_path() -> anyhow::Result<PathBuf> {
+    Ok(config_dir().ok_or_else(|| anyhow!("no config dir"))?.join("endecode").join("preferences.json"))
+}
+
+impl Preferences {
+    /// The stored preferences, or the defaults when they can't be read. Commands
+    /// load them once and pass down what they need.
+    fn load() -> Preferences {
+        load_preferences().unwrap_or_default()
     }
-    
-    let scan_size = TAIL_SCAN_SIZE.min(file_size);
-    let offset = file_size - scan_size;
-    
-    file.seek(SeekFrom::Start(offset as u64))
-        .map_err(|e| anyhow!("Failed to seek in file {}: {}", path, e))?;
-    
-    let mut buffer = vec![0u8; scan_size];
-    file.read_exact(&mut buffer)
-        .map_err(|e| anyhow!("Failed to read from file {}: {}", path, e))?;
-    
-    let content = String::from_utf8_lossy(&buffer);
-    Ok(content.contains(WATERMARK_PREFIX) || content.contains(OLD_WATERMARK_PREFIX))
+
+    fn key_path(&self) -> Option<&str> {
+        self.watermark_key_path.as_deref()
+    }
+
+    fn keep_backups(&self) -> bool {
+        self.keep_backups.unwrap_or(false)
+    }
+
+    fn save_options(&self) -> SaveOptions {
+        SaveOptions {
+            keep_backup: self.keep_backups(),
+            jpeg_quality: self.jpeg_quality.map(|q| q.clamp(1, 100)),
+            upright: self.upright_images.unwrap_or(false),
+        }
+    }
+}
+
+/// How edited images are written back, see `save_image`.
+#[derive(Clone, Copy, Default)]
+struct SaveOptions {
+    /// Keep the original as `<name>.bak`.
+    keep_backup: bool,
+    /// Unset means the original's estimated quality.
+    jpeg_quality: Option<u8>,
+    /// Store EXIF-rotated photos upright, see `edit_image`.
+    upright: bool,
 }
 
-/// Extract watermark from file tail
 #[tauri::command]
-fn extract_tail_watermark(path: String) -> tauri::Result<Option<String>> {
-    let mut file = fs::File::open(&path)
-        .map_err(|e| anyhow!("Failed to open file {}: {}", path, e))?;
-    
-    // Read last TAIL_SCAN_SIZE bytes
-    let metadata = file.metadata()
-        .map_err(|e| anyhow!("Failed to get file metadata {}: {}", path, e))?;
-    
-    let file_size = metadata.len() as usize;
-    if file_size == 0 {
-        return Ok(None);
+fn load_preferences() -> tauri::Result<Preferences> {
+    let p = prefs_path().map_err(|e| anyhow!(e))?;
+    if !p.exists() { return Ok(Preferences::default()); }
+    let data = fs::read(&p).map_err(|e| anyhow!(e))?;
+    let prefs: Preferences = serde_json::from_slice(&data).map_err(|e| anyhow!(e))?;
+    Ok(prefs)
+}
+
+/// Store the preferences in `prefs` over the stored ones. Preferences it leaves
+/// out keep their stored value, so a frontend that doesn't know of them can't
+/// erase them.
+#[tauri::command]
+fn save_preferences(prefs: serde_json::Map<String, serde_json::Value>) -> tauri::Result<bool> {
+    let p = prefs_path().map_err(|e| anyhow!(e))?;
+    write_preferences(&p, prefs)?;
+    Ok(true)
+}
+
+fn write_preferences(path: &Path, prefs: serde_json::Map<String, serde_json::Value>) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() { fs::create_dir_all(dir)?; }
+    let mut merged = match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+        Err(_) => serde_json::Map::new(),
+    };
+    merged.extend(prefs);
+    let merged: Preferences = serde_json::from_value(serde_json::Value::Object(merged))?;
+    fs::write(path, serde_json::to_vec_pretty(&merged)?)?;
+    Ok(())
+}
+
+// ==================== Invisible Watermark Functions ====================
+
+/// Where the marker goes.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum EmbedMode {
+    /// Binary trailer after the end of the file.
+    #[default]
+    Tail,
+    /// Inside the container (PNG chunk, JPEG comment, MP4 box, Matroska tag);
+    /// files without a supported container get a tail marker.
+    Structured,
+    /// In the pixel LSBs of PNG, BMP, TIFF and lossless WebP images, keyed with the watermark key; other files
+    /// get a tail marker. LSB markers can't be signed and are not removed.
+    Lsb,
+}
+
+/// How tail markers are written.
+#[derive(Clone, Default)]
+struct MarkOptions {
+    scheme: CipherScheme,
+    mode: EmbedMode,
+    /// Sign the marker and the original content with the local Ed25519 key.
+    sign: bool,
+    /// Keep the unmarked file as `<name>.bak`.
+    keep_backup: bool,
+    /// Key file for the keyed scheme and LSB markers; unset means the per-installation key.
+    project_key: Option<String>,
+}
+
+/// Why `p` can't take a marker with `text` the way `opts.mode` says. In
+/// structured mode `add_tail_marker` then appends a tail marker itself; an
+/// image too small or too deep for an LSB marker is refused by it, so batches
+/// ask for a tail marker instead.
+fn tail_fallback(p: &PathBuf, text: &str, opts: &MarkOptions) -> Option<String> {
+    let format = image_format(p);
+    match opts.mode {
+        EmbedMode::Structured => {
+            let c = container_for_format(p, format)?;
+            container::check_appendable(p, c).err().map(|e| e.to_string())
+        }
+        EmbedMode::Lsb if is_lossless(p, format) => {
+            let len = lsb_marker_len(text, opts.scheme).ok()?;
+            check_lsb_capacity(p, format?, len).err().map(|e| e.to_string())
+        }
+        _ => None,
     }
-    
-    let scan_size = TAIL_SCAN_SIZE.min(file_size);
-    let offset = file_size - scan_size;
-    
-    file.seek(SeekFrom::Start(offset as u64))
-        .map_err(|e| anyhow!("Failed to seek in file {}: {}", path, e))?;
-    
-    let mut buffer = vec![0u8; scan_size];
-    file.read_exact(&mut buffer)
-        .map_err(|e| anyhow!("Failed to read from file {}: {}", path, e))?;
-    
-    let content = String::from_utf8_lossy(&buffer);
-    
-    // Try new format first: <<==ENCODED_TEXT==>>
-    if let Some(start_pos) = content.rfind(WATERMARK_PREFIX) {
-        let after_prefix = &content[start_pos + WATERMARK_PREFIX.len()..];
-        if let Some(end_pos) = after_prefix.find(WATERMARK_SUFFIX) {
-            let encoded = &after_prefix[..end_pos];
-            let decoded = decode_text_impl(encoded);
-            return Ok(Some(decoded));
+}
+
+fn add_tail_marker(p: &PathBuf, text: &str, opts: &MarkOptions) -> anyhow::Result<bool> {
+    if !p.exists() {
+        fs::File::create(p).map_err(|e| anyhow!("Failed to create file {}: {}", p.display(), e))?;
+    }
+
+    // Don't add if watermark already exists; the pixels are only decoded to look
+    // for an LSB marker when this would add one
+    let format = image_format(p);
+    match read_marker_as(p, format, opts.mode == EmbedMode::Lsb, opts.project_key.as_deref())? {
+        FoundMarker::None => {}
+        FoundMarker::Corrupt(msg) => return Err(anyhow!("Corrupted watermark in {}: {}", p.display(), msg)),
+        _ => return Ok(false),
+    }
+
+    let lsb = opts.mode == EmbedMode::Lsb && is_lossless(p, format);
+    if lsb && opts.sign {
+        return Err(anyhow!("LSB watermarks can't be signed: {}", p.display()));
+    }
+
+    let scheme_id = opts.scheme.id();
+    let encoded_text = encode_text_impl(text, opts.scheme, opts.project_key.as_deref())?;
+    let mut fields: Vec<(u8, Vec<u8>)> = vec![(marker::FIELD_TEXT, encoded_text.into_bytes())];
+    if opts.sign {
+        let key = signing::signing_key()?;
+        let content_hash = signing::hash_file_prefix(p, fs::metadata(p)?.len())?;
+        let signature = signing::sign(&key, scheme_id, &fields[0].1, &content_hash);
+        fields.push((marker::FIELD_CONTENT_HASH, content_hash.to_vec()));
+        fields.push((marker::FIELD_SIGNATURE, signature.to_vec()));
+        fields.push((marker::FIELD_SIGNER, key.verifying_key().to_bytes().to_vec()));
+    }
+    let field_refs: Vec<(u8, &[u8])> = fields.iter().map(|(t, v)| (*t, v.as_slice())).collect();
+    let trailer = marker::encode(scheme_id, &field_refs)?;
+
+    if lsb {
+        let key = keyed::load_or_create_key(opts.project_key.as_deref())?;
+        embed_lsb_marker(p, &trailer, lsb::seed(&key), SaveOptions { keep_backup: opts.keep_backup, ..SaveOptions::default() })?;
+        return Ok(true);
+    }
+    if opts.mode == EmbedMode::Structured {
+        // Files whose container can't take one get the tail marker, see `tail_fallback`
+        if let Some(c) = container_for_format(p, format).filter(|&c| container::check_appendable(p, c).is_ok()) {
+            container::embed(p, c, &trailer, opts.keep_backup)
+                .map_err(|e| anyhow!("Failed to write watermark to {}: {}", p.display(), e))?;
+            return Ok(true);
         }
     }
-    
-    // Try old format: */ENCODED_TEXT (at end of file)
-    if let Some(start_pos) = content.rfind(OLD_WATERMARK_PREFIX) {
-        let encoded = &content[start_pos + OLD_WATERMARK_PREFIX.len()..];
-        let decoded = decode_text_impl(encoded.trim());
-        return Ok(Some(decoded));
+
+    atomic::append(p, opts.keep_backup, &trailer, None)
+        .map_err(|e| anyhow!("Failed to write watermark to {}: {}", p.display(), e))?;
+
+    Ok(true)
+}
+
+/// Add watermark to the tail/end of a file
+#[tauri::command]
+fn add_tail_watermark(path: String, text: String, scheme: Option<CipherScheme>, sign: Option<bool>, mode: Option<EmbedMode>) -> tauri::Result<bool> {
+    let prefs = Preferences::load();
+    let opts = MarkOptions {
+        scheme: scheme.unwrap_or_default(),
+        mode: mode.unwrap_or_default(),
+        sign: sign.unwrap_or(false),
+        keep_backup: prefs.keep_backups(),
+        project_key: prefs.watermark_key_path,
+    };
+    Ok(add_tail_marker(&PathBuf::from(&path), &text, &opts)?)
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum SignatureStatus {
+    Valid,
+    Invalid,
+    Unsigned,
+}
+
+#[derive(serde::Serialize)]
+struct VerifyReport {
+    status: SignatureStatus,
+    /// Why the marker is invalid or unsigned.
+    reason: Option<String>,
+}
+
+impl VerifyReport {
+    fn new(status: SignatureStatus, reason: Option<&str>) -> Self {
+        VerifyReport { status, reason: reason.map(str::to_string) }
     }
-    
-    Ok(None)
+}
+
+/// Check the signature of a tail marker. Verifies against `public_key` (base64)
+/// if given, otherwise against this installation's signing key, which is not
+/// created here if there is none. `lsb` also looks for an (always unsigned) LSB
+/// marker, as in `extract_tail_watermark`.
+#[tauri::command]
+fn verify_tail_watermark(path: String, public_key: Option<String>, lsb: Option<bool>) -> tauri::Result<VerifyReport> {
+    Ok(verify_marker(&PathBuf::from(&path), public_key, lsb.unwrap_or(false), Preferences::load().key_path())?)
+}
+
+fn verify_marker(p: &PathBuf, public_key: Option<String>, lsb: bool, project_key: Option<&str>) -> anyhow::Result<VerifyReport> {
+    let (trailer, embedded) = match read_marker(p, lsb, project_key)? {
+        FoundMarker::Trailer(t) => (t, None),
+        FoundMarker::Structured(c, e, t) => (t, Some((c, e))),
+        FoundMarker::Legacy(_) => return Ok(VerifyReport::new(SignatureStatus::Unsigned, Some("legacy text marker"))),
+        FoundMarker::Lsb(_) => return Ok(VerifyReport::new(SignatureStatus::Unsigned, Some("LSB marker"))),
+        FoundMarker::Corrupt(msg) => return Ok(VerifyReport::new(SignatureStatus::Invalid, Some(&format!("corrupted marker: {}", msg)))),
+        FoundMarker::None => return Ok(VerifyReport::new(SignatureStatus::Unsigned, Some("no watermark"))),
+    };
+    let (Some(text), Some(content_hash), Some(signature)) = (
+        trailer.field(marker::FIELD_TEXT),
+        trailer.field(marker::FIELD_CONTENT_HASH),
+        trailer.field(marker::FIELD_SIGNATURE),
+    ) else {
+        return Ok(VerifyReport::new(SignatureStatus::Unsigned, None));
+    };
+
+    let key = match public_key {
+        Some(b64) => signing::parse_public_key(&b64)?,
+        None => signing::load_signing_key()?.verifying_key(),
+    };
+    if trailer.field(marker::FIELD_SIGNER).is_some_and(|s| s != key.as_bytes()) {
+        return Ok(VerifyReport::new(SignatureStatus::Invalid, Some("signed by a different key")));
+    }
+    if !signing::verify(&key, trailer.scheme, text, content_hash, signature) {
+        return Ok(VerifyReport::new(SignatureStatus::Invalid, Some("signature does not match")));
+    }
+
+    let original_hash = match embedded {
+        Some((c, e)) => container::original_hash(p, c, &e)?,
+        None => {
+            let content_len = fs::metadata(p)?.len() - trailer.total_len as u64;
+            signing::hash_file_prefix(p, content_len)?
+        }
+    };
+    if original_hash != content_hash {
+        return Ok(VerifyReport::new(SignatureStatus::Invalid, Some("file content was modified")));
+    }
+    Ok(VerifyReport::new(SignatureStatus::Valid, None))
+}
+
+/// Public half of the signing key, to hand to whoever has to check signatures.
+#[tauri::command]
+fn export_signing_public_key() -> tauri::Result<String> {
+    Ok(signing::public_key_base64()?)
+}
+
+/// Check if file has a tail watermark (a corrupted one counts); `lsb` as in
+/// `extract_tail_watermark`.
+#[tauri::command]
+fn has_tail_watermark(path: String, lsb: Option<bool>) -> tauri::Result<bool> {
+    Ok(!matches!(read_marker(&PathBuf::from(&path), lsb.unwrap_or(false), Preferences::load().key_path())?, FoundMarker::None))
+}
+
+/// Extract watermark from file tail. With `lsb`, lossless images are also looked
+/// at for an LSB marker, which means decoding the whole image.
+#[tauri::command]
+fn extract_tail_watermark(path: String, lsb: Option<bool>) -> tauri::Result<Option<String>> {
+    Ok(extract_marker(&PathBuf::from(&path), lsb.unwrap_or(false), Preferences::load().key_path())?)
+}
+
+fn extract_marker(p: &PathBuf, lsb: bool, project_key: Option<&str>) -> anyhow::Result<Option<String>> {
+    decode_marker(read_marker(p, lsb, project_key)?, p, project_key)
+}
+
+#[derive(serde::Serialize)]
+struct ExtractReport {
+    text: Option<String>,
+    /// Damaged marker bytes that were repaired.
+    corrected_symbols: usize,
+    /// 1 for an undamaged marker, falling towards 0 as the repairs approach what
+    /// its parity can fix; `None` for markers without parity (legacy ones).
+    confidence: Option<f32>,
+}
+
+/// Like `extract_tail_watermark`, also reporting how much of the marker had to be repaired.
+#[tauri::command]
+fn inspect_tail_watermark(path: String, lsb: Option<bool>) -> tauri::Result<ExtractReport> {
+    Ok(inspect_marker(&PathBuf::from(&path), lsb.unwrap_or(false), Preferences::load().key_path())?)
+}
+
+fn inspect_marker(p: &PathBuf, lsb: bool, project_key: Option<&str>) -> anyhow::Result<ExtractReport> {
+    let found = read_marker(p, lsb, project_key)?;
+    let (corrected, correctable) = match &found {
+        FoundMarker::Trailer(t) | FoundMarker::Structured(_, _, t) | FoundMarker::Lsb(t) => (t.corrected, t.correctable),
+        _ => (0, 0),
+    };
+    let text = decode_marker(found, p, project_key)?;
+    let confidence = match (&text, correctable) {
+        (None, _) => Some(0.0),
+        (Some(_), 0) => None,
+        (Some(_), n) => Some(ecc::confidence(corrected, n)),
+    };
+    Ok(ExtractReport { text, corrected_symbols: corrected, confidence })
 }
 
 /// Remove tail watermarks from all supported files in directory
@@ -494,6 +1934,7 @@ fn remove_tail_watermarks(dir: String) -> tauri::Result<String> {
         return Err(anyhow!("Directory does not exist: {}", dir).into());
     }
     
+    let keep_backup = Preferences::load().keep_backups();
     let mut processed_count = 0;
     let mut removed_count = 0;
     let mut error_count = 0;
@@ -514,7 +1955,7 @@ fn remove_tail_watermarks(dir: String) -> tauri::Result<String> {
         
         processed_count += 1;
         
-        match remove_watermark_from_file(&path_str) {
+        match remove_watermark_from_file(&path_str, keep_backup) {
             Ok(true) => removed_count += 1,
             Ok(false) => {}, // No watermark found, that's ok
             Err(_) => error_count += 1,
@@ -530,63 +1971,54 @@ fn remove_tail_watermarks(dir: String) -> tauri::Result<String> {
 }
 
 /// Helper function to remove watermark from a single file
-fn remove_watermark_from_file(path: &str) -> anyhow::Result<bool> {
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(path)?;
-    
-    // Read entire file
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    
-    if buffer.is_empty() {
-        return Ok(false);
-    }
-    
-    // Convert to string (using lossy conversion for watermark detection)
-    let content = String::from_utf8_lossy(&buffer);
-    let mut modified = false;
-    let mut new_content = content.to_string();
-    
-    // Remove new format watermarks: <<==...==>>
-    while let Some(start_pos) = new_content.rfind(WATERMARK_PREFIX) {
-        if let Some(end_pos) = new_content[start_pos..].find(WATERMARK_SUFFIX) {
-            let actual_end = start_pos + end_pos + WATERMARK_SUFFIX.len();
-            new_content = format!("{}{}", &new_content[..start_pos], &new_content[actual_end..]);
-            modified = true;
-        } else {
-            break; // Malformed watermark, stop trying
+fn remove_watermark_from_file(path: &str, keep_backup: bool) -> anyhow::Result<bool> {
+    let p = PathBuf::from(path);
+    let mut removed = false;
+    if let Some(c) = container_for(&p) {
+        // Files that don't parse as their container can still have a tail marker
+        if matches!(container::find(&p, c), Ok(Some(_))) {
+            removed = container::remove(&p, c, keep_backup)?;
         }
     }
+
+    let mut file = fs::File::open(path)?;
     
-    // Remove old format watermarks: */...
-    if let Some(start_pos) = new_content.rfind(OLD_WATERMARK_PREFIX) {
-        // Remove everything from the old prefix to end of file
-        new_content = new_content[..start_pos].to_string();
-        modified = true;
+    // Markers only ever sit at the end, so removal is a truncation: only the
+    // trailer region is read and every byte before the marker stays untouched
+    let file_len = file.metadata()?.len();
+    let mut end = file_len;
+    loop {
+        match marker::read_before(&mut file, end)? {
+            marker::Detected::Found(t) => { end -= t.total_len as u64; continue; }
+            marker::Detected::Corrupt(msg) => return Err(anyhow!("Corrupted watermark in {}: {}", path, msg)),
+            marker::Detected::None => {}
+        }
+        let window = read_window_before(&mut file, end, TAIL_SCAN_SIZE)?;
+        match find_legacy_marker(&window) {
+            Some(l) => end -= (window.len() - l.start) as u64,
+            None => break,
+        }
     }
-    
-    if modified {
-        // Write back the cleaned content
-        file.seek(SeekFrom::Start(0))?;
-        file.set_len(0)?; // Truncate file
-        file.write_all(new_content.as_bytes())?;
-        file.flush()?;
+
+    if end == file_len {
+        return Ok(removed);
     }
-    
-    Ok(modified)
+    drop(file);
+    atomic::truncate(std::path::Path::new(path), keep_backup, end, None)?;
+    Ok(true)
 }
 
-/// Helper function to check if file is supported
+/// Helper function to check if file is supported; files without an extension
+/// count when their content is an image.
 fn is_supported_file(path: &std::path::Path) -> bool {
     if let Some(extension) = path.extension() {
         if let Some(ext_str) = extension.to_str() {
             let ext_lower = ext_str.to_lowercase();
-            return matches!(ext_lower.as_str(), "txt" | "jpg" | "jpeg" | "png" | "mp4" | "avi" | "mov" | "mkv");
+            return matches!(ext_lower.as_str(), "txt" | "jpg" | "jpeg" | "png" | "webp" | "tif" | "tiff" | "gif" | "bmp" | "mp4" | "avi" | "mov" | "mkv");
         }
+        return false;
     }
-    false
+    is_image_file(path)
 }
 
 /// Get list of all supported files in directory
@@ -620,20 +2052,1574 @@ fn get_supported_files(dir: String) -> tauri::Result<Vec<String>> {
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .manage(jobs::Jobs::default())
         .invoke_handler(tauri::generate_handler![
             encode_text,
             decode_text,
             add_watermark_marker,
+            create_watermark_key,
             has_tail_watermark,
             extract_tail_watermark,
+            inspect_tail_watermark,
             add_tail_watermark,
+            verify_tail_watermark,
+            export_signing_public_key,
             remove_tail_watermarks,
             get_supported_files,
             add_text_to_image,
+            add_image_overlay,
+            add_invisible_watermark,
+            detect_invisible_watermark,
             load_preferences,
             save_preferences,
-            batch_copy_and_encode
+            batch_copy_and_encode,
+            plan_batch,
+            resume_batch,
+            cancel_job,
+            list_jobs,
+            job_status
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sha256_of(path: &PathBuf) -> Vec<u8> {
+        Sha256::digest(fs::read(path).unwrap()).to_vec()
+    }
+
+    /// JPEG-looking bytes that are not valid UTF-8 and contain marker-like
+    /// sequences in the middle of the payload.
+    fn jpeg_fixture() -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0];
+        data.extend((0..=255u8).cycle().take(4096));
+        data.extend_from_slice(b"<<==not a marker*/");
+        data.extend((0..=255u8).rev().cycle().take(4096));
+        data.extend_from_slice(&[0xFF, 0xD9]);
+        data
+    }
+
+    fn png_fixture(path: &PathBuf) {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, (x ^ y) as u8]));
+        img.save(path).unwrap();
+    }
+
+    fn fixture_dir(name: &str) -> tempfile::TempDir {
+        tempfile::Builder::new().prefix(name).tempdir().unwrap()
+    }
+
+    fn round_trip(path: &PathBuf) {
+        let original = sha256_of(path);
+        let path_str = path.to_string_lossy().to_string();
+
+        assert!(add_tail_marker(path, "Order 042", &MarkOptions::default()).unwrap());
+        assert_ne!(sha256_of(path), original);
+        assert_eq!(extract_marker(path, false, None).unwrap().as_deref(), Some("Order 042"));
+
+        assert!(remove_watermark_from_file(&path_str, false).unwrap());
+        assert_eq!(sha256_of(path), original);
+        assert!(!remove_watermark_from_file(&path_str, false).unwrap());
+    }
+
+    #[test]
+    fn round_trip_restores_binary_jpeg() {
+        let dir = fixture_dir("jpeg");
+        let path = dir.path().join("photo.jpg");
+        fs::write(&path, jpeg_fixture()).unwrap();
+        round_trip(&path);
+    }
+
+    #[test]
+    fn round_trip_restores_png() {
+        let dir = fixture_dir("png");
+        let path = dir.path().join("photo.png");
+        png_fixture(&path);
+        round_trip(&path);
+        image::open(&path).unwrap();
+    }
+
+    #[test]
+    fn removes_legacy_text_marker_from_binary_file() {
+        let dir = fixture_dir("legacy");
+        let path = dir.path().join("clip.mp4");
+        let original = jpeg_fixture();
+        let mut marked = original.clone();
+        marked.extend_from_slice(format!("{}{}{}", WATERMARK_PREFIX, caesar_encode("Order 7"), WATERMARK_SUFFIX).as_bytes());
+        fs::write(&path, &marked).unwrap();
+
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn stacked_markers_deep_in_a_large_file_are_removed_window_by_window() {
+        use std::io::Write;
+        let dir = fixture_dir("stacked");
+        let path = dir.path().join("archive.bin");
+        // Sparse and larger than a test could read into memory, so only reading
+        // the windows around the markers gets through
+        let content_len: u64 = 5 << 30;
+        let mut f = fs::File::create(&path).unwrap();
+        f.write_all(b"archive start").unwrap();
+        f.set_len(content_len).unwrap();
+        let trailer = |text: &str| marker::encode(CipherScheme::Caesar.id(), &[(marker::FIELD_TEXT, caesar_encode(text).as_bytes())]).unwrap();
+        // The legacy marker almost fills the scan window, so the window that finds
+        // it also holds the end of the trailer before it
+        let legacy = format!("{}{}{}", WATERMARK_PREFIX, caesar_encode(&"Order 7 ".repeat(30)), WATERMARK_SUFFIX);
+        assert!((TAIL_SCAN_SIZE - 16..TAIL_SCAN_SIZE).contains(&legacy.len()));
+        let tail = [trailer("Order 1"), legacy.into_bytes(), trailer("Order 2")].concat();
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&tail).unwrap();
+
+        assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 2"));
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().len(), content_len);
+        let mut start = [0; 13];
+        fs::File::open(&path).unwrap().read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"archive start");
+        assert!(!remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+    }
+
+    #[test]
+    fn version_1_trailers_without_header_or_parity_still_read() {
+        let dir = fixture_dir("trailer-v1");
+        let path = dir.path().join("photo.jpg");
+        let original = jpeg_fixture();
+        let text = caesar_encode("Order 042");
+        let payload = [&[marker::FIELD_TEXT][..], &(text.len() as u16).to_le_bytes(), text.as_bytes()].concat();
+        let meta = [1, CipherScheme::Caesar.id(), 0, 0];
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&payload);
+        crc.update(&meta);
+        let footer = [&(payload.len() as u32).to_le_bytes()[..], &crc.finalize().to_le_bytes(), &meta, marker::MAGIC].concat();
+        fs::write(&path, [&original[..], &payload, &footer].concat()).unwrap();
+
+        let Ok(FoundMarker::Trailer(t)) = read_marker(&path, false, None) else { panic!("v1 trailer not found") };
+        assert_eq!((t.total_len, t.corrected, t.correctable), (payload.len() + footer.len(), 0, 0));
+        assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 042"));
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn legacy_text_markers_of_both_formats_still_read() {
+        let dir = fixture_dir("legacy-read");
+        let encoded = caesar_encode("Order 042");
+        for (name, marker) in [
+            ("new.txt", format!("{}{}{}\n", WATERMARK_PREFIX, encoded, WATERMARK_SUFFIX)),
+            ("old.txt", format!("{}{}", OLD_WATERMARK_PREFIX, encoded)),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, format!("shot list\n{}", marker)).unwrap();
+            let Ok(FoundMarker::Legacy(l)) = read_marker(&path, false, None) else { panic!("{}: no legacy marker", name) };
+            assert_eq!((l.start, l.old_format), ("shot list\n".len(), name == "old.txt"));
+            assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 042"), "{}", name);
+            assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "shot list\n", "{}", name);
+        }
+    }
+
+    fn structured_round_trip(path: &PathBuf) {
+        let original = sha256_of(path);
+        let opts = MarkOptions { mode: EmbedMode::Structured, ..Default::default() };
+        assert!(add_tail_marker(path, "Order 5", &opts).unwrap());
+        assert!(matches!(read_marker(path, true, None).unwrap(), FoundMarker::Structured(..)));
+        assert_eq!(extract_marker(path, false, None).unwrap().as_deref(), Some("Order 5"));
+        assert!(!add_tail_marker(path, "Order 6", &opts).unwrap());
+
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(sha256_of(path), original);
+    }
+
+    #[test]
+    fn structured_png_stays_decodable() {
+        let dir = fixture_dir("spng");
+        let path = dir.path().join("photo.png");
+        png_fixture(&path);
+        let opts = MarkOptions { mode: EmbedMode::Structured, ..Default::default() };
+        assert!(add_tail_marker(&path, "Order 5", &opts).unwrap());
+        image::open(&path).unwrap();
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        structured_round_trip(&path);
+    }
+
+    #[test]
+    fn structured_jpeg_stays_decodable() {
+        let dir = fixture_dir("sjpg");
+        let path = dir.path().join("photo.jpg");
+        image::RgbImage::from_pixel(32, 32, image::Rgb([200, 100, 50])).save(&path).unwrap();
+        let opts = MarkOptions { mode: EmbedMode::Structured, ..Default::default() };
+        assert!(add_tail_marker(&path, "Order 5", &opts).unwrap());
+        image::open(&path).unwrap();
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        structured_round_trip(&path);
+    }
+
+    #[test]
+    fn structured_mp4_round_trip() {
+        let dir = fixture_dir("smp4");
+        let path = dir.path().join("clip.mp4");
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0, 0, 0, 16]);
+        data.extend_from_slice(b"ftypisom\0\0\0\x01");
+        data.extend_from_slice(&[0, 0, 0, 12]);
+        data.extend_from_slice(b"mdat\xde\xad\xbe\xef");
+        fs::write(&path, data).unwrap();
+        structured_round_trip(&path);
+    }
+
+    #[test]
+    fn structured_mkv_round_trip() {
+        let dir = fixture_dir("smkv");
+        let path = dir.path().join("clip.mkv");
+        let mut data = vec![0x1A, 0x45, 0xDF, 0xA3, 0x84, 0x42, 0x86, 0x81, 0x01];
+        data.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x01, 0, 0, 0, 0, 0, 0, 0x06]);
+        data.extend_from_slice(&[0xEC, 0x84, 1, 2, 3, 4]);
+        fs::write(&path, data).unwrap();
+        structured_round_trip(&path);
+    }
+
+    #[test]
+    fn structured_mkv_with_a_live_cluster_gets_a_tail_marker() {
+        let dir = fixture_dir("smkv-live");
+        let path = dir.path().join("live.mkv");
+        // Segment and cluster of unknown size, as written while streaming
+        let mut data = vec![0x1A, 0x45, 0xDF, 0xA3, 0x84, 0x42, 0x86, 0x81, 0x01];
+        data.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        data.extend_from_slice(&[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x81, 0x00, 0xA3, 0x84, 1, 2, 3, 4]);
+        fs::write(&path, &data).unwrap();
+        let opts = MarkOptions { mode: EmbedMode::Structured, ..Default::default() };
+        let reason = tail_fallback(&path, "Order 5", &opts).unwrap();
+        assert!(reason.contains("unknown size"), "{}", reason);
+
+        assert!(add_tail_marker(&path, "Order 5", &opts).unwrap());
+        assert!(matches!(read_marker(&path, false, None).unwrap(), FoundMarker::Trailer(_)));
+        assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 5"));
+        assert!(!add_tail_marker(&path, "Order 6", &opts).unwrap(), "found again, not added twice");
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn keeps_backup_and_leaves_no_temp_files() {
+        let dir = fixture_dir("backup");
+        let path = dir.path().join("photo.jpg");
+        let original = jpeg_fixture();
+        fs::write(&path, &original).unwrap();
+
+        let opts = MarkOptions { keep_backup: true, ..Default::default() };
+        assert!(add_tail_marker(&path, "Order 9", &opts).unwrap());
+        assert_eq!(fs::read(atomic::backup_path(&path).unwrap()).unwrap(), original);
+
+        let names: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names.len(), 2, "unexpected files: {:?}", names);
+    }
+
+    #[test]
+    fn failed_trailer_writes_leave_the_original_untouched() {
+        let dir = fixture_dir("failed-write");
+        let path = dir.path().join("clip.mkv");
+        let original = jpeg_fixture();
+        fs::write(&path, &original).unwrap();
+        let trailer = marker::encode(CipherScheme::Caesar.id(), &[(marker::FIELD_TEXT, b"Vykly 9")]).unwrap();
+
+        // The disk fills up halfway through the trailer
+        for keep_backup in [false, true] {
+            let err = atomic::modify(&path, keep_backup, |f| {
+                f.seek(SeekFrom::End(0))?;
+                f.write_all(&trailer[..trailer.len() / 2])?;
+                Err::<(), _>(std::io::Error::other("No space left on device").into())
+            }).unwrap_err();
+            assert!(err.to_string().contains("No space left on device"), "{}", err);
+            assert_eq!(fs::read(&path).unwrap(), original);
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "no temp files or backup");
+        }
+
+        assert!(add_tail_marker(&path, "Order 9", &MarkOptions::default()).unwrap());
+        assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 9"));
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "no temp files");
+    }
+
+    #[test]
+    fn leaves_unmarked_file_untouched() {
+        let dir = fixture_dir("unmarked");
+        let path = dir.path().join("photo.jpg");
+        fs::write(&path, jpeg_fixture()).unwrap();
+        let original = sha256_of(&path);
+
+        assert!(!remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(sha256_of(&path), original);
+    }
+
+    #[test]
+    fn repairs_damaged_trailer() {
+        let dir = fixture_dir("damaged");
+        let path = dir.path().join("photo.jpg");
+        let original = jpeg_fixture();
+        fs::write(&path, &original).unwrap();
+        let opts = MarkOptions { scheme: CipherScheme::Caesar, ..Default::default() };
+        assert!(add_tail_marker(&path, "Order 042 for client", &opts).unwrap());
+        let marked = fs::read(&path).unwrap();
+        let damage = |count: usize| {
+            let mut data = marked.clone();
+            for i in 0..count { data[original.len() + marker::HEADER_LEN + 3 + i * 2] ^= 0x5A; }
+            fs::write(&path, &data).unwrap();
+        };
+
+        damage(5);
+        let report = inspect_marker(&path, false, None).unwrap();
+        assert_eq!(report.text.as_deref(), Some("Order 042 for client"));
+        assert_eq!(report.corrected_symbols, 5);
+        assert!(report.confidence.unwrap() < 1.0);
+        assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        damage(12);
+        assert!(matches!(read_marker(&path, true, None).unwrap(), FoundMarker::Corrupt(_)));
+    }
+
+    /// A JPEG fixture with a tail marker, the original bytes and the marked ones.
+    fn marked_fixture(name: &str) -> (tempfile::TempDir, PathBuf, Vec<u8>, Vec<u8>) {
+        let dir = fixture_dir(name);
+        let path = dir.path().join("photo.jpg");
+        let original = jpeg_fixture();
+        fs::write(&path, &original).unwrap();
+        assert!(add_tail_marker(&path, "Order 042 for client", &MarkOptions::default()).unwrap());
+        let marked = fs::read(&path).unwrap();
+        (dir, path, original, marked)
+    }
+
+    #[test]
+    fn any_damaged_footer_byte_is_read_through_the_header() {
+        let (_dir, path, original, marked) = marked_fixture("footer");
+        for i in marked.len() - marker::FOOTER_LEN..marked.len() {
+            let mut data = marked.clone();
+            data[i] ^= 0xA5;
+            fs::write(&path, &data).unwrap();
+            let text = extract_marker(&path, false, None);
+            assert_eq!(text.ok().flatten().as_deref(), Some("Order 042 for client"), "footer byte {}", i - original.len());
+            assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+            assert_eq!(fs::read(&path).unwrap(), original, "footer byte {}", i - original.len());
+        }
+    }
+
+    #[test]
+    fn checksum_damage_alone_is_vouched_for_by_the_parity() {
+        let (_dir, path, _, marked) = marked_fixture("crc");
+        let mut data = marked.clone();
+        let crc_at = data.len() - 16;
+        for b in &mut data[crc_at..crc_at + 4] { *b = !*b; }
+        fs::write(&path, &data).unwrap();
+        let report = inspect_marker(&path, false, None).unwrap();
+        assert_eq!(report.text.as_deref(), Some("Order 042 for client"));
+        assert_eq!(report.corrected_symbols, 0);
+
+        // Damaged header as well: nothing is left to vouch for the scheme byte
+        let Ok(FoundMarker::Trailer(t)) = read_marker(&path, true, None) else { panic!("no trailer") };
+        data[marked.len() - t.total_len + 10] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(read_marker(&path, true, None).unwrap(), FoundMarker::Corrupt(_)));
+    }
+
+    #[test]
+    fn truncated_download_keeps_its_marker() {
+        let (_dir, path, original, marked) = marked_fixture("truncated");
+        for cut in [1, 7, marker::FOOTER_LEN] {
+            fs::write(&path, &marked[..marked.len() - cut]).unwrap();
+            assert_eq!(extract_marker(&path, false, None).unwrap().as_deref(), Some("Order 042 for client"), "cut {}", cut);
+            assert!(remove_watermark_from_file(&path.to_string_lossy(), false).unwrap());
+            assert_eq!(fs::read(&path).unwrap(), original, "cut {}", cut);
+        }
+
+        // Cut into the payload: found, but can't be trusted
+        fs::write(&path, &marked[..marked.len() - marker::FOOTER_LEN - 5]).unwrap();
+        match read_marker(&path, true, None).unwrap() {
+            FoundMarker::Corrupt(msg) => assert!(msg.starts_with("trailer truncated"), "{}", msg),
+            _ => panic!("truncated trailer not reported"),
+        }
+    }
+
+    fn lsb_trailer(text: &str) -> Vec<u8> {
+        marker::encode(CipherScheme::Caesar.id(), &[(marker::FIELD_TEXT, caesar_encode(text).as_bytes())]).unwrap()
+    }
+
+    #[test]
+    fn lsb_marker_round_trip() {
+        let dir = fixture_dir("lsb");
+        let path = dir.path().join("photo.png");
+        png_fixture(&path);
+        let original = image::open(&path).unwrap().to_rgb8();
+
+        embed_lsb_marker(&path, &lsb_trailer("Order 12"), 11, SaveOptions::default()).unwrap();
+        let found = find_lsb_marker(&path, 11);
+        assert!(matches!(found, FoundMarker::Lsb(_)));
+        assert_eq!(decode_marker(found, &path, None).unwrap().as_deref(), Some("Order 12"));
+        assert!(matches!(find_lsb_marker(&path, 12), FoundMarker::None), "read with the wrong key");
+
+        let marked = image::open(&path).unwrap().to_rgb8();
+        assert!(original.as_raw().iter().zip(marked.as_raw()).all(|(a, b)| a.abs_diff(*b) <= 1));
+    }
+
+    #[test]
+    fn lsb_rejects_too_small_image() {
+        let dir = fixture_dir("lsb-small");
+        let path = dir.path().join("icon.png");
+        image::RgbImage::from_pixel(4, 4, image::Rgb([9, 9, 9])).save(&path).unwrap();
+        let original = sha256_of(&path);
+
+        let err = embed_lsb_marker(&path, &lsb_trailer("Order 12"), 11, SaveOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("too small"), "{}", err);
+        assert_eq!(sha256_of(&path), original);
+    }
+
+    /// Photo-like content: smooth shading, hard-edged shapes and sensor noise.
+    fn photo_fixture(path: &PathBuf, w: u32, h: u32) {
+        let mut noise = 0x2545F4914F6CDD1Du64;
+        let img = image::RgbImage::from_fn(w, h, |x, y| {
+            noise ^= noise << 13;
+            noise ^= noise >> 7;
+            noise ^= noise << 17;
+            let (fx, fy) = (x as f32 / w as f32, y as f32 / h as f32);
+            let mut v = 120.0 + 60.0 * (fx * 5.0).sin() * (fy * 3.0).cos() + (noise % 17) as f32 - 8.0;
+            if ((x / 50) + (y / 70)) % 3 == 0 { v += 40.0; }
+            let v = v.clamp(0.0, 255.0);
+            image::Rgb([v as u8, (v * 0.8) as u8, (255.0 - v) as u8])
+        });
+        img.save(path).unwrap();
+    }
+
+    fn resave(from: &PathBuf, to: &PathBuf, edit: impl FnOnce(DynamicImage) -> DynamicImage, quality: u8) {
+        let img = edit(image::open(from).unwrap()).to_rgb8();
+        let mut out = fs::File::create(to).unwrap();
+        DynamicImage::ImageRgb8(img).write_to(&mut out, ImageOutputFormat::Jpeg(quality)).unwrap();
+    }
+
+    #[test]
+    fn invisible_mark_survives_recompression_and_downscale() {
+        let dir = fixture_dir("invisible");
+        let path = dir.path().join("photo.jpg");
+        photo_fixture(&path, 640, 480);
+        embed_invisible(&path, 42, 7, 1.0, SaveOptions::default()).unwrap();
+
+        let report = detect_invisible(&path, 7).unwrap();
+        assert_eq!(report.order, Some(42));
+        assert!(!detect_invisible(&path, 8).unwrap().found, "read with the wrong key");
+
+        let q50 = dir.path().join("q50.jpg");
+        resave(&path, &q50, |img| img, 50);
+        assert_eq!(detect_invisible(&q50, 7).unwrap().order, Some(42));
+
+        let half = dir.path().join("half.jpg");
+        resave(&path, &half, |img| img.resize(320, 240, image::imageops::FilterType::Triangle), 50);
+        assert_eq!(detect_invisible(&half, 7).unwrap().order, Some(42));
+    }
+
+    #[test]
+    fn invisible_mark_survives_mild_crop() {
+        let dir = fixture_dir("invisible-crop");
+        let path = dir.path().join("photo.png");
+        photo_fixture(&path, 640, 480);
+        assert!(!detect_invisible(&path, 7).unwrap().found);
+        embed_invisible(&path, 1234, 7, 1.0, SaveOptions::default()).unwrap();
+
+        let cropped = dir.path().join("cropped.jpg");
+        resave(&path, &cropped, |mut img| img.crop(26, 14, 595, 456), 70);
+        assert_eq!(detect_invisible(&cropped, 7).unwrap().order, Some(1234));
+    }
+
+    #[test]
+    fn invisible_mark_keeps_the_depth_and_channels_of_the_image() {
+        let dir = fixture_dir("invisible-16");
+        let path = dir.path().join("scan.png");
+        photo_fixture(&path, 320, 240);
+        let before = image::open(&path).unwrap().to_rgb16();
+        before.save(&path).unwrap();
+        embed_invisible(&path, 42, 7, 1.0, SaveOptions::default()).unwrap();
+
+        let img = image::open(&path).unwrap();
+        assert_eq!(img.color(), image::ColorType::Rgb16);
+        assert_eq!(detect_invisible(&path, 7).unwrap().order, Some(42));
+        let off_grid = img.to_rgb16().pixels().zip(before.pixels())
+            .any(|(after, before)| after != before && after.0.iter().any(|v| v % 257 != 0));
+        assert!(off_grid, "marked at 8 bits");
+    }
+
+    #[test]
+    fn visible_text_is_drawn_in_the_anchor_corner() {
+        let dir = fixture_dir("visible");
+        let path = dir.path().join("photo.png");
+        photo_fixture(&path, 400, 300);
+        let before = image::open(&path).unwrap().to_rgba8();
+
+        let font = load_font(None).unwrap();
+        draw_text_on_image(&path, "Order 042", TextPosition::BottomRight, &WatermarkStyle::default(), &font, SaveOptions::default()).unwrap();
+        let after = image::open(&path).unwrap().to_rgba8();
+        let changed: Vec<(u32, u32)> = before.enumerate_pixels()
+            .filter(|(x, y, px)| after.get_pixel(*x, *y) != *px)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert!(changed.len() > 50, "only {} pixels changed", changed.len());
+        assert!(changed.iter().all(|&(x, y)| x >= 200 && y >= 270 && x < 395 && y < 295));
+    }
+
+    #[test]
+    fn visible_text_reports_why_it_cannot_draw() {
+        let dir = fixture_dir("visible-errors");
+        let path = dir.path().join("tiny.png");
+        png_fixture(&path);
+        let original = sha256_of(&path);
+        let font = load_font(None).unwrap();
+
+        let err = draw_text_on_image(&path, "A label much wider than the picture", TextPosition::default(), &WatermarkStyle::default(), &font, SaveOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("doesn't fit"), "{}", err);
+        assert!(draw_text_on_image(&path, "  ", TextPosition::default(), &WatermarkStyle::default(), &font, SaveOptions::default()).is_err());
+        assert_eq!(sha256_of(&path), original);
+
+        let not_a_font = dir.path().join("font.ttf");
+        fs::write(&not_a_font, b"definitely not a font").unwrap();
+        let err = load_font(Some(&not_a_font.to_string_lossy())).unwrap_err();
+        assert!(err.to_string().contains("Not a usable"), "{}", err);
+        assert!(load_font(Some(&dir.path().join("missing.ttf").to_string_lossy())).is_err());
+    }
+
+    #[test]
+    fn visible_style_sets_color_outline_and_rotation() {
+        let dir = fixture_dir("visible-style");
+        let path = dir.path().join("white.png");
+        image::RgbImage::from_pixel(400, 300, image::Rgb([255, 255, 255])).save(&path).unwrap();
+        let font = load_font(None).unwrap();
+        let style = WatermarkStyle {
+            color: [255, 0, 0, 255],
+            opacity: 1.0,
+            size: visible::FontSize::Px(40.0),
+            rotation: 90.0,
+            outline: Some(visible::Outline { color: [0, 0, 0, 255], width: 2 }),
+            offset_x: -20,
+            ..WatermarkStyle::default()
+        };
+        draw_text_on_image(&path, "PROOF", TextPosition::TopRight, &style, &font, SaveOptions::default()).unwrap();
+
+        let img = image::open(&path).unwrap().to_rgb8();
+        let (mut red, mut black) = (Vec::new(), 0);
+        for (x, y, px) in img.enumerate_pixels() {
+            match px.0 {
+                [255, 0, 0] => red.push((x, y)),
+                [0, 0, 0] => black += 1,
+                _ => {}
+            }
+        }
+        assert!(red.len() > 100 && black > 100, "{} red, {} black", red.len(), black);
+        // Turned on its side: the label is taller than wide, right edge 25 px in
+        let (min_x, max_x) = (red.iter().map(|p| p.0).min().unwrap(), red.iter().map(|p| p.0).max().unwrap());
+        let (min_y, max_y) = (red.iter().map(|p| p.1).min().unwrap(), red.iter().map(|p| p.1).max().unwrap());
+        assert!(max_y - min_y > 2 * (max_x - min_x));
+        assert!(min_x > 300 && max_x < 375 && min_y < 30);
+
+        let bad = WatermarkStyle { opacity: 1.5, ..WatermarkStyle::default() };
+        let err = draw_text_on_image(&path, "PROOF", TextPosition::Center, &bad, &font, SaveOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("opacity"), "{}", err);
+    }
+
+    #[test]
+    fn tiled_label_covers_the_whole_image() {
+        let dir = fixture_dir("visible-tiled");
+        let path = dir.path().join("grey.png");
+        image::RgbImage::from_pixel(400, 300, image::Rgb([90, 90, 90])).save(&path).unwrap();
+        let font = load_font(None).unwrap();
+        let style = WatermarkStyle { tile: Some(visible::Tile::default()), ..WatermarkStyle::default() };
+        draw_text_on_image(&path, "PROOF 042", TextPosition::default(), &style, &font, SaveOptions::default()).unwrap();
+
+        // No 80x80 crop anywhere escapes the pattern
+        let img = image::open(&path).unwrap().to_rgb8();
+        for top in (0..=220).step_by(20) {
+            for left in (0..=320).step_by(20) {
+                let marked = (0..80).flat_map(|y| (0..80).map(move |x| (left + x, top + y)))
+                    .filter(|&(x, y)| img.get_pixel(x, y).0 != [90, 90, 90])
+                    .count();
+                assert!(marked > 20, "crop at {},{} has {} marked pixels", left, top, marked);
+            }
+        }
+    }
+
+    fn logo_fixture(path: &PathBuf) {
+        // Opaque red square inside a transparent border
+        let logo = image::RgbaImage::from_fn(40, 20, |x, y| {
+            if (5..35).contains(&x) && (5..15).contains(&y) { image::Rgba([255, 0, 0, 255]) } else { image::Rgba([0, 0, 0, 0]) }
+        });
+        logo.save(path).unwrap();
+    }
+
+    /// Source folder "Shoot" of a batch, with the logo next to it.
+    struct Shoot {
+        /// Removed with the fixture.
+        _dir: tempfile::TempDir,
+        src: PathBuf,
+        logo: PathBuf,
+        copies: PathBuf,
+    }
+
+    impl Shoot {
+        /// A photo `IMG_<n>.png` made by `photo` for each of `numbers`.
+        fn new(name: &str, numbers: impl IntoIterator<Item = u32>, photo: impl Fn(&PathBuf)) -> Shoot {
+            let dir = fixture_dir(name);
+            let (src, logo, copies) = (dir.path().join("Shoot"), dir.path().join("logo.png"), dir.path().join("Shoot-Copies"));
+            fs::create_dir(&src).unwrap();
+            for n in numbers {
+                photo(&src.join(format!("IMG_{}.png", n)));
+            }
+            logo_fixture(&logo);
+            Shoot { _dir: dir, src, logo, copies }
+        }
+
+        /// White 200×150 photos.
+        fn white(name: &str, numbers: impl IntoIterator<Item = u32>) -> Shoot {
+            Shoot::new(name, numbers, |path| image::RgbImage::from_pixel(200, 150, image::Rgb([255, 255, 255])).save(path).unwrap())
+        }
+
+        /// `num_copies` copies from "Order 1" with the logo as overlay.
+        fn args(&self, num_copies: i32) -> BatchArgs {
+            BatchArgs {
+                source_folder: self.src.to_string_lossy().to_string(),
+                num_copies,
+                base_text: "Order 1".into(),
+                overlay_path: Some(self.logo.to_string_lossy().to_string()),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[test]
+    fn logo_overlay_is_scaled_and_blended() {
+        let dir = fixture_dir("overlay");
+        let (path, logo_path) = (dir.path().join("white.png"), dir.path().join("logo.png"));
+        image::RgbImage::from_pixel(400, 300, image::Rgb([255, 255, 255])).save(&path).unwrap();
+        logo_fixture(&logo_path);
+        let logo = load_logo(&logo_path).unwrap();
+
+        // Twice its size (80 px wide) in the top-left corner, at full strength
+        let style = visible::OverlayStyle { scale: Some(0.2), opacity: 1.0, ..Default::default() };
+        edit_image(&path, SaveOptions::default(), |img| visible::overlay(img, &logo, TextPosition::TopLeft, &style)).unwrap();
+        let img = image::open(&path).unwrap().to_rgb8();
+        assert_eq!(img.get_pixel(45, 20).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(6, 6).0, [255, 255, 255], "transparent border must stay clear");
+        assert_eq!(img.get_pixel(95, 20).0, [255, 255, 255]);
+
+        let half = visible::OverlayStyle { opacity: 0.5, ..Default::default() };
+        edit_image(&path, SaveOptions::default(), |img| visible::overlay(img, &logo, TextPosition::BottomRight, &half)).unwrap();
+        let px = image::open(&path).unwrap().to_rgb8().get_pixel(400 - 5 - 20, 300 - 5 - 10).0;
+        assert!(px[0] == 255 && (120..=135).contains(&px[1]), "{:?}", px);
+
+        let huge = visible::OverlayStyle { scale: Some(1.5), ..Default::default() };
+        assert!(edit_image(&path, SaveOptions::default(), |img| visible::overlay(img, &logo, TextPosition::Center, &huge)).is_err());
+    }
+
+    #[test]
+    fn sixteen_bit_images_are_edited_at_sixteen_bits() {
+        let dir = fixture_dir("overlay-16");
+        let (path, logo_path) = (dir.path().join("deep.png"), dir.path().join("logo.png"));
+        image::ImageBuffer::from_pixel(200, 100, image::Rgb([0x1234u16, 0x5678, 0x9ABC])).save(&path).unwrap();
+        logo_fixture(&logo_path);
+        let logo = load_logo(&logo_path).unwrap();
+
+        let half = visible::OverlayStyle { opacity: 0.5, ..Default::default() };
+        edit_image(&path, SaveOptions::default(), |img| visible::overlay(img, &logo, TextPosition::TopLeft, &half)).unwrap();
+        let img = image::open(&path).unwrap();
+        assert_eq!(img.color(), image::ColorType::Rgb16);
+        let img = img.to_rgb16();
+        assert_eq!(img.get_pixel(150, 80).0, [0x1234, 0x5678, 0x9ABC], "untouched pixels must keep every bit");
+        // Half red over the background, off the 8-bit grid
+        let px = img.get_pixel(5 + 20, 5 + 10).0;
+        let expected = [(0xFFFF + 0x1234) / 2, 0x5678 / 2, 0x9ABC / 2];
+        assert!(px.iter().zip(expected).all(|(&v, e): (&u16, u32)| (v as u32).abs_diff(e) <= 1), "{:?}", px);
+        assert_ne!(px[1] % 257, 0, "blended at 8 bits");
+    }
+
+    #[test]
+    fn batch_applies_logo_without_text() {
+        let shoot = Shoot::white("overlay-batch", 1..=3);
+        let style = visible::OverlayStyle { opacity: 1.0, ..Default::default() };
+
+        Batch::prepare(BatchArgs {
+            base_text: "Order 2".into(),
+            overlay_position: Some(TextPosition::TopLeft),
+            overlay_style: Some(style),
+            ..shoot.args(2)
+        }, &Preferences::default()).unwrap().run(&AtomicBool::new(false), &|_| {}).unwrap();
+
+        for (order, marked) in [("002", 2), ("003", 3)] {
+            for n in 1..=3 {
+                let img = image::open(shoot.copies.join(order).join("Shoot").join(format!("IMG_{}.png", n))).unwrap().to_rgb8();
+                let red = img.get_pixel(15, 10).0 == [255, 0, 0];
+                assert_eq!(red, n == marked, "copy {} photo {}", order, n);
+            }
+        }
+    }
+
+    /// JPEG with an EXIF block (orientation 6, "rotate 90° clockwise to view")
+    /// and an ICC profile segment.
+    fn jpeg_with_metadata(path: &PathBuf, img: image::RgbImage, quality: u8) -> (Vec<u8>, Vec<u8>) {
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut std::io::Cursor::new(&mut encoded), ImageOutputFormat::Jpeg(quality)).unwrap();
+        let segment = |marker: u8, body: &[u8]| {
+            [&[0xFF, marker][..], &((body.len() + 2) as u16).to_be_bytes(), body].concat()
+        };
+        // Big-endian TIFF header with one IFD entry: orientation = 6
+        let exif = segment(0xE1, b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0");
+        let icc = segment(0xE2, &[&b"ICC_PROFILE\0\x01\x01"[..], &[0x5A; 300]].concat());
+        let data = [&encoded[..2], &exif, &icc, &encoded[2..]].concat();
+        fs::write(path, data).unwrap();
+        (exif, icc)
+    }
+
+    #[test]
+    fn reencoding_keeps_jpeg_metadata_and_quality() {
+        let dir = fixture_dir("metadata-jpeg");
+        let path = dir.path().join("photo.jpg");
+        let img = image::RgbImage::from_fn(320, 240, |x, y| image::Rgb([x as u8, y as u8, 128]));
+        let (exif, icc) = jpeg_with_metadata(&path, img, 93);
+        assert_eq!(metadata::read(&fs::read(&path).unwrap()).jpeg_quality, Some(93));
+
+        let font = load_font(None).unwrap();
+        draw_text_on_image(&path, "Proof", TextPosition::default(), &WatermarkStyle::default(), &font, SaveOptions::default()).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Jpeg);
+        assert!(data.windows(exif.len()).any(|w| w == exif), "EXIF lost");
+        assert!(data.windows(icc.len()).any(|w| w == icc), "ICC profile lost");
+        assert_eq!(metadata::read(&data).jpeg_quality, Some(93));
+        image::open(&path).unwrap();
+
+        // A quality that is set wins over the estimated one
+        let save = SaveOptions { jpeg_quality: Some(60), ..SaveOptions::default() };
+        edit_image(&path, save, |_| Ok(())).unwrap();
+        assert_eq!(metadata::read(&fs::read(&path).unwrap()).jpeg_quality, Some(60));
+    }
+
+    #[test]
+    fn reencoding_keeps_png_format_chunks_and_color_type() {
+        let dir = fixture_dir("metadata-png");
+        // A PNG behind a .jpg name stays a PNG
+        let path = dir.path().join("scan.jpg");
+        let mut encoded = Vec::new();
+        DynamicImage::ImageLuma8(image::GrayImage::from_pixel(200, 100, image::Luma([40])))
+            .write_to(&mut std::io::Cursor::new(&mut encoded), ImageOutputFormat::Png).unwrap();
+        let body = b"tEXtAuthor\0Studio";
+        let crc = crc32fast::hash(body).to_be_bytes();
+        let text = [&((body.len() - 4) as u32).to_be_bytes()[..], body, &crc].concat();
+        let ihdr_end = 8 + 25;
+        fs::write(&path, [&encoded[..ihdr_end], &text, &encoded[ihdr_end..]].concat()).unwrap();
+
+        let font = load_font(None).unwrap();
+        draw_text_on_image(&path, "Proof", TextPosition::default(), &WatermarkStyle::default(), &font, SaveOptions::default()).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Png);
+        assert!(data.windows(text.len()).any(|w| w == text), "tEXt chunk lost");
+        let img = open_image(&path).unwrap();
+        assert_eq!(img.color(), image::ColorType::L8);
+        assert!(img.to_luma8().pixels().any(|p| p.0[0] > 40), "label not drawn");
+    }
+
+    #[test]
+    fn labels_follow_exif_orientation() {
+        let dir = fixture_dir("orientation");
+        let path = dir.path().join("portrait.jpg");
+        jpeg_with_metadata(&path, image::RgbImage::from_pixel(320, 240, image::Rgb([20, 20, 20])), 95);
+        let kept = metadata::read(&fs::read(&path).unwrap());
+        assert_eq!(kept.orientation(), 6);
+
+        let font = load_font(None).unwrap();
+        let style = WatermarkStyle { opacity: 1.0, ..WatermarkStyle::default() };
+        draw_text_on_image(&path, "Proof", TextPosition::BottomRight, &style, &font, SaveOptions::default()).unwrap();
+
+        // Still stored landscape; the viewer's bottom-right is the stored top-right
+        let img = image::open(&path).unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (320, 240));
+        let bright: Vec<(u32, u32)> = img.enumerate_pixels().filter(|p| p.2 .0[0] > 120).map(|p| (p.0, p.1)).collect();
+        assert!(bright.len() > 30);
+        assert!(bright.iter().all(|&(x, y)| x > 280 && y < 120), "{:?}", &bright[..5]);
+        assert_eq!(metadata::read(&fs::read(&path).unwrap()).orientation(), 6);
+    }
+
+    #[test]
+    fn auto_color_contrasts_with_the_background() {
+        let dir = fixture_dir("visible-auto");
+        let font = load_font(None).unwrap();
+        let label_pixels = |shade: u8, opacity: f32| -> Vec<u8> {
+            let path = dir.path().join(format!("{}.png", shade));
+            image::GrayImage::from_pixel(300, 200, image::Luma([shade])).save(&path).unwrap();
+            let style = WatermarkStyle { auto_color: true, opacity, size: visible::FontSize::Px(30.0), ..WatermarkStyle::default() };
+            draw_text_on_image(&path, "Proof", TextPosition::Center, &style, &font, SaveOptions::default()).unwrap();
+            image::open(&path).unwrap().to_luma8().pixels().map(|p| p.0[0]).filter(|&v| v != shade).collect()
+        };
+
+        // Snow gets dark text, night sky light text
+        let on_white = label_pixels(250, 0.8);
+        assert!(!on_white.is_empty() && on_white.iter().all(|&v| v < 250));
+        let on_black = label_pixels(10, 0.8);
+        assert!(!on_black.is_empty() && on_black.iter().all(|&v| v > 10));
+        // Half-strength text can't reach 4.5:1 on mid grey, so it gets an outline
+        let on_grey = label_pixels(128, 0.5);
+        assert!(on_grey.iter().any(|&v| v > 140) && on_grey.iter().any(|&v| v < 116));
+    }
+
+    #[test]
+    fn multi_line_labels_are_aligned() {
+        let dir = fixture_dir("visible-lines");
+        let font = load_font(None).unwrap();
+        // Left and right ink edges of the two text lines
+        let edges = |align: Option<visible::TextAlign>| -> Vec<(u32, u32)> {
+            let path = dir.path().join("black.png");
+            image::GrayImage::from_pixel(400, 200, image::Luma([0])).save(&path).unwrap();
+            let style = WatermarkStyle { opacity: 1.0, align, size: visible::FontSize::Px(24.0), ..WatermarkStyle::default() };
+            draw_text_on_image(&path, "Proof\nOrder 042 / 2026", TextPosition::TopLeft, &style, &font, SaveOptions::default()).unwrap();
+            let img = &image::open(&path).unwrap().to_luma8();
+            let ink_rows: Vec<u32> = (0..200).filter(|&y| (0..400).any(|x| img.get_pixel(x, y).0[0] > 128)).collect();
+            let split = ink_rows.windows(2).find(|w| w[1] > w[0] + 1).map(|w| w[1]).unwrap();
+            [(0, split), (split, 200)].iter().map(|&(from, to)| {
+                let xs: Vec<u32> = (from..to).flat_map(|y| (0..400).filter(move |&x| img.get_pixel(x, y).0[0] > 128)).collect();
+                (*xs.iter().min().unwrap(), *xs.iter().max().unwrap())
+            }).collect()
+        };
+
+        // Top-left anchors left-align by default
+        let left = edges(None);
+        assert!(left[0].0.abs_diff(left[1].0) <= 2 && left[1].1 > left[0].1 + 50, "{:?}", left);
+        let right = edges(Some(visible::TextAlign::Right));
+        assert!(right[0].1.abs_diff(right[1].1) <= 2 && right[0].0 > right[1].0 + 50, "{:?}", right);
+    }
+
+    #[test]
+    fn batch_label_placeholders_expand() {
+        let vars = LabelVars { order: "042", base_text: "Client", index: 3, date: "2026-10-17" };
+        assert_eq!(
+            expand_label("Proof — Order {order} — {date}\n{base_text} #{index} {filename} {other}", &vars, "IMG_7.jpg"),
+            "Proof — Order 042 — 2026-10-17\nClient #3 IMG_7.jpg {other}"
+        );
+        let tricky = LabelVars { base_text: "{date}", ..vars };
+        assert_eq!(expand_label("{base_text}", &tricky, ""), "{date}");
+    }
+
+    #[test]
+    fn photo_selection_picks_numbers_globs_and_random_counts() {
+        assert_eq!(parse_number_ranges("1-5, 12").unwrap(), vec![1..=5, 12..=12]);
+        assert!(parse_number_ranges("5-1").is_err());
+        assert!(parse_number_ranges("a,b").is_err());
+        assert!(parse_number_ranges(" , ").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in ["IMG_1.png", "IMG_2_final.png", "IMG_7.png", "sub/IMG_12_final.png", "notes_3.txt"] {
+            std::fs::write(dir.path().join(name), b"x").unwrap();
+        }
+        let mut rng = fastrand::Rng::with_seed(7);
+        let names = |selection: PhotoSelection, rng: &mut fastrand::Rng| -> Vec<String> {
+            select_photos(dir.path(), &selection.compile().unwrap(), rng).unwrap().iter()
+                .map(|p| p.strip_prefix(dir.path()).unwrap().to_string_lossy().replace('\\', "/"))
+                .collect()
+        };
+        assert_eq!(names(PhotoSelection::All, &mut rng).len(), 4);
+        assert_eq!(names(PhotoSelection::Numbers("1-2,12".into()), &mut rng), ["IMG_1.png", "IMG_2_final.png", "sub/IMG_12_final.png"]);
+        assert_eq!(names(PhotoSelection::Glob("*_FINAL.png".into()), &mut rng), ["IMG_2_final.png"]);
+        assert_eq!(names(PhotoSelection::Glob("**/*_final.png".into()), &mut rng), ["IMG_2_final.png", "sub/IMG_12_final.png"]);
+        assert_eq!(names(PhotoSelection::Random(2), &mut rng).len(), 2);
+        assert_eq!(names(PhotoSelection::Random(10), &mut rng).len(), 4);
+        assert!(PhotoSelection::Glob("[".into()).compile().is_err());
+    }
+
+    #[test]
+    fn batch_records_the_visibly_marked_photos_of_each_copy() {
+        let shoot = Shoot::white("ledger", 1..=4);
+        let style = visible::OverlayStyle { opacity: 1.0, ..Default::default() };
+
+        Batch::prepare(BatchArgs {
+            overlay_position: Some(TextPosition::TopLeft),
+            overlay_style: Some(style),
+            photo_selection: Some(PhotoSelection::Random(2)),
+            ..shoot.args(2)
+        }, &Preferences::default()).unwrap().run(&AtomicBool::new(false), &|_| {}).unwrap();
+
+        let copies = &shoot.copies;
+        let ledger = std::fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap();
+        let records: Vec<serde_json::Value> = ledger.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        for (record, order) in records.iter().zip(["001", "002"]) {
+            assert_eq!(record["order"], order);
+            let files: Vec<&str> = record["files"].as_array().unwrap().iter().map(|f| f.as_str().unwrap()).collect();
+            assert_eq!(files.len(), 2);
+            for n in 1..=4 {
+                let name = format!("IMG_{}.png", n);
+                let img = image::open(copies.join(order).join("Shoot").join(&name)).unwrap().to_rgb8();
+                assert_eq!(img.get_pixel(15, 10).0 == [255, 0, 0], files.contains(&name.as_str()), "copy {} {}", order, name);
+            }
+        }
+    }
+
+    #[test]
+    fn image_formats_are_sniffed_by_content() {
+        let dir = fixture_dir("sniff");
+        let encode = |format: ImageOutputFormat| {
+            let mut out = Vec::new();
+            DynamicImage::ImageRgb8(image::RgbImage::new(8, 8)).write_to(&mut std::io::Cursor::new(&mut out), format).unwrap();
+            out
+        };
+        for (name, output, format) in [
+            ("webp_as.jpg", ImageOutputFormat::WebP, ImageFormat::WebP),
+            ("TIFF_SCAN", ImageOutputFormat::Tiff, ImageFormat::Tiff),
+            ("anim.png", ImageOutputFormat::Gif, ImageFormat::Gif),
+            ("shot", ImageOutputFormat::Bmp, ImageFormat::Bmp),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, encode(output)).unwrap();
+            assert_eq!(image_format(&path), Some(format), "{}", name);
+            assert!(is_supported_file(&path), "{}", name);
+        }
+        assert_eq!(container_for(&dir.path().join("webp_as.jpg")), None, "WebP only takes a tail marker");
+
+        // An AVI is RIFF too, a text starting with "BM" is not a bitmap
+        let avi = dir.path().join("clip");
+        fs::write(&avi, b"RIFF\x10\0\0\0AVI LIST\0\0\0\0").unwrap();
+        let notes = dir.path().join("notes");
+        fs::write(&notes, b"BM notes for the shoot, nothing else").unwrap();
+        for path in [&avi, &notes] {
+            assert_eq!(image_format(path), None);
+            assert!(!is_supported_file(path));
+        }
+        // Backups keep their image content but are not picked up
+        let backup = dir.path().join("shot.bak");
+        fs::write(&backup, encode(ImageOutputFormat::Bmp)).unwrap();
+        assert!(!is_supported_file(&backup));
+    }
+
+    #[test]
+    fn watermarks_keep_webp_tiff_gif_and_bmp_formats() {
+        let dir = fixture_dir("formats");
+        let logo_path = dir.path().join("logo.png");
+        logo_fixture(&logo_path);
+        let logo = load_logo(&logo_path).unwrap();
+        let style = visible::OverlayStyle { opacity: 1.0, ..Default::default() };
+
+        for (name, output, format) in [
+            ("photo.webp", ImageOutputFormat::WebP, ImageFormat::WebP),
+            ("photo.tif", ImageOutputFormat::Tiff, ImageFormat::Tiff),
+            ("photo.gif", ImageOutputFormat::Gif, ImageFormat::Gif),
+            ("photo.bmp", ImageOutputFormat::Bmp, ImageFormat::Bmp),
+        ] {
+            let path = dir.path().join(name);
+            let mut out = fs::File::create(&path).unwrap();
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(200, 150, image::Rgb([255, 255, 255]))).write_to(&mut out, output).unwrap();
+            drop(out);
+
+            edit_image(&path, SaveOptions::default(), |img| visible::overlay(img, &logo, TextPosition::TopLeft, &style)).unwrap();
+            assert_eq!(sniff_image(&fs::read(&path).unwrap()), Some(format), "{}", name);
+            let img = open_image(&path).unwrap().to_rgb8();
+            let px = img.get_pixel(15, 10).0;
+            assert!(px[0] > 200 && px[1] < 60 && px[2] < 60, "{}: {:?}", name, px);
+            assert_eq!(img.get_pixel(150, 100).0, [255, 255, 255], "{}", name);
+
+            if format != ImageFormat::Gif {
+                embed_lsb_marker(&path, &lsb_trailer("Order 12"), 11, SaveOptions::default()).unwrap();
+                assert!(is_lossless_image(&path));
+                let found = find_lsb_marker(&path, 11);
+                assert_eq!(decode_marker(found, &path, None).unwrap().as_deref(), Some("Order 12"), "{}", name);
+            }
+        }
+
+        let webp = dir.path().join("large.webp");
+        let mut out = fs::File::create(&webp).unwrap();
+        let photo = dir.path().join("photo.png");
+        photo_fixture(&photo, 640, 480);
+        image::open(&photo).unwrap().write_to(&mut out, ImageOutputFormat::WebP).unwrap();
+        drop(out);
+        embed_invisible(&webp, 77, 7, 1.0, SaveOptions::default()).unwrap();
+        assert_eq!(sniff_image(&fs::read(&webp).unwrap()), Some(ImageFormat::WebP));
+        assert_eq!(detect_invisible(&webp, 7).unwrap().order, Some(77));
+    }
+
+    #[test]
+    fn animated_gif_is_not_flattened() {
+        let dir = fixture_dir("anim");
+        let path = dir.path().join("anim.gif");
+        let frames = [[255, 0, 0, 255], [0, 0, 255, 255]]
+            .map(|c| image::Frame::new(image::RgbaImage::from_pixel(100, 80, image::Rgba(c))));
+        image::codecs::gif::GifEncoder::new(fs::File::create(&path).unwrap()).encode_frames(frames).unwrap();
+        let original = sha256_of(&path);
+
+        let err = edit_image(&path, SaveOptions::default(), |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("animated"), "{}", err);
+        assert_eq!(sha256_of(&path), original);
+    }
+
+    #[test]
+    fn reencoding_keeps_webp_exif_and_icc_chunks() {
+        let dir = fixture_dir("metadata-webp");
+        let path = dir.path().join("photo.webp");
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(120, 80, image::Rgb([200, 200, 200])))
+            .write_to(&mut std::io::Cursor::new(&mut encoded), ImageOutputFormat::WebP).unwrap();
+        let chunk = |ty: &[u8], body: &[u8]| {
+            let pad: &[u8] = if body.len() % 2 == 1 { &[0] } else { &[] };
+            [ty, &(body.len() as u32).to_le_bytes(), body, pad].concat()
+        };
+        // Orientation 6, as in `jpeg_with_metadata`, and an odd-sized profile
+        let exif = chunk(b"EXIF", b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0");
+        let icc = chunk(b"ICCP", &[0x5A; 301]);
+        let vp8x = chunk(b"VP8X", &[0x28, 0, 0, 0, 119, 0, 0, 79, 0, 0]);
+        let body = [&b"WEBP"[..], &vp8x, &icc, &encoded[12..], &exif].concat();
+        fs::write(&path, [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat()).unwrap();
+        assert_eq!(metadata::read(&fs::read(&path).unwrap()).orientation(), 6);
+
+        let font = load_font(None).unwrap();
+        draw_text_on_image(&path, "Proof", TextPosition::default(), &WatermarkStyle::default(), &font, SaveOptions::default()).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert!(metadata::is_webp(&data));
+        assert!(data.windows(exif.len()).any(|w| w == exif), "EXIF lost");
+        assert!(data.windows(icc.len()).any(|w| w == icc), "ICC profile lost");
+        assert_eq!(metadata::read(&data).orientation(), 6);
+        let img = open_image(&path).unwrap();
+        assert_eq!((img.width(), img.height()), (120, 80));
+    }
+
+    #[test]
+    fn reencoding_keeps_tiff_resolution_icc_and_exif_tags() {
+        let dir = fixture_dir("metadata-tiff");
+        let path = dir.path().join("scan.tif");
+        // A big-endian 4x2 RGB TIFF; the encoder writes little-endian ones
+        let entry = |tag: u16, ty: u16, count: u32, field: [u8; 4]| [&tag.to_be_bytes()[..], &ty.to_be_bytes(), &count.to_be_bytes(), &field].concat();
+        let short = |v: u16| [v.to_be_bytes()[0], v.to_be_bytes()[1], 0, 0];
+        let long = |v: u32| v.to_be_bytes();
+        let icc = [0x5Au8; 301];
+        let date = b"2024:05:01 10:00:00\0";
+        let pixels: Vec<u8> = (0..24).map(|i| i * 10).collect();
+        let ifd = [
+            entry(256, 3, 1, short(4)),
+            entry(257, 3, 1, short(2)),
+            entry(258, 3, 3, long(182)),
+            entry(259, 3, 1, short(1)),
+            entry(262, 3, 1, short(2)),
+            entry(273, 4, 1, long(544)),
+            entry(277, 3, 1, short(3)),
+            entry(278, 3, 1, short(2)),
+            entry(279, 4, 1, long(24)),
+            entry(282, 5, 1, long(188)),
+            entry(283, 5, 1, long(196)),
+            entry(296, 3, 1, short(2)),
+            entry(34665, 4, 1, long(204)),
+            entry(34675, 7, icc.len() as u32, long(242)),
+        ].concat();
+        let exif = [&1u16.to_be_bytes()[..], &entry(36867, 2, date.len() as u32, long(222)), &[0; 4]].concat();
+        let data = [
+            &b"MM\0*"[..], &long(8), &14u16.to_be_bytes(), &ifd, &[0; 4],
+            &[0, 8, 0, 8, 0, 8], &long(300), &long(1), &long(300), &long(1), &exif, date, &icc, &[0], &pixels,
+        ].concat();
+        assert_eq!(data.len(), 544 + 24);
+        fs::write(&path, &data).unwrap();
+        let kept = metadata::read(&data);
+        assert!(!kept.is_empty());
+
+        edit_image(&path, SaveOptions::default(), |_| Ok(())).unwrap();
+        let out = fs::read(&path).unwrap();
+        assert!(out.starts_with(b"II*\0"));
+        assert_eq!(metadata::read(&out), kept, "resolution, ICC profile or EXIF lost");
+        assert_eq!(open_image(&path).unwrap().to_rgb8().into_raw(), pixels);
+    }
+
+    #[test]
+    fn lossy_webp_is_not_rewritten_lossless() {
+        let dir = fixture_dir("lossy-webp");
+        let (lossy, lossless) = (dir.path().join("lossy.webp"), dir.path().join("lossless.webp"));
+        // Only the chunk type matters before anything is decoded
+        let body = [&b"WEBPVP8 "[..], &10u32.to_le_bytes(), &[0x10, 0x02, 0, 0x9D, 0x01, 0x2A, 4, 0, 4, 0]].concat();
+        let data = [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat();
+        fs::write(&lossy, &data).unwrap();
+        DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)).save_with_format(&lossless, ImageFormat::WebP).unwrap();
+
+        let err = save_image(&lossy, DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)), SaveOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("lossy WebP"), "{}", err);
+        assert_eq!(fs::read(&lossy).unwrap(), data);
+        assert!(!is_lossless_image(&lossy), "no LSB marker for lossy WebP");
+        assert!(is_lossless_image(&lossless));
+    }
+
+    #[test]
+    fn batch_gives_images_too_small_or_deep_for_lsb_a_tail_marker() {
+        let shoot = Shoot::white("lsb-fallback", [1]);
+        let key = shoot.src.parent().unwrap().join("project.key").to_string_lossy().to_string();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([255, 255, 255])).save(shoot.src.join("tiny.png")).unwrap();
+        DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(64, 64, image::Rgb([40000u16, 2000, 9000])))
+            .save(shoot.src.join("deep.png")).unwrap();
+        let prefs = Preferences { watermark_key_path: Some(key.clone()), ..Preferences::default() };
+        let batch = Batch::prepare(BatchArgs { mode: Some(EmbedMode::Lsb), overlay_path: None, ..shoot.args(1) }, &prefs).unwrap();
+
+        let warnings = batch.plan().unwrap().warnings;
+        assert!(warnings.iter().any(|w| w.starts_with("tiny.png: Image too small for LSB watermark")), "{:?}", warnings);
+        assert!(warnings.iter().any(|w| w.starts_with("deep.png: LSB watermarks need an 8-bit image")), "{:?}", warnings);
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+
+        let events = std::sync::Mutex::new(Vec::new());
+        batch.run(&AtomicBool::new(false), &|e| events.lock().unwrap().push(e)).unwrap();
+        let events = events.into_inner().unwrap();
+        let copy = shoot.copies.join("001/Shoot");
+        for name in ["tiny.png", "deep.png"] {
+            assert!(matches!(read_marker(&copy.join(name), true, Some(&key)).unwrap(), FoundMarker::Trailer(_)), "{}", name);
+            assert!(events.iter().any(|e| matches!(e, BatchEvent::Log { message, .. }
+                if message.contains(name) && message.ends_with("added a tail marker instead"))), "{}", name);
+        }
+        assert!(matches!(read_marker(&copy.join("IMG_1.png"), true, Some(&key)).unwrap(), FoundMarker::Lsb(_)));
+
+        // Marking the file on its own still fails
+        let opts = MarkOptions { mode: EmbedMode::Lsb, project_key: Some(key), ..Default::default() };
+        let err = add_tail_marker(&shoot.src.join("tiny.png"), "Order 1", &opts).unwrap_err();
+        assert!(err.to_string().contains("Image too small"), "{}", err);
+    }
+
+    #[test]
+    fn batch_reports_progress_per_phase_then_done() {
+        let shoot = Shoot::white("events", [1, 2, 11, 12]);
+        let batch = Batch::prepare(BatchArgs { add_swap: true, create_zip: true, ..shoot.args(2) }, &Preferences::default()).unwrap();
+        let events = std::sync::Mutex::new(Vec::new());
+        batch.run(&AtomicBool::new(false), &|e| events.lock().unwrap().push(e)).unwrap();
+        let events = events.into_inner().unwrap();
+
+        let phases = |copy: usize| {
+            let mut seen: Vec<BatchPhase> = events.iter().filter_map(|e| match e {
+                BatchEvent::Progress { phase, copy: c, .. } | BatchEvent::Log { phase, copy: c, .. } if *c == copy => Some(*phase),
+                _ => None,
+            }).collect();
+            seen.dedup();
+            seen
+        };
+        use BatchPhase::*;
+        assert_eq!(phases(1), [Copy, Watermark, Mark, Swap, Zip]);
+        assert_eq!(phases(2), [Copy, Watermark, Mark, Swap, Zip]);
+        for e in &events {
+            if let BatchEvent::Progress { file, files, bytes, total_bytes, copies, .. } = e {
+                assert!(file <= files && bytes <= total_bytes && *copies == 2, "{:?}", e);
+            }
+        }
+        let last_copy = events.iter().rev().find(|e| matches!(e, BatchEvent::Progress { phase: Copy, copy: 2, .. })).unwrap();
+        assert!(matches!(last_copy, BatchEvent::Progress { file: 4, files: 4, bytes, total_bytes, .. } if bytes == total_bytes));
+        assert!(events.iter().any(|e| matches!(e, BatchEvent::Log { phase: Swap, message, .. } if message == "Swapped photos 1 and 11")));
+        assert!(matches!(events.last(), Some(BatchEvent::Done { copies: 2, .. })));
+        assert!(shoot.copies.join("002/Shoot.zip").exists());
+
+        // The schema the frontend binds to
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["type"], "progress");
+        assert_eq!(json["phase"], "copy");
+        assert_eq!(json["copy"], 1);
+        for field in ["copies", "file", "files", "bytes", "total_bytes"] {
+            assert!(json[field].is_u64(), "{}", field);
+        }
+        let done = serde_json::to_value(events.last().unwrap()).unwrap();
+        assert_eq!(done["type"], "done");
+        assert!(done["output"].as_str().unwrap().ends_with("Shoot-Copies"));
+    }
+
+    #[test]
+    fn batch_reports_the_phase_it_failed_in() {
+        // Too small for the logo
+        let shoot = Shoot::new("events-error", [1], |path| image::RgbImage::from_pixel(40, 30, image::Rgb([255, 255, 255])).save(path).unwrap());
+        let batch = Batch::prepare(BatchArgs { workers: Some(1), ..shoot.args(2) }, &Preferences::default()).unwrap();
+        let events = std::sync::Mutex::new(Vec::new());
+        let err = batch.run(&AtomicBool::new(false), &|e| events.lock().unwrap().push(e)).unwrap_err();
+
+        let events = events.into_inner().unwrap();
+        match events.last().unwrap() {
+            BatchEvent::Error { phase, copy, message, .. } => {
+                assert_eq!((*phase, *copy), (BatchPhase::Watermark, 1));
+                assert_eq!(message, &err.to_string());
+            }
+            other => panic!("expected an error event, got {:?}", other),
+        }
+        assert!(!events.iter().any(|e| matches!(e, BatchEvent::Done { .. })));
+    }
+
+    #[test]
+    fn cancelling_removes_the_unfinished_copy() {
+        let shoot = Shoot::white("cancel", 1..=3);
+        let copies = &shoot.copies;
+
+        for stop_in in [BatchPhase::Copy, BatchPhase::Mark] {
+            let _ = fs::remove_dir_all(copies);
+            let batch = Batch::prepare(BatchArgs { workers: Some(1), ..shoot.args(3) }, &Preferences::default()).unwrap();
+            let cancel = AtomicBool::new(false);
+            let events = std::sync::Mutex::new(Vec::new());
+            let result = batch.run(&cancel, &|e| {
+                if matches!(e, BatchEvent::Progress { phase, copy: 2, .. } if phase == stop_in) {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                events.lock().unwrap().push(e);
+            });
+            assert!(result.is_err());
+
+            let events = events.into_inner().unwrap();
+            assert_eq!(events.last(), Some(&BatchEvent::Cancelled { copy: 2, copies: 3 }), "{:?}", stop_in);
+            assert!(!events.iter().any(|e| matches!(e, BatchEvent::Error { .. } | BatchEvent::Done { .. })));
+            assert!(!matches!(read_marker(&copies.join("001/Shoot/IMG_1.png"), false, None).unwrap(), FoundMarker::None), "finished copy stays");
+            assert!(!copies.join("002").exists(), "unfinished copy removed after {:?}", stop_in);
+            assert!(!copies.join("003").exists());
+            let ledger = fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap();
+            assert_eq!(ledger.lines().count(), 1, "only the finished copy is recorded");
+        }
+    }
+
+    #[test]
+    fn job_registry_follows_events_and_cancellation() {
+        let jobs = jobs::Jobs::default();
+        let first = jobs.start("/shoots/A", 2);
+        let second = jobs.start("/shoots/B", 5);
+        assert_eq!((first.id, second.id), (1, 2));
+
+        first.record(&BatchEvent::Progress { phase: BatchPhase::Mark, copy: 2, copies: 2, file: 1, files: 3, bytes: 10, total_bytes: 30 });
+        let status = jobs.get(1).unwrap().status();
+        assert_eq!((status.state, status.copy, status.phase), (jobs::JobState::Running, 2, Some(BatchPhase::Mark)));
+        first.record(&BatchEvent::Done { copies: 2, output: "/shoots/A-Copies".into() });
+        assert!(!first.cancel(), "a finished job can't be cancelled");
+        assert!(!first.cancel_flag().load(Ordering::Relaxed));
+
+        assert!(second.cancel());
+        assert!(second.cancel_flag().load(Ordering::Relaxed));
+        second.record(&BatchEvent::Cancelled { copy: 1, copies: 5 });
+        second.finish(&Err(anyhow!("Batch cancelled")));
+
+        let states: Vec<_> = jobs.list().into_iter().map(|s| (s.id, s.state)).collect();
+        assert_eq!(states, [(1, jobs::JobState::Done), (2, jobs::JobState::Cancelled)]);
+        assert!(jobs.get(3).is_none());
+
+        let failed = jobs.start("/shoots/C", 1);
+        failed.finish(&Err(anyhow!("Permission denied")));
+        let status = failed.status();
+        assert_eq!((status.state, status.error.as_deref()), (jobs::JobState::Failed, Some("Permission denied")));
+
+        let json = serde_json::to_value(JobEvent { job: 2, event: BatchEvent::Cancelled { copy: 1, copies: 5 } }).unwrap();
+        assert_eq!(json, serde_json::json!({"job": 2, "type": "cancelled", "copy": 1, "copies": 5}));
+    }
+
+    #[test]
+    fn parallel_batches_match_the_sequential_output() {
+        let shoot = Shoot::new("parallel", 1..=6, |path| photo_fixture(path, 120, 90));
+        let copies = &shoot.copies;
+
+        let run = |workers: usize| {
+            let _ = fs::remove_dir_all(copies);
+            let mut batch = Batch::prepare(BatchArgs {
+                add_swap: true,
+                photo_selection: Some(PhotoSelection::Random(2)),
+                workers: Some(workers),
+                io_limit: Some(2),
+                ..shoot.args(5)
+            }, &Preferences::default()).unwrap();
+            batch.seed = 7;
+            batch.run(&AtomicBool::new(false), &|_| {}).unwrap();
+            let mut hashes = Vec::new();
+            for order in 1..=5 {
+                for n in 1..=6 {
+                    hashes.push(sha256_of(&copies.join(format!("{:03}/Shoot/IMG_{}.png", order, n))));
+                }
+            }
+            (hashes, fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap())
+        };
+        let (sequential, ledger) = run(1);
+        assert_eq!(ledger.lines().count(), 5);
+        let orders: Vec<_> = ledger.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["order"].clone()).collect();
+        assert_eq!(orders, ["001", "002", "003", "004", "005"]);
+        assert_eq!(run(4), (sequential, ledger));
+    }
+
+    #[test]
+    fn cancelling_a_parallel_batch_keeps_only_finished_copies() {
+        let shoot = Shoot::white("parallel-cancel", 1..=3);
+        let batch = Batch::prepare(BatchArgs { workers: Some(3), io_limit: Some(1), ..shoot.args(6) }, &Preferences::default()).unwrap();
+        let cancel = AtomicBool::new(false);
+        let events = std::sync::Mutex::new(Vec::new());
+        let result = batch.run(&cancel, &|e| {
+            if matches!(e, BatchEvent::Progress { phase: BatchPhase::Mark, copy: 2, .. }) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            events.lock().unwrap().push(e);
+        });
+        assert!(result.is_err());
+
+        let events = events.into_inner().unwrap();
+        let cancelled: Vec<_> = events.iter().filter(|e| matches!(e, BatchEvent::Cancelled { .. })).collect();
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(events.last(), Some(cancelled[0]));
+        assert!(!events.iter().any(|e| matches!(e, BatchEvent::Error { .. } | BatchEvent::Done { .. })));
+
+        let copies = &shoot.copies;
+        let ledger = fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap_or_default();
+        let recorded: Vec<String> = ledger.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["order"].as_str().unwrap().to_string()).collect();
+        for order in 1..=6 {
+            let name = format!("{:03}", order);
+            let folder = copies.join(&name);
+            assert_eq!(folder.exists(), recorded.contains(&name), "copy {} is kept only if finished", name);
+            if folder.exists() {
+                for n in 1..=3 {
+                    assert!(!matches!(read_marker(&folder.join(format!("Shoot/IMG_{}.png", n)), false, None).unwrap(), FoundMarker::None));
+                }
+            }
+        }
+        assert!(!copies.join("002").exists());
+    }
+
+    #[test]
+    fn batch_args_come_as_the_frontend_sends_them() {
+        // As in batchForm.ts: options left out are unset
+        let args: BatchArgs = serde_json::from_value(serde_json::json!({
+            "source_folder": "/photos/Shoot", "num_copies": 3, "base_text": "Order", "add_swap": false,
+            "add_watermark": true, "create_zip": false, "photo_number": 2,
+        })).unwrap();
+        assert_eq!((args.source_folder.as_str(), args.num_copies, args.photo_number), ("/photos/Shoot", 3, Some(2)));
+        assert!(args.watermark_text.is_none() && args.seed.is_none() && args.workers.is_none());
+    }
+
+    #[test]
+    fn plan_matches_the_batch_without_writing_anything() {
+        let shoot = Shoot::new("plan", [1, 2, 3, 11], |path| photo_fixture(path, 60, 40));
+        let (src, copies) = (&shoot.src, &shoot.copies);
+        fs::create_dir(src.join("raw")).unwrap();
+        fs::write(src.join("raw/notes.txt"), "shot list").unwrap();
+        fs::write(src.join("readme.md"), "not a supported file").unwrap();
+        add_tail_marker(&src.join("raw/notes.txt"), "Earlier 1", &MarkOptions::default()).unwrap();
+
+        let args = |selection: Option<PhotoSelection>, create_zip| BatchArgs {
+            add_swap: true,
+            create_zip,
+            photo_selection: selection,
+            seed: Some(9),
+            workers: Some(3),
+            io_limit: Some(1),
+            ..shoot.args(2)
+        };
+        let plan = Batch::prepare(args(None, true), &Preferences::default()).unwrap().plan().unwrap();
+        assert!(!copies.exists(), "planning writes nothing");
+
+        let source_bytes = tree_size(src).1;
+        assert_eq!(plan.bytes_per_copy, source_bytes);
+        // One copy zipped at a time, as there is a single I/O slot
+        assert_eq!((plan.disk_bytes, plan.peak_disk_bytes), (2 * source_bytes, 3 * source_bytes));
+        assert_eq!(plan.copies.iter().map(|c| c.order.as_str()).collect::<Vec<_>>(), ["001", "002"]);
+        let first = &plan.copies[0];
+        assert!(first.zip.as_deref().unwrap().ends_with("001/Shoot.zip"));
+        let file = |path: &str| first.files.iter().find(|f| f.path == path).unwrap();
+        assert!(file("IMG_1.png").visible_watermark && file("IMG_1.png").marker);
+        assert_eq!(file("IMG_1.png").swapped_with.as_deref(), Some("IMG_11.png"));
+        assert_eq!(file("IMG_11.png").swapped_with.as_deref(), Some("IMG_1.png"));
+        assert!(!file("IMG_2.png").visible_watermark);
+        assert!(!file("raw/notes.txt").marker, "already marked");
+        assert!(!file("readme.md").marker, "not a supported file");
+        assert_eq!(first.files.len(), 6);
+        assert_eq!(plan.warnings, [
+            "1 files already carry a watermark and keep it",
+            "Copy 002: no photo 12 to swap with photo 2",
+        ]);
+
+        // Random picks planned with a seed are the ones the batch makes with it
+        let plan = Batch::prepare(args(Some(PhotoSelection::Random(2)), false), &Preferences::default()).unwrap().plan().unwrap();
+        assert!(plan.copies[0].zip.is_none());
+        Batch::prepare(args(Some(PhotoSelection::Random(2)), false), &Preferences::default()).unwrap().run(&AtomicBool::new(false), &|_| {}).unwrap();
+        let ledger = fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap();
+        assert_eq!(ledger.lines().count(), 2);
+        for (line, copy) in ledger.lines().zip(&plan.copies) {
+            let planned: Vec<&str> = copy.files.iter().filter(|f| f.visible_watermark).map(|f| f.path.as_str()).collect();
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["files"], serde_json::json!(planned));
+        }
+
+        let plan = Batch::prepare(args(Some(PhotoSelection::Glob("*.jpg".into())), false), &Preferences::default()).unwrap().plan().unwrap();
+        assert!(plan.warnings.contains(&"The photo selection matches no images".to_string()));
+        assert!(plan.warnings.iter().any(|w| w.starts_with("Copy 001:") && w.ends_with("already exists, its files will be overwritten")));
+    }
+
+    #[test]
+    fn resumed_batch_redoes_only_unfinished_or_damaged_copies() {
+        let shoot = Shoot::new("resume", 1..=4, |path| photo_fixture(path, 80, 60));
+        let (src, copies) = (&shoot.src, &shoot.copies);
+        let font = src.parent().unwrap().join("label.ttf");
+        fs::write(&font, BUNDLED_FONT).unwrap();
+        let prefs = Preferences { watermark_font_path: Some(font.to_string_lossy().to_string()), ..Preferences::default() };
+        let args = || BatchArgs {
+            add_watermark: true,
+            photo_selection: Some(PhotoSelection::Random(2)),
+            seed: Some(5),
+            workers: Some(1),
+            ..shoot.args(4)
+        };
+        let outputs = || -> Vec<Vec<u8>> {
+            (1..=4).flat_map(|order| (1..=4).map(move |n| (order, n)))
+                .map(|(order, n)| sha256_of(&copies.join(format!("{:03}/Shoot/IMG_{}.png", order, n))))
+                .collect()
+        };
+        Batch::prepare(args(), &prefs).unwrap().run(&AtomicBool::new(false), &|_| {}).unwrap();
+        let (expected, expected_ledger) = (outputs(), fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap());
+        fs::remove_dir_all(copies).unwrap();
+
+        let Err(e) = Batch::resume(&src.to_string_lossy()) else { panic!("nothing to resume") };
+        assert!(e.to_string().starts_with("No batch to resume"));
+
+        // Stopped in copy 4, then copy 2 loses a file and copy 3 has a byte changed
+        let cancel = AtomicBool::new(false);
+        let result = Batch::prepare(args(), &prefs).unwrap().run(&cancel, &|e| {
+            if matches!(e, BatchEvent::Progress { phase: BatchPhase::Mark, copy: 4, .. }) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        assert!(result.is_err());
+        let state = batch_state::BatchState::load(copies).unwrap();
+        assert_eq!(state.finished.keys().collect::<Vec<_>>(), ["001", "002", "003"]);
+        assert_eq!(state.prefs.watermark_font_path, prefs.watermark_font_path, "copies made on resume use the same font");
+        fs::remove_file(copies.join("002/Shoot/IMG_4.png")).unwrap();
+        let changed = copies.join("003/Shoot/IMG_2.png");
+        let mut data = fs::read(&changed).unwrap();
+        data[40] ^= 1;
+        fs::write(&changed, data).unwrap();
+        let first = sha256_of(&copies.join("001/Shoot/IMG_1.png"));
+        // Lines of an earlier batch into the same folder, and one for the redone copy 3
+        let earlier = "{\"order\":\"041\",\"files\":[\"IMG_1.png\"]}\n{\"order\":\"003\",\"files\":[\"stale.png\"]}\n";
+        let mut ledger_file = OpenOptions::new().append(true).open(copies.join(VISIBLE_LEDGER)).unwrap();
+        ledger_file.write_all(earlier.as_bytes()).unwrap();
+        drop(ledger_file);
+
+        let batch = Batch::resume(&src.to_string_lossy()).unwrap();
+        assert_eq!(batch.date, state.date);
+        let events = std::sync::Mutex::new(Vec::new());
+        batch.run(&AtomicBool::new(false), &|e| events.lock().unwrap().push(e)).unwrap();
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.iter().filter(|e| matches!(e, BatchEvent::Skipped { .. })).collect::<Vec<_>>(), [&BatchEvent::Skipped { copy: 1, copies: 4 }]);
+        assert!(!events.iter().any(|e| matches!(e, BatchEvent::Progress { copy: 1, .. })));
+        assert!(matches!(events.last(), Some(BatchEvent::Done { copies: 4, .. })));
+
+        assert_eq!(sha256_of(&copies.join("001/Shoot/IMG_1.png")), first);
+        assert_eq!(outputs(), expected, "same copies as an uninterrupted run");
+        let mut ledger: Vec<_> = fs::read_to_string(copies.join(VISIBLE_LEDGER)).unwrap().lines().map(String::from).collect();
+        assert!(ledger.contains(&"{\"order\":\"041\",\"files\":[\"IMG_1.png\"]}".to_string()), "other batches' lines stay");
+        ledger.retain(|line| !line.contains("\"041\""));
+        ledger.sort();
+        assert_eq!(ledger, expected_ledger.lines().collect::<Vec<_>>(), "every copy recorded once");
+        let state = batch_state::BatchState::load(copies).unwrap();
+        assert_eq!(state.finished.keys().collect::<Vec<_>>(), ["001", "002", "003", "004"]);
+        let output = &state.finished["003"];
+        assert_eq!(output.files.keys().collect::<Vec<_>>(), ["IMG_1.png", "IMG_2.png", "IMG_3.png", "IMG_4.png"]);
+        assert_eq!(output.files["IMG_2.png"].bytes, fs::metadata(&changed).unwrap().len());
+        assert_eq!(*output, batch_state::CopyOutput::of_folder(&copies.join("003/Shoot")).unwrap());
+    }
+
+    #[test]
+    fn saving_preferences_keeps_the_ones_left_out() {
+        let dir = fixture_dir("prefs");
+        let path = dir.path().join("preferences.json");
+        let prefs = |value: serde_json::Value| value.as_object().unwrap().clone();
+        write_preferences(&path, prefs(serde_json::json!({"watermark_key_path": "/projects/key", "batch_workers": 4}))).unwrap();
+        // As the frontend sends them on a folder change
+        write_preferences(&path, prefs(serde_json::json!({"theme_mode": "dark", "auto_clear_console": true, "last_selected_path": "/shoots/A"}))).unwrap();
+
+        let stored: Preferences = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(stored.watermark_key_path.as_deref(), Some("/projects/key"));
+        assert_eq!((stored.batch_workers, stored.theme_mode.as_deref()), (Some(4), Some("dark")));
+        assert!(write_preferences(&path, prefs(serde_json::json!({"batch_workers": "many"}))).is_err());
+        assert_eq!(serde_json::from_slice::<Preferences>(&fs::read(&path).unwrap()).unwrap().batch_workers, Some(4));
+    }
+
+    #[test]
+    fn keyed_tail_markers_use_the_project_key_they_are_given() {
+        let dir = fixture_dir("project-key");
+        let key_path = dir.path().join("project.key").to_string_lossy().to_string();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, b"notes").unwrap();
+        let opts = MarkOptions { scheme: CipherScheme::Keyed, project_key: Some(key_path.clone()), ..Default::default() };
+        assert!(add_tail_marker(&path, "Order 042", &opts).unwrap());
+        assert!(dir.path().join("project.key").exists(), "key made on first use");
+        assert_eq!(extract_marker(&path, false, Some(&key_path)).unwrap().as_deref(), Some("Order 042"));
+
+        let other = dir.path().join("other.key");
+        keyed::create_key(&other).unwrap();
+        assert!(extract_marker(&path, false, Some(&other.to_string_lossy())).is_err());
+    }
+
+    #[test]
+    fn marker_bodies_are_tagged_with_their_scheme() {
+        assert_eq!(parse_marker_body("!k!c2VhbGVk"), (CipherScheme::Keyed, "c2VhbGVk"));
+        assert_eq!(parse_marker_body("!c!Vykly 719"), (CipherScheme::Caesar, "Vykly 719"));
+        // Unknown tags are part of an old untagged body
+        assert_eq!(parse_marker_body("!x!Vykly"), (CipherScheme::Caesar, "!x!Vykly"));
+        assert_eq!(parse_marker_body("!k"), (CipherScheme::Caesar, "!k"));
+
+        let body = marker_body("Order 042", CipherScheme::Caesar, None).unwrap();
+        assert_eq!(body, "!c!Vykly 719");
+        assert_eq!(decode_marker_body(&body, None).unwrap(), "Order 042");
+    }
+
+    #[test]
+    fn untagged_legacy_bodies_still_decode_as_caesar() {
+        assert_eq!(decode_marker_body("Vykly 719", None).unwrap(), "Order 042");
+        assert_eq!(caesar_decode(&caesar_encode("Zebra yz 09")), "Zebra yz 09");
+        assert_eq!(caesar_encode("xyz XYZ 389"), "efg EFG 056");
+    }
+
+    /// Trailer for `content` signed with `key`, as `add_tail_marker` writes it.
+    fn signed_trailer(key: &ed25519_dalek::SigningKey, content: &[u8], text: &str) -> Vec<u8> {
+        use sha2::Digest;
+        let scheme = CipherScheme::Caesar.id();
+        let text = caesar_encode(text).into_bytes();
+        let hash: [u8; 32] = sha2::Sha256::digest(content).into();
+        let signature = signing::sign(key, scheme, &text, &hash);
+        marker::encode(scheme, &[
+            (marker::FIELD_TEXT, &text),
+            (marker::FIELD_CONTENT_HASH, &hash),
+            (marker::FIELD_SIGNATURE, &signature),
+            (marker::FIELD_SIGNER, key.verifying_key().as_bytes()),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn signatures_verify_only_for_the_signed_content_and_key() {
+        use base64::Engine;
+        let dir = fixture_dir("verify");
+        let path = dir.path().join("photo.jpg");
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let public = Some(base64::engine::general_purpose::STANDARD.encode(key.verifying_key().as_bytes()));
+        let verify = |public: &Option<String>| verify_marker(&path, public.clone(), false, None).unwrap();
+        let original = jpeg_fixture();
+
+        fs::write(&path, [&original[..], &signed_trailer(&key, &original, "Order 042")].concat()).unwrap();
+        assert_eq!(verify(&public).status, SignatureStatus::Valid);
+
+        let other = ed25519_dalek::SigningKey::from_bytes(&[10; 32]);
+        let report = verify(&Some(base64::engine::general_purpose::STANDARD.encode(other.verifying_key().as_bytes())));
+        assert_eq!((report.status, report.reason.as_deref()), (SignatureStatus::Invalid, Some("signed by a different key")));
+
+        // The file body changed after signing
+        let mut edited = original.clone();
+        edited[100] ^= 0xFF;
+        fs::write(&path, [&edited[..], &signed_trailer(&key, &original, "Order 042")].concat()).unwrap();
+        let report = verify(&public);
+        assert_eq!((report.status, report.reason.as_deref()), (SignatureStatus::Invalid, Some("file content was modified")));
+
+        // Another text under the old signature
+        let genuine = signed_trailer(&key, &original, "Order 042");
+        let marker::Detected::Found(t) = marker::parse(&genuine) else { panic!("trailer not found") };
+        let forged_text = caesar_encode("Order 043").into_bytes();
+        let forged = marker::encode(t.scheme, &[
+            (marker::FIELD_TEXT, &forged_text),
+            (marker::FIELD_CONTENT_HASH, t.field(marker::FIELD_CONTENT_HASH).unwrap()),
+            (marker::FIELD_SIGNATURE, t.field(marker::FIELD_SIGNATURE).unwrap()),
+            (marker::FIELD_SIGNER, t.field(marker::FIELD_SIGNER).unwrap()),
+        ]).unwrap();
+        fs::write(&path, [&original[..], &forged].concat()).unwrap();
+        let report = verify(&public);
+        assert_eq!((report.status, report.reason.as_deref()), (SignatureStatus::Invalid, Some("signature does not match")));
+
+        fs::write(&path, &original).unwrap();
+        assert!(add_tail_marker(&path, "Order 042", &MarkOptions::default()).unwrap());
+        assert_eq!(verify(&public).status, SignatureStatus::Unsigned);
+    }
+}
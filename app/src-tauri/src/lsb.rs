@@ -0,0 +1,124 @@
+// Least-significant-bit watermark for lossless images. The payload, prefixed by
+// its length (u32 LE), is written one bit per colour sample (alpha is left
+// alone) in an order given by a keyed permutation of all samples, so the bits are
+// spread over the whole picture and can't be read back without the key.
+//
+// The permutation is a small Feistel network over the sample indices with cycle
+// walking, which maps index i to its slot without materialising a table as large
+// as the image.
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+use crate::keyed::WatermarkKey;
+
+const LEN_PREFIX: usize = 4;
+const ROUNDS: usize = 4;
+
+/// Permutation seed for a watermark key.
+pub fn seed(key: &WatermarkKey) -> u64 {
+    let digest = Sha256::new().chain_update(b"endecode-lsb-v1").chain_update(key).finalize();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Which bytes of an interleaved 8-bit buffer carry bits: every colour sample,
+/// alpha (the last channel of grey+alpha and RGBA) is skipped.
+struct Slots {
+    channels: usize,
+    color: usize,
+    count: u64,
+}
+
+impl Slots {
+    fn new(samples: usize, channels: usize) -> Self {
+        let color = if channels.is_multiple_of(2) { channels - 1 } else { channels };
+        Slots { channels, color, count: (samples / channels * color) as u64 }
+    }
+
+    fn index(&self, slot: u64) -> usize {
+        let slot = slot as usize;
+        slot / self.color * self.channels + slot % self.color
+    }
+
+    /// Payload bytes that fit after the length prefix.
+    fn capacity(&self) -> usize {
+        ((self.count / 8) as usize).saturating_sub(LEN_PREFIX)
+    }
+}
+
+struct Permutation {
+    n: u64,
+    half_bits: u32,
+    keys: [u64; ROUNDS],
+}
+
+impl Permutation {
+    fn new(n: u64, seed: u64) -> Self {
+        let bits = 64 - n.saturating_sub(1).leading_zeros();
+        let mut state = seed;
+        let keys = std::array::from_fn(|_| { state = mix(state); state });
+        Permutation { n, half_bits: bits.div_ceil(2).max(1), keys }
+    }
+
+    fn apply(&self, mut x: u64) -> u64 {
+        // Cycle walking: the network permutes a power-of-four domain, step again
+        // until the result falls inside 0..n
+        loop {
+            x = self.feistel(x);
+            if x < self.n { return x; }
+        }
+    }
+
+    fn feistel(&self, x: u64) -> u64 {
+        let mask = (1u64 << self.half_bits) - 1;
+        let (mut l, mut r) = (x >> self.half_bits, x & mask);
+        for k in self.keys {
+            (l, r) = (r, l ^ (mix(r ^ k) & mask));
+        }
+        (l << self.half_bits) | r
+    }
+}
+
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Fails if `len` payload bytes don't fit in an image of `pixels` pixels with
+/// `channels` 8-bit channels.
+pub fn check_capacity(pixels: usize, channels: usize, len: usize) -> anyhow::Result<()> {
+    let holds = Slots::new(pixels * channels, channels).capacity();
+    if len > holds {
+        return Err(anyhow!("Image too small for LSB watermark: payload needs {} bytes, image holds {}", len, holds));
+    }
+    Ok(())
+}
+
+pub fn embed(data: &mut [u8], channels: usize, payload: &[u8], seed: u64) -> anyhow::Result<()> {
+    check_capacity(data.len() / channels, channels, payload.len())?;
+    let slots = Slots::new(data.len(), channels);
+    let perm = Permutation::new(slots.count, seed);
+    let len = (payload.len() as u32).to_le_bytes();
+    for (i, byte) in len.iter().chain(payload).enumerate() {
+        for bit in 0..8 {
+            let at = slots.index(perm.apply((i * 8 + bit) as u64));
+            data[at] = (data[at] & !1) | (byte >> bit & 1);
+        }
+    }
+    Ok(())
+}
+
+/// The embedded payload, or `None` if the length prefix can't be right (no LSB
+/// watermark, or one made with another key). Payloads over `max_len` are refused.
+pub fn extract(data: &[u8], channels: usize, seed: u64, max_len: usize) -> Option<Vec<u8>> {
+    let slots = Slots::new(data.len(), channels);
+    if slots.count < (LEN_PREFIX * 8) as u64 { return None; }
+    let perm = Permutation::new(slots.count, seed);
+    let read = |i: usize| -> u8 {
+        (0..8).fold(0u8, |b, bit| b | (data[slots.index(perm.apply((i * 8 + bit) as u64))] & 1) << bit)
+    };
+    let len = u32::from_le_bytes(std::array::from_fn(read)) as usize;
+    if len == 0 || len > max_len || len > slots.capacity() { return None; }
+    Some((LEN_PREFIX..LEN_PREFIX + len).map(read).collect())
+}
@@ -0,0 +1,287 @@
+// Binary tail marker ("trailer") appended to watermarked files.
+//
+// Layout, read backwards from the end of the file:
+//
+//   header   : HEAD_MAGIC | payload_len u32 LE | meta | crc32 of payload_len and meta, u32 LE
+//   payload  : payload_len bytes of TLV fields (type u8, len u16 LE, data)
+//   footer   : payload_len u32 LE | crc32 u32 LE | meta | MAGIC
+//
+// where meta is version u8 | scheme u8 | reserved [u8; 2]. The footer CRC covers
+// the payload plus meta. New data goes into new TLV field types, which older
+// readers skip; `version` is only bumped for changes old readers cannot safely
+// ignore (version 2 added the header, which removal has to cut off too).
+//
+// The last field holds Reed-Solomon parity over all payload bytes before it, so a
+// trailer whose CRC fails because of a few damaged bytes is repaired rather than
+// rejected. The parity can't see the footer, which is why the header repeats the
+// length and meta under a CRC of its own: with an intact header, a damaged footer
+// or CRC doesn't matter as long as the parity vouches for the payload, and a
+// trailer whose footer was lost to a truncated download is still found.
+// Trailers written before the parity field or the header existed are still read.
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+pub const MAGIC: &[u8; 8] = b"\x89EDCWM\r\n";
+pub const HEAD_MAGIC: &[u8; 8] = b"\x89EDCWH\r\n";
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 20;
+pub const FOOTER_LEN: usize = 20;
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+/// Longest a whole trailer can be.
+pub const MAX_TRAILER_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + FOOTER_LEN;
+
+/// Encoded watermark text (already passed through the trailer's scheme).
+pub const FIELD_TEXT: u8 = 1;
+/// SHA-256 of the file content the trailer was appended to.
+pub const FIELD_CONTENT_HASH: u8 = 2;
+/// Ed25519 signature over scheme, text and content hash (see `signing.rs`).
+pub const FIELD_SIGNATURE: u8 = 3;
+/// Public key of the signer.
+pub const FIELD_SIGNER: u8 = 4;
+/// Reed-Solomon parity over the preceding payload bytes (see `ecc.rs`). Always last.
+pub const FIELD_PARITY: u8 = 5;
+
+pub struct Trailer {
+    pub scheme: u8,
+    pub fields: Vec<(u8, Vec<u8>)>,
+    /// Payload plus footer, i.e. how many bytes to cut from the end to remove it.
+    pub total_len: usize,
+    /// Damaged bytes repaired while reading.
+    pub corrected: usize,
+    /// Most damaged bytes the parity could have repaired; 0 for trailers without parity.
+    pub correctable: usize,
+}
+
+impl Trailer {
+    pub fn field(&self, ty: u8) -> Option<&[u8]> {
+        self.fields.iter().find(|(t, _)| *t == ty).map(|(_, v)| v.as_slice())
+    }
+}
+
+pub enum Detected {
+    Found(Trailer),
+    /// Footer magic is present but the trailer does not check out.
+    Corrupt(String),
+    None,
+}
+
+pub fn encode(scheme: u8, fields: &[(u8, &[u8])]) -> anyhow::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for (ty, data) in fields {
+        if data.len() > u16::MAX as usize { return Err(anyhow::anyhow!("Watermark field {} too long", ty)); }
+        push_field_header(&mut payload, *ty, data.len());
+        payload.extend_from_slice(data);
+    }
+    let parity = crate::ecc::parity(&payload);
+    push_field_header(&mut payload, FIELD_PARITY, parity.len());
+    payload.extend_from_slice(&parity);
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(anyhow::anyhow!("Watermark payload too long ({} bytes)", payload.len()));
+    }
+    let meta = [VERSION, scheme, 0, 0];
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut out = HEAD_MAGIC.to_vec();
+    out.extend_from_slice(&len);
+    out.extend_from_slice(&meta);
+    out.extend_from_slice(&checksum(&len, &meta).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&len);
+    out.extend_from_slice(&checksum(&payload, &meta).to_le_bytes());
+    out.extend_from_slice(&meta);
+    out.extend_from_slice(MAGIC);
+    Ok(out)
+}
+
+fn push_field_header(payload: &mut Vec<u8>, ty: u8, len: usize) {
+    payload.push(ty);
+    payload.extend_from_slice(&(len as u16).to_le_bytes());
+}
+
+/// Fix a payload whose CRC failed, or can't be checked, using its parity field.
+/// The field's position follows from the payload length alone, so its own
+/// damaged header doesn't matter. `None` if the parity can't vouch for it.
+fn repair(payload: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let data_len = (0..payload.len()).find(|&n| n + 3 + crate::ecc::parity_len(n) == payload.len())?;
+    let parity_len = payload.len() - data_len - 3;
+    let mut fixed = payload[..data_len].to_vec();
+    let corrected = crate::ecc::correct(&mut fixed, &payload[data_len + 3..])?;
+    push_field_header(&mut fixed, FIELD_PARITY, parity_len);
+    fixed.extend_from_slice(&payload[data_len + 3..]);
+    Some((fixed, corrected))
+}
+
+fn checksum(payload: &[u8], meta: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.update(meta);
+    hasher.finalize()
+}
+
+fn footer_payload_len(footer: &[u8]) -> usize {
+    u32::from_le_bytes(footer[0..4].try_into().unwrap()) as usize
+}
+
+/// Length and meta from an intact header.
+struct Header {
+    payload_len: usize,
+    meta: [u8; 4],
+}
+
+fn header_at(data: &[u8], pos: usize) -> Option<Header> {
+    let h = data.get(pos..pos + HEADER_LEN)?;
+    if &h[0..8] != HEAD_MAGIC { return None; }
+    if checksum(&h[8..12], &h[12..16]) != u32::from_le_bytes(h[16..20].try_into().unwrap()) { return None; }
+    let payload_len = u32::from_le_bytes(h[8..12].try_into().unwrap()) as usize;
+    (payload_len <= MAX_PAYLOAD_LEN).then(|| Header { payload_len, meta: h[12..16].try_into().unwrap() })
+}
+
+/// The last intact header in the trailer-sized end of `data`, and where it starts.
+fn find_header(data: &[u8]) -> Option<(usize, Header)> {
+    let last = data.len().checked_sub(HEADER_LEN)?;
+    (data.len().saturating_sub(MAX_TRAILER_LEN)..=last).rev()
+        .filter(|&pos| data[pos] == HEAD_MAGIC[0])
+        .find_map(|pos| header_at(data, pos).map(|h| (pos, h)))
+}
+
+/// Parse a trailer ending exactly at the end of `data`, or one whose footer (and
+/// nothing before it) was cut off.
+pub fn parse(data: &[u8]) -> Detected {
+    let footer = data.len().checked_sub(FOOTER_LEN).map(|at| &data[at..]).filter(|f| &f[12..20] == MAGIC);
+
+    let header = find_header(data).filter(|(pos, h)| data.len() - pos - HEADER_LEN <= h.payload_len + FOOTER_LEN);
+    let (payload, meta, crc, start, trusted_meta) = match (header, footer) {
+        (Some((pos, h)), _) => {
+            let start = pos + HEADER_LEN;
+            let present = data.len() - start;
+            if present < h.payload_len {
+                return Detected::Corrupt(format!("trailer truncated, {} of {} payload bytes left", present, h.payload_len));
+            }
+            // The footer's CRC, if the footer is all there
+            let crc = (present == h.payload_len + FOOTER_LEN)
+                .then(|| u32::from_le_bytes(data[data.len() - 16..data.len() - 12].try_into().unwrap()));
+            (&data[start..start + h.payload_len], h.meta, crc, pos, true)
+        }
+        (None, Some(footer)) => {
+            let payload_len = footer_payload_len(footer);
+            if payload_len > MAX_PAYLOAD_LEN || payload_len > data.len() - FOOTER_LEN {
+                return Detected::Corrupt(format!("payload length {} out of range", payload_len));
+            }
+            let start = data.len() - FOOTER_LEN - payload_len;
+            let crc = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+            (&data[start..data.len() - FOOTER_LEN], footer[8..12].try_into().unwrap(), Some(crc), start, false)
+        }
+        (None, None) => return Detected::None,
+    };
+    let payload_len = payload.len();
+
+    let repaired;
+    let (payload, corrected) = if crc == Some(checksum(payload, &meta)) {
+        (payload, 0)
+    } else {
+        match repair(payload) {
+            // Without a header the parity can't vouch for the footer's scheme and
+            // version, so the CRC still has to match then
+            Some((fixed, corrected)) if trusted_meta || crc == Some(checksum(&fixed, &meta)) => {
+                repaired = fixed;
+                (&repaired[..], corrected)
+            }
+            _ if crc.is_none() => return Detected::Corrupt("trailer footer missing and payload can't be verified".to_string()),
+            _ => return Detected::Corrupt("checksum mismatch".to_string()),
+        }
+    };
+    let version = meta[0];
+    if version > VERSION {
+        return Detected::Corrupt(format!("unsupported trailer version {}", version));
+    }
+
+    let mut fields = Vec::new();
+    let mut i = 0;
+    while i < payload.len() {
+        if i + 3 > payload.len() { return Detected::Corrupt("truncated field header".to_string()); }
+        let ty = payload[i];
+        let len = u16::from_le_bytes([payload[i + 1], payload[i + 2]]) as usize;
+        i += 3;
+        if i + len > payload.len() { return Detected::Corrupt(format!("field {} overruns payload", ty)); }
+        fields.push((ty, payload[i..i + len].to_vec()));
+        i += len;
+    }
+
+    let correctable = match fields.last() {
+        Some((FIELD_PARITY, parity)) => crate::ecc::correctable(payload_len - 3 - parity.len()),
+        _ => 0,
+    };
+    Detected::Found(Trailer { scheme: meta[1], fields, total_len: data.len() - start, corrected, correctable })
+}
+
+/// Read the trailer at the end of `file` without loading the rest of it.
+pub fn read(file: &mut File) -> std::io::Result<Detected> {
+    let file_len = file.metadata()?.len();
+    read_before(file, file_len)
+}
+
+/// Read a trailer ending at byte offset `end` of `file`. Only the footer and the
+/// trailer it points at are read, or the last `MAX_TRAILER_LEN` bytes when the
+/// footer is damaged or missing.
+pub fn read_before(file: &mut File, end: u64) -> std::io::Result<Detected> {
+    if end >= FOOTER_LEN as u64 {
+        let mut footer = [0u8; FOOTER_LEN];
+        file.seek(SeekFrom::Start(end - FOOTER_LEN as u64))?;
+        file.read_exact(&mut footer)?;
+        let payload_len = footer_payload_len(&footer);
+        if &footer[12..20] == MAGIC && payload_len <= MAX_PAYLOAD_LEN {
+            // With room for a header, when there is one
+            let total = ((HEADER_LEN + payload_len + FOOTER_LEN) as u64).min(end);
+            if let found @ Detected::Found(_) = parse(&read_window(file, end, total)?) {
+                return Ok(found);
+            }
+        }
+    }
+    Ok(parse(&read_window(file, end, end.min(MAX_TRAILER_LEN as u64))?))
+}
+
+fn read_window(file: &mut File, end: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(end - len))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailer_encodes_and_parses_back() {
+        let hash = [0xAB; 32];
+        let trailer = encode(1, &[(FIELD_TEXT, b"sealed"), (FIELD_CONTENT_HASH, &hash)]).unwrap();
+        assert!(trailer.starts_with(HEAD_MAGIC) && trailer.ends_with(MAGIC));
+        let data = [&b"file content"[..], &trailer].concat();
+        let Detected::Found(t) = parse(&data) else { panic!("trailer not found") };
+        assert_eq!(t.scheme, 1);
+        assert_eq!((t.field(FIELD_TEXT), t.field(FIELD_CONTENT_HASH)), (Some(&b"sealed"[..]), Some(&hash[..])));
+        assert_eq!(t.fields.last().unwrap().0, FIELD_PARITY);
+        assert_eq!((t.total_len, t.corrected), (trailer.len(), 0));
+        assert!(t.correctable > 0);
+        assert!(matches!(parse(b"file content"), Detected::None));
+    }
+
+    #[test]
+    fn footer_only_trailers_with_a_bad_checksum_or_length_are_corrupt() {
+        // Without its header, only the footer CRC can vouch for the payload
+        let mut trailer = encode(0, &[(FIELD_TEXT, b"Vykly 719")]).unwrap();
+        trailer[0] ^= 0xFF;
+        let crc_at = trailer.len() - 16;
+        let mut bad_crc = trailer.clone();
+        bad_crc[crc_at] ^= 0x01;
+        match parse(&bad_crc) {
+            Detected::Corrupt(msg) => assert_eq!(msg, "checksum mismatch"),
+            _ => panic!("bad checksum not reported"),
+        }
+        let mut bad_len = trailer.clone();
+        bad_len[crc_at - 4..crc_at].copy_from_slice(&u32::MAX.to_le_bytes());
+        match parse(&bad_len) {
+            Detected::Corrupt(msg) => assert!(msg.contains("out of range"), "{}", msg),
+            _ => panic!("bad length not reported"),
+        }
+    }
+}
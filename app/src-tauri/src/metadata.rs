@@ -0,0 +1,596 @@
+// What re-encoding an image through the `image` crate would lose, taken from
+// the original bytes and put back into the newly encoded file: EXIF (and with it
+// the orientation tag), ICC profiles, XMP/IPTC and PNG colour and text chunks.
+// Segments and chunks are copied verbatim, which is possible because the output
+// keeps the container format of the input. WebP keeps its ICCP/EXIF/XMP chunks,
+// for which the encoder's simple file is rewritten in the extended (VP8X) layout.
+// TIFF keeps its resolution, XMP and ICC tags and its EXIF and GPS IFDs, which are
+// copied entry by entry into a new first IFD, in the byte order of the output.
+// GIF and BMP metadata is not carried over.
+//
+// The EXIF orientation tag is read (and can be reset) here too, together with
+// the pixel transforms between how an image is stored and how viewers show it.
+//
+// For JPEG the source quality is also estimated from its luminance quantization
+// table, by finding the libjpeg quality setting that reproduces it most closely.
+use anyhow::anyhow;
+use std::io::{Read, Seek, SeekFrom};
+use image::imageops::{flip_horizontal, flip_vertical, rotate180, rotate270, rotate90};
+use image::{ImageBuffer, Pixel};
+
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_APP2: u8 = 0xE2;
+const JPEG_APP13: u8 = 0xED;
+const JPEG_DQT: u8 = 0xDB;
+const JPEG_SOS: u8 = 0xDA;
+const JPEG_EOI: u8 = 0xD9;
+
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Colour space, EXIF, text and density chunks; all must precede IDAT, so they
+/// go right after IHDR.
+const PNG_KEPT: [&[u8; 4]; 9] = [b"iCCP", b"sRGB", b"gAMA", b"cHRM", b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"pHYs"];
+
+/// WebP metadata chunks, in the order the container wants them around the image
+/// data: ICCP before it, EXIF and XMP after.
+const WEBP_KEPT: [&[u8; 4]; 3] = [b"ICCP", b"EXIF", b"XMP "];
+const VP8X_ICC: u8 = 0x20;
+const VP8X_ALPHA: u8 = 0x10;
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+
+const TIFF_LE: &[u8] = b"II*\0";
+const TIFF_BE: &[u8] = b"MM\0*";
+/// XResolution, YResolution, ResolutionUnit, XMP, EXIF IFD, ICC profile, GPS IFD.
+const TIFF_KEPT: [u16; 7] = [282, 283, 296, 700, 34665, 34675, 34853];
+/// Tags pointing at an IFD of their own: EXIF, GPS and, inside EXIF, Interop.
+const TIFF_SUB_IFDS: [u16; 3] = [34665, 34853, 40965];
+const TIFF_LONG: u16 = 4;
+
+/// libjpeg's base luminance table (JPEG spec K.1), row-major.
+const STD_LUMA: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Preserved {
+    /// Whole JPEG segments, PNG chunks or WebP chunks, headers included.
+    blocks: Vec<Vec<u8>>,
+    /// Entries of a TIFF's first IFD.
+    tiff: Vec<TiffEntry>,
+    /// Estimated quality of a JPEG source, 1..=100.
+    pub jpeg_quality: Option<u8>,
+}
+
+/// A TIFF IFD entry, with its value in little-endian order.
+#[derive(Debug, PartialEq)]
+struct TiffEntry {
+    tag: u16,
+    ty: u16,
+    count: u32,
+    value: Vec<u8>,
+    /// Entries of the IFD the tag points at, for `TIFF_SUB_IFDS`.
+    ifd: Option<Vec<TiffEntry>>,
+}
+
+impl Preserved {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.tiff.is_empty()
+    }
+
+    /// EXIF orientation, 1 (as stored) to 8; 1 when there is no tag.
+    pub fn orientation(&self) -> u8 {
+        self.orientation_tag()
+            .and_then(|(block, at, le)| read_u16(&self.blocks[block], at, le))
+            .filter(|v| (1..=8).contains(v))
+            .map_or(1, |v| v as u8)
+    }
+
+    /// Set the orientation tag to 1, for pixels that were turned upright.
+    pub fn reset_orientation(&mut self) {
+        let Some((block, at, le)) = self.orientation_tag() else { return };
+        let b = &mut self.blocks[block];
+        b[at..at + 2].copy_from_slice(&if le { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() });
+        if &b[4..8] == b"eXIf" {
+            let end = b.len() - 4;
+            let crc = crc32fast::hash(&b[4..end]);
+            b[end..].copy_from_slice(&crc.to_be_bytes());
+        }
+    }
+
+    /// (block, offset of the value in it, little endian) of the orientation entry
+    /// in IFD0 of the EXIF block.
+    fn orientation_tag(&self) -> Option<(usize, usize, bool)> {
+        self.blocks.iter().enumerate().find_map(|(i, b)| {
+            let start = if b[1] == JPEG_APP1 && b.get(4..10) == Some(b"Exif\0\0") {
+                10
+            } else if b.get(4..8) == Some(b"eXIf") {
+                8
+            } else if b.starts_with(b"EXIF") {
+                // Some writers keep the JPEG-style identifier in front
+                if b.get(8..14) == Some(b"Exif\0\0") { 14 } else { 8 }
+            } else {
+                return None;
+            };
+            let le = match b.get(start..start + 4)? {
+                b"II*\0" => true,
+                b"MM\0*" => false,
+                _ => return None,
+            };
+            let ifd = start + read_u32(b, start + 4, le)? as usize;
+            let count = read_u16(b, ifd, le)? as usize;
+            (0..count).map(|e| ifd + 2 + 12 * e)
+                .find(|&entry| read_u16(b, entry, le) == Some(EXIF_ORIENTATION))
+                .filter(|&entry| entry + 10 <= b.len())
+                .map(|entry| (i, entry + 8, le))
+        })
+    }
+}
+
+fn read_u16(data: &[u8], at: usize, le: bool) -> Option<u16> {
+    let b: [u8; 2] = data.get(at..at + 2)?.try_into().ok()?;
+    Some(if le { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+}
+
+fn read_u32(data: &[u8], at: usize, le: bool) -> Option<u32> {
+    let b: [u8; 4] = data.get(at..at + 4)?.try_into().ok()?;
+    Some(if le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+}
+
+/// Stored pixels turned the way a viewer shows them for EXIF `orientation`.
+pub fn to_display<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>, orientation: u8) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    match orientation {
+        2 => flip_horizontal(&img),
+        3 => rotate180(&img),
+        4 => flip_vertical(&img),
+        5 => flip_horizontal(&rotate90(&img)),
+        6 => rotate90(&img),
+        7 => flip_horizontal(&rotate270(&img)),
+        8 => rotate270(&img),
+        _ => img,
+    }
+}
+
+/// Inverse of [`to_display`].
+pub fn from_display<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>, orientation: u8) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    match orientation {
+        5 => rotate270(&flip_horizontal(&img)),
+        6 => rotate270(&img),
+        7 => rotate90(&flip_horizontal(&img)),
+        8 => rotate90(&img),
+        o => to_display(img, o),
+    }
+}
+
+/// Metadata worth keeping from an encoded JPEG, PNG, WebP or TIFF; empty for
+/// anything else or for files too damaged to walk.
+pub fn read(data: &[u8]) -> Preserved {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        let Ok(segments) = jpeg_segments(data) else { return Preserved::default() };
+        let mut tables = Vec::new();
+        let blocks = segments.into_iter().filter_map(|(offset, len, marker)| {
+            let body = &data[offset + 4..offset + len];
+            match marker {
+                JPEG_DQT => { tables.push(body); None }
+                JPEG_APP1 | JPEG_APP2 | JPEG_APP13 => Some(data[offset..offset + len].to_vec()),
+                _ => None,
+            }
+        }).collect();
+        let jpeg_quality = tables.into_iter().find_map(luma_table).map(estimate_quality);
+        Preserved { blocks, jpeg_quality, ..Default::default() }
+    } else if data.starts_with(PNG_SIGNATURE) {
+        let Ok(chunks) = png_chunks(data) else { return Preserved::default() };
+        let blocks = chunks.into_iter()
+            .filter(|(_, _, ty)| PNG_KEPT.contains(&ty))
+            .map(|(offset, len, _)| data[offset..offset + len].to_vec())
+            .collect();
+        Preserved { blocks, ..Default::default() }
+    } else if is_webp(data) {
+        let Ok(chunks) = riff_chunks(data) else { return Preserved::default() };
+        let blocks = chunks.into_iter()
+            .filter(|(_, _, ty)| WEBP_KEPT.contains(&ty))
+            .map(|(offset, len, _)| data[offset..offset + len].to_vec())
+            .collect();
+        Preserved { blocks, ..Default::default() }
+    } else if data.starts_with(TIFF_LE) || data.starts_with(TIFF_BE) {
+        let le = data.starts_with(TIFF_LE);
+        let entries = read_u32(data, 4, le).and_then(|ifd| tiff_ifd(data, ifd as usize, le, 0)).unwrap_or_default();
+        Preserved { tiff: entries.into_iter().filter(|e| TIFF_KEPT.contains(&e.tag)).collect(), ..Default::default() }
+    } else {
+        Preserved::default()
+    }
+}
+
+pub fn is_webp(data: &[u8]) -> bool {
+    data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP")
+}
+
+/// Whether a WebP holds lossy (VP8) image data rather than lossless (VP8L).
+pub fn is_lossy_webp(r: &mut (impl Read + Seek)) -> std::io::Result<bool> {
+    r.seek(SeekFrom::Start(12))?;
+    let mut header = [0; 8];
+    while r.read_exact(&mut header).is_ok() {
+        match &header[..4] {
+            b"VP8 " => return Ok(true),
+            b"VP8L" => return Ok(false),
+            _ => {
+                let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as i64;
+                r.seek(SeekFrom::Current(size + size % 2))?;
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Put `kept` into a freshly encoded file of the same format. Blocks of a kind
+/// the encoder already wrote are not duplicated; kept TIFF tags replace the
+/// encoder's (its default resolution, say).
+pub fn restore(encoded: Vec<u8>, kept: &Preserved) -> anyhow::Result<Vec<u8>> {
+    if kept.is_empty() { return Ok(encoded); }
+    if is_webp(&encoded) { return restore_webp(&encoded, kept); }
+    if encoded.starts_with(TIFF_LE) || encoded.starts_with(TIFF_BE) { return restore_tiff(encoded, kept); }
+    let (insert_at, present): (usize, Vec<Vec<u8>>) = if encoded.starts_with(&[0xFF, 0xD8]) {
+        let segments = jpeg_segments(&encoded)?;
+        // After the encoder's JFIF APP0, which has to come first
+        let at = segments.iter().take_while(|s| s.2 == 0xE0).last().map_or(2, |s| s.0 + s.1);
+        (at, segments.iter().map(|&(o, _, m)| jpeg_kind(&encoded[o..], m)).collect())
+    } else if encoded.starts_with(PNG_SIGNATURE) {
+        let chunks = png_chunks(&encoded)?;
+        let ihdr = chunks.first().filter(|c| &c.2 == b"IHDR").ok_or_else(|| anyhow!("PNG doesn't start with IHDR"))?;
+        (ihdr.0 + ihdr.1, chunks.iter().map(|c| c.2.to_vec()).collect())
+    } else {
+        return Err(anyhow!("Can't carry metadata over into this image format"));
+    };
+
+    let mut out = Vec::with_capacity(encoded.len() + kept.blocks.iter().map(Vec::len).sum::<usize>());
+    out.extend_from_slice(&encoded[..insert_at]);
+    for block in &kept.blocks {
+        let kind = if encoded.starts_with(PNG_SIGNATURE) { block[4..8].to_vec() } else { jpeg_kind(block, block[1]) };
+        if !present.contains(&kind) { out.extend_from_slice(block); }
+    }
+    out.extend_from_slice(&encoded[insert_at..]);
+    Ok(out)
+}
+
+/// Rebuild a WebP as VP8X + ICCP + image data + EXIF + XMP, taking metadata the
+/// encoder wrote over the kept one.
+fn restore_webp(encoded: &[u8], kept: &Preserved) -> anyhow::Result<Vec<u8>> {
+    let chunks = riff_chunks(encoded)?;
+    let (width, height, alpha) = webp_canvas(encoded, &chunks)?;
+    let meta = |ty: &[u8; 4]| -> Option<&[u8]> {
+        chunks.iter().find(|c| &c.2 == ty).map(|c| &encoded[c.0..c.0 + c.1])
+            .or_else(|| kept.blocks.iter().find(|b| b.starts_with(ty)).map(Vec::as_slice))
+    };
+    let [icc, exif, xmp] = WEBP_KEPT.map(meta);
+
+    let mut flags = 0;
+    for (present, flag) in [(icc.is_some(), VP8X_ICC), (alpha, VP8X_ALPHA), (exif.is_some(), VP8X_EXIF), (xmp.is_some(), VP8X_XMP)] {
+        if present { flags |= flag; }
+    }
+    let mut body = b"WEBPVP8X".to_vec();
+    body.extend_from_slice(&10u32.to_le_bytes());
+    body.extend_from_slice(&[flags, 0, 0, 0]);
+    body.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    body.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    body.extend_from_slice(icc.unwrap_or_default());
+    for &(offset, len, ty) in &chunks {
+        if &ty != b"VP8X" && !WEBP_KEPT.contains(&&ty) { body.extend_from_slice(&encoded[offset..offset + len]); }
+    }
+    body.extend_from_slice(exif.unwrap_or_default());
+    body.extend_from_slice(xmp.unwrap_or_default());
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Canvas width, height and whether alpha is used, from the VP8X header or the
+/// bitstream of a simple WebP.
+fn webp_canvas(data: &[u8], chunks: &[(usize, usize, [u8; 4])]) -> anyhow::Result<(u32, u32, bool)> {
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    let alph = chunks.iter().any(|c| &c.2 == b"ALPH");
+    for &(offset, len, ty) in chunks {
+        let payload = &data[offset + 8..offset + len];
+        match &ty {
+            b"VP8X" if payload.len() >= 10 => {
+                return Ok((u24(&payload[4..7]) + 1, u24(&payload[7..10]) + 1, payload[0] & VP8X_ALPHA != 0));
+            }
+            b"VP8L" if payload.len() >= 5 && payload[0] == 0x2F => {
+                let bits = u32::from_le_bytes(payload[1..5].try_into().unwrap());
+                return Ok(((bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1, bits >> 28 & 1 != 0));
+            }
+            b"VP8 " if payload.len() >= 10 && payload[3..6] == [0x9D, 0x01, 0x2A] => {
+                let dim = |at: usize| u16::from_le_bytes([payload[at], payload[at + 1]]) as u32 & 0x3FFF;
+                return Ok((dim(6), dim(8), alph));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("WebP without image data"))
+}
+
+/// (offset, total length including padding, FourCC) of each chunk in a RIFF file.
+fn riff_chunks(data: &[u8]) -> anyhow::Result<Vec<(usize, usize, [u8; 4])>> {
+    // Anything after the RIFF size (a tail marker, say) is not a chunk
+    let riff_len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    let end = data.len().min(8 + riff_len);
+    let mut out = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= end {
+        let ty: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let len = 8 + size + size % 2;
+        if pos + 8 + size > end { return Err(anyhow!("Truncated WebP chunk")); }
+        out.push((pos, len.min(end - pos), ty));
+        pos += len;
+    }
+    Ok(out)
+}
+
+/// Entries of the IFD at `at`, following sub-IFD pointers two levels deep (EXIF,
+/// then Interop). Entries of unknown type or with values out of bounds are left out.
+fn tiff_ifd(data: &[u8], at: usize, le: bool, depth: u32) -> Option<Vec<TiffEntry>> {
+    let count = read_u16(data, at, le)? as usize;
+    let mut out = Vec::with_capacity(count);
+    for entry in (0..count).map(|e| at + 2 + 12 * e) {
+        let (tag, ty, n) = (read_u16(data, entry, le)?, read_u16(data, entry + 2, le)?, read_u32(data, entry + 4, le)?);
+        if TIFF_SUB_IFDS.contains(&tag) {
+            if depth < 2 {
+                let Some(ifd) = read_u32(data, entry + 8, le).and_then(|o| tiff_ifd(data, o as usize, le, depth + 1)) else { continue };
+                out.push(TiffEntry { tag, ty: TIFF_LONG, count: 1, value: Vec::new(), ifd: Some(ifd) });
+            }
+            continue;
+        }
+        let Some(size) = tiff_type_size(ty).and_then(|s| s.checked_mul(n as usize)) else { continue };
+        let at = if size <= 4 { entry + 8 } else { read_u32(data, entry + 8, le)? as usize };
+        let Some(value) = data.get(at..at.saturating_add(size)) else { continue };
+        let mut value = value.to_vec();
+        if !le { swap_tiff_value(&mut value, ty); }
+        out.push(TiffEntry { tag, ty, count: n, value, ifd: None });
+    }
+    Some(out)
+}
+
+/// Bytes per value of a TIFF field type.
+fn tiff_type_size(ty: u16) -> Option<usize> {
+    match ty {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+/// Turn a value between byte orders; rationals are two longs each.
+fn swap_tiff_value(value: &mut [u8], ty: u16) {
+    let unit = if matches!(ty, 5 | 10) { 4 } else { tiff_type_size(ty).unwrap_or(1) };
+    for v in value.chunks_mut(unit) { v.reverse(); }
+}
+
+/// Write a new first IFD holding the encoder's entries and the kept ones, after
+/// the image data, and point the header at it. The encoder's IFD stays in the
+/// file unreferenced.
+fn restore_tiff(mut out: Vec<u8>, kept: &Preserved) -> anyhow::Result<Vec<u8>> {
+    let le = out.starts_with(TIFF_LE);
+    let first = read_u32(&out, 4, le).ok_or_else(|| anyhow!("Truncated TIFF header"))? as usize;
+    let count = read_u16(&out, first, le).ok_or_else(|| anyhow!("Truncated TIFF IFD"))? as usize;
+    let next = read_u32(&out, first + 2 + 12 * count, le).ok_or_else(|| anyhow!("Truncated TIFF IFD"))?;
+    let mut entries: Vec<[u8; 12]> = (0..count).map(|e| <[u8; 12]>::try_from(&out[first + 2 + 12 * e..][..12]).unwrap())
+        .filter(|e| !kept.tiff.iter().any(|k| Some(k.tag) == read_u16(e, 0, le)))
+        .collect();
+    for e in &kept.tiff {
+        entries.push(place_tiff_entry(&mut out, e, le)?);
+    }
+    let at = append_tiff_ifd(&mut out, entries, next, le)?;
+    out[4..8].copy_from_slice(&if le { at.to_le_bytes() } else { at.to_be_bytes() });
+    Ok(out)
+}
+
+/// The 12 bytes of `e` in an IFD of `out`, its value (or sub-IFD) appended to
+/// `out` when it doesn't fit in the entry.
+fn place_tiff_entry(out: &mut Vec<u8>, e: &TiffEntry, le: bool) -> anyhow::Result<[u8; 12]> {
+    let field = match &e.ifd {
+        Some(ifd) => {
+            let entries = ifd.iter().map(|sub| place_tiff_entry(out, sub, le)).collect::<anyhow::Result<_>>()?;
+            let at = append_tiff_ifd(out, entries, 0, le)?;
+            if le { at.to_le_bytes() } else { at.to_be_bytes() }
+        }
+        None => {
+            let mut value = e.value.clone();
+            if !le { swap_tiff_value(&mut value, e.ty); }
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                value.try_into().unwrap()
+            } else {
+                let at = tiff_offset(out)?;
+                out.extend_from_slice(&value);
+                if le { at.to_le_bytes() } else { at.to_be_bytes() }
+            }
+        }
+    };
+    let mut entry = [0; 12];
+    let (tag, ty, count) = if le {
+        (e.tag.to_le_bytes(), e.ty.to_le_bytes(), e.count.to_le_bytes())
+    } else {
+        (e.tag.to_be_bytes(), e.ty.to_be_bytes(), e.count.to_be_bytes())
+    };
+    entry[..2].copy_from_slice(&tag);
+    entry[2..4].copy_from_slice(&ty);
+    entry[4..8].copy_from_slice(&count);
+    entry[8..].copy_from_slice(&field);
+    Ok(entry)
+}
+
+/// Append an IFD with `entries` (sorted by tag, as TIFF wants) and return its offset.
+fn append_tiff_ifd(out: &mut Vec<u8>, mut entries: Vec<[u8; 12]>, next: u32, le: bool) -> anyhow::Result<u32> {
+    entries.sort_by_key(|e| read_u16(e, 0, le));
+    let at = tiff_offset(out)?;
+    let count = entries.len() as u16;
+    out.extend_from_slice(&if le { count.to_le_bytes() } else { count.to_be_bytes() });
+    for e in &entries { out.extend_from_slice(e); }
+    out.extend_from_slice(&if le { next.to_le_bytes() } else { next.to_be_bytes() });
+    Ok(at)
+}
+
+/// Offset of the next value appended to `out`, padded to a word boundary.
+fn tiff_offset(out: &mut Vec<u8>) -> anyhow::Result<u32> {
+    if out.len() % 2 == 1 { out.push(0); }
+    u32::try_from(out.len()).map_err(|_| anyhow!("TIFF too large to add metadata to"))
+}
+
+/// Marker plus the identifier string of an APPn segment ("Exif", "ICC_PROFILE"...),
+/// so an encoder-written EXIF block is recognised but XMP next to it is not.
+fn jpeg_kind(segment: &[u8], marker: u8) -> Vec<u8> {
+    let body = segment.get(4..).unwrap_or_default();
+    let id = body.iter().position(|&b| b == 0).map_or(&[][..], |end| &body[..end.min(32)]);
+    [&[marker][..], id].concat()
+}
+
+/// (offset, total length, marker) of each segment between SOI and SOS.
+fn jpeg_segments(data: &[u8]) -> anyhow::Result<Vec<(usize, usize, u8)>> {
+    let mut out = Vec::new();
+    let mut pos = 2;
+    loop {
+        if pos + 4 > data.len() || data[pos] != 0xFF { return Err(anyhow!("Malformed JPEG segment at {}", pos)); }
+        let marker = data[pos + 1];
+        if marker == JPEG_SOS || marker == JPEG_EOI { break; }
+        if marker == 0xFF { pos += 1; continue; }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if len < 2 || pos + 2 + len > data.len() { return Err(anyhow!("Truncated JPEG segment at {}", pos)); }
+        out.push((pos, 2 + len, marker));
+        pos += 2 + len;
+    }
+    Ok(out)
+}
+
+/// (offset, total length, type) of each chunk.
+fn png_chunks(data: &[u8]) -> anyhow::Result<Vec<(usize, usize, [u8; 4])>> {
+    let mut out = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let ty: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        if pos + 12 + len > data.len() { return Err(anyhow!("Truncated PNG chunk")); }
+        out.push((pos, 12 + len, ty));
+        pos += 12 + len;
+        if &ty == b"IEND" { break; }
+    }
+    Ok(out)
+}
+
+/// Table 0 (luminance) from a DQT segment body, in zigzag order as stored.
+fn luma_table(mut body: &[u8]) -> Option<[u16; 64]> {
+    while let Some(&pq_tq) = body.first() {
+        let wide = pq_tq >> 4 != 0;
+        let size = if wide { 128 } else { 64 };
+        let values = body.get(1..1 + size)?;
+        if pq_tq & 0x0F == 0 {
+            return Some(std::array::from_fn(|i| {
+                if wide { u16::from_be_bytes([values[2 * i], values[2 * i + 1]]) } else { values[i] as u16 }
+            }));
+        }
+        body = &body[1 + size..];
+    }
+    None
+}
+
+/// Row-major index of each zigzag position.
+fn zigzag() -> [usize; 64] {
+    let mut order = [0; 64];
+    let mut k = 0;
+    for s in 0..15usize {
+        let rows: Vec<usize> = (s.saturating_sub(7)..=s.min(7)).collect();
+        // Even diagonals run up and to the right, odd ones down and to the left
+        let rows: Vec<usize> = if s.is_multiple_of(2) { rows.into_iter().rev().collect() } else { rows };
+        for row in rows {
+            order[k] = row * 8 + (s - row);
+            k += 1;
+        }
+    }
+    order
+}
+
+fn estimate_quality(table: [u16; 64]) -> u8 {
+    let order = zigzag();
+    (1..=100u32).min_by_key(|&q| {
+        let scale = if q < 50 { 5000 / q } else { 200 - 2 * q };
+        order.iter().zip(table).map(|(&i, actual)| {
+            let expected = ((STD_LUMA[i] as u32 * scale + 50) / 100).clamp(1, 255);
+            expected.abs_diff(actual as u32)
+        }).sum::<u32>()
+    }).unwrap() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage, Rgba, RgbaImage};
+
+    fn encode_jpeg(quality: u8) -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 128]));
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut std::io::Cursor::new(&mut encoded), ImageOutputFormat::Jpeg(quality)).unwrap();
+        encoded
+    }
+
+    /// A JPEG with an EXIF segment (orientation 6) and an ICC profile segment
+    /// after SOI, and the two segments.
+    fn jpeg_with_metadata() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let segment = |marker: u8, body: &[u8]| [&[0xFF, marker][..], &((body.len() + 2) as u16).to_be_bytes(), body].concat();
+        // Big-endian TIFF header with one IFD entry: orientation = 6
+        let exif = segment(0xE1, b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0");
+        let icc = segment(0xE2, &[&b"ICC_PROFILE\0\x01\x01"[..], &[0x5A; 300]].concat());
+        let encoded = encode_jpeg(90);
+        ([&encoded[..2], &exif, &icc, &encoded[2..]].concat(), exif, icc)
+    }
+
+    fn count(data: &[u8], block: &[u8]) -> usize {
+        data.windows(block.len()).filter(|w| *w == block).count()
+    }
+
+    #[test]
+    fn jpeg_quality_is_estimated_from_the_luminance_table() {
+        for quality in [50, 75, 93] {
+            assert_eq!(read(&encode_jpeg(quality)).jpeg_quality, Some(quality));
+        }
+        assert_eq!(read(b"not an image"), Preserved::default());
+    }
+
+    #[test]
+    fn jpeg_segments_go_back_into_a_fresh_encode_once() {
+        let (original, exif, icc) = jpeg_with_metadata();
+        let kept = read(&original);
+        assert!(!kept.is_empty());
+        let restored = restore(encode_jpeg(80), &kept).unwrap();
+        assert_eq!((count(&restored, &exif), count(&restored, &icc)), (1, 1));
+        image::load_from_memory(&restored).unwrap();
+        // An encoder that already wrote EXIF keeps its own
+        let again = restore(restored.clone(), &kept).unwrap();
+        assert_eq!((count(&again, &exif), count(&again, &icc)), (1, 1));
+    }
+
+    #[test]
+    fn orientation_is_read_reset_and_undone() {
+        let mut kept = read(&jpeg_with_metadata().0);
+        assert_eq!(kept.orientation(), 6);
+        kept.reset_orientation();
+        assert_eq!(kept.orientation(), 1);
+        assert_eq!(read(&encode_jpeg(90)).orientation(), 1, "no tag");
+
+        let stored = RgbaImage::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        assert_eq!(to_display(stored.clone(), 6).dimensions(), (2, 3));
+        for o in 1..=8 {
+            assert_eq!(from_display(to_display(stored.clone(), o), o), stored, "orientation {}", o);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Ed25519 signatures over tail markers, so a marker found in a leaked file can be
+// shown to have been written by us for that exact file content.
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::keyed;
+
+const SIGNING_KEY_FILE: &str = "signing.key";
+/// Prefix of every signed message, so a signature can't be replayed in another context.
+const DOMAIN: &[u8] = b"endecode-watermark-v1";
+
+/// Local private key, created on first use.
+pub fn signing_key() -> anyhow::Result<SigningKey> {
+    let path = keyed::config_key_path(SIGNING_KEY_FILE)?;
+    let seed = keyed::read_or_create_key(&path)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Local private key, without creating one: checking a signature against a key
+/// made up on the spot could only ever fail.
+pub fn load_signing_key() -> anyhow::Result<SigningKey> {
+    let path = keyed::config_key_path(SIGNING_KEY_FILE)?;
+    if !path.exists() {
+        return Err(anyhow!("No signing key found at {}, give the signer's public key instead", path.display()));
+    }
+    Ok(SigningKey::from_bytes(&keyed::read_key(&path)?))
+}
+
+pub fn public_key_base64() -> anyhow::Result<String> {
+    Ok(STANDARD.encode(signing_key()?.verifying_key().as_bytes()))
+}
+
+pub fn parse_public_key(b64: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes: [u8; 32] = STANDARD.decode(b64.trim()).map_err(|e| anyhow!("Invalid public key: {}", e))?
+        .try_into().map_err(|_| anyhow!("Invalid public key: must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| anyhow!("Invalid public key: {}", e))
+}
+
+/// SHA-256 of the first `len` bytes of the file, streamed.
+pub fn hash_file_prefix(path: &Path, len: u64) -> anyhow::Result<[u8; 32]> {
+    hash_file_prefix_patched(path, len, 0, &[])
+}
+
+/// Like [`hash_file_prefix`], but hashing `patch` in place of the bytes at `patch_offset`.
+pub fn hash_file_prefix_patched(path: &Path, len: u64, patch_offset: u64, patch: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha256::new();
+    let mut pos = 0u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    while pos < len {
+        let want = (len - pos).min(buf.len() as u64) as usize;
+        let n = f.read(&mut buf[..want])?;
+        if n == 0 { return Err(anyhow!("File {} shorter than expected", path.display())); }
+        for (i, b) in patch.iter().enumerate() {
+            let at = patch_offset + i as u64;
+            if at >= pos && at < pos + n as u64 { buf[(at - pos) as usize] = *b; }
+        }
+        hasher.update(&buf[..n]);
+        pos += n as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn message(scheme: u8, text: &[u8], content_hash: &[u8]) -> Vec<u8> {
+    let mut m = Vec::with_capacity(DOMAIN.len() + 1 + text.len() + content_hash.len());
+    m.extend_from_slice(DOMAIN);
+    m.push(scheme);
+    m.extend_from_slice(text);
+    m.extend_from_slice(content_hash);
+    m
+}
+
+pub fn sign(key: &SigningKey, scheme: u8, text: &[u8], content_hash: &[u8]) -> [u8; 64] {
+    key.sign(&message(scheme, text, content_hash)).to_bytes()
+}
+
+pub fn verify(key: &VerifyingKey, scheme: u8, text: &[u8], content_hash: &[u8], signature: &[u8]) -> bool {
+    let Ok(sig) = Signature::from_slice(signature) else { return false };
+    key.verify(&message(scheme, text, content_hash), &sig).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_cover_scheme_text_and_content() {
+        let key = SigningKey::from_bytes(&[9; 32]);
+        let public = key.verifying_key();
+        let signature = sign(&key, 1, b"sealed", &[0xAB; 32]);
+        assert!(verify(&public, 1, b"sealed", &[0xAB; 32], &signature));
+
+        assert!(!verify(&public, 0, b"sealed", &[0xAB; 32], &signature), "other scheme");
+        assert!(!verify(&public, 1, b"sealeD", &[0xAB; 32], &signature), "other text");
+        assert!(!verify(&public, 1, b"sealed", &[0xAC; 32], &signature), "other content");
+        assert!(!verify(&SigningKey::from_bytes(&[10; 32]).verifying_key(), 1, b"sealed", &[0xAB; 32], &signature));
+        assert!(!verify(&public, 1, b"sealed", &[0xAB; 32], &signature[..63]), "malformed signature");
+    }
+
+    #[test]
+    fn public_keys_parse_back_from_base64() {
+        let public = SigningKey::from_bytes(&[9; 32]).verifying_key();
+        assert_eq!(parse_public_key(&format!(" {}\n", STANDARD.encode(public.as_bytes()))).unwrap(), public);
+        assert!(parse_public_key(&STANDARD.encode([1; 16])).unwrap_err().to_string().contains("32 bytes"));
+        assert!(parse_public_key("not base64!").is_err());
+    }
+
+    #[test]
+    fn file_prefixes_hash_with_or_without_a_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let sha = |data: &[u8]| -> [u8; 32] { Sha256::digest(data).into() };
+
+        assert_eq!(hash_file_prefix(&path, 150_000).unwrap(), sha(&content[..150_000]));
+        // A patch across the 64 KiB read boundary
+        let mut patched = content.clone();
+        patched[65_530..65_540].copy_from_slice(&[0xEE; 10]);
+        assert_eq!(hash_file_prefix_patched(&path, 100_000, 65_530, &[0xEE; 10]).unwrap(), sha(&patched[..100_000]));
+        assert!(hash_file_prefix(&path, 200_001).unwrap_err().to_string().contains("shorter than expected"));
+    }
+}
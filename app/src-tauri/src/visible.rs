@@ -0,0 +1,639 @@
+// Visible text watermark. The label is rendered into a layer of its own (fill,
+// optional outline and drop shadow), rotated, and then alpha-blended onto the
+// picture, so colour, opacity and angle don't depend on what imageproc's text
+// drawing can do.
+//
+// Layers hold premultiplied RGBA in 0..1, which keeps the bilinear sampling used
+// for rotation free of dark fringes around the glyphs.
+//
+// Logo overlays go through the same layer and placement code; the logo is
+// premultiplied before it is scaled so its transparent edges stay clean.
+//
+// Auto colour picks white or black, whichever contrasts more (WCAG contrast
+// ratio) with the pixels the label actually covers, and adds an outline in the
+// other tone when even the better one stays under the required ratio.
+//
+// Pictures are blended in the depth they were decoded at (8 or 16-bit, or float),
+// so editing a 16-bit or HDR file doesn't round it to 8 bits per channel.
+//
+// In tiled mode the same rotated label is stamped on a lattice whose rows run
+// along the text direction, every other row shifted by half a step, so there is
+// no label-free strip left to crop to.
+use anyhow::anyhow;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba, Rgba32FImage, RgbaImage};
+use rusttype::{point, Font, Scale};
+
+/// Gap between the label and the image edge before offsets are applied.
+const PADDING: i32 = 5;
+/// Smallest font size a relative size is rounded up to.
+const MIN_FONT_PX: f32 = 10.0;
+
+/// Channel type a picture can be edited in.
+pub trait Channel: image::Primitive + 'static {
+    /// The value scaled to 0..1.
+    fn to_unit(self) -> f32;
+    fn from_unit(v: f32) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_unit(self) -> f32 { self as f32 / 255.0 }
+    fn from_unit(v: f32) -> Self { (v * 255.0).round().clamp(0.0, 255.0) as u8 }
+}
+
+impl Channel for u16 {
+    fn to_unit(self) -> f32 { self as f32 / 65535.0 }
+    fn from_unit(v: f32) -> Self { (v * 65535.0).round().clamp(0.0, 65535.0) as u16 }
+}
+
+impl Channel for f32 {
+    fn to_unit(self) -> f32 { self }
+    fn from_unit(v: f32) -> Self { v }
+}
+
+/// A picture being edited, in the depth of the file it was decoded from.
+pub enum Canvas {
+    Rgba8(RgbaImage),
+    Rgba16(ImageBuffer<Rgba<u16>, Vec<u16>>),
+    Rgba32F(Rgba32FImage),
+}
+
+impl Canvas {
+    /// `img` in its own depth: 16-bit images at 16 bits, float ones in float,
+    /// everything else at 8 bits.
+    pub fn new(img: DynamicImage) -> Canvas {
+        let color = img.color();
+        match color.bytes_per_pixel() / color.channel_count() {
+            2 => Canvas::Rgba16(img.into_rgba16()),
+            4 => Canvas::Rgba32F(img.into_rgba32f()),
+            _ => Canvas::Rgba8(img.into_rgba8()),
+        }
+    }
+
+    pub fn into_image(self) -> DynamicImage {
+        match self {
+            Canvas::Rgba8(img) => DynamicImage::ImageRgba8(img),
+            Canvas::Rgba16(img) => DynamicImage::ImageRgba16(img),
+            Canvas::Rgba32F(img) => DynamicImage::ImageRgba32F(img),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextPosition {
+    TopLeft,
+    TopRight,
+    Center,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// Horizontal alignment of the lines of a multi-line label.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FontSize {
+    /// Line height in pixels.
+    Px(f32),
+    /// Share of the image width, never below `MIN_FONT_PX`.
+    Relative(f32),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Outline {
+    pub color: [u8; 4],
+    /// Pixels around each glyph.
+    pub width: u32,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Outline { color: [0, 0, 0, 255], width: 2 }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Shadow {
+    pub color: [u8; 4],
+    pub dx: i32,
+    pub dy: i32,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Shadow { color: [0, 0, 0, 160], dx: 2, dy: 2 }
+    }
+}
+
+/// Repeat the label over the whole image instead of placing it once.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Tile {
+    /// Degrees, counter-clockwise; used for both the labels and the rows.
+    pub angle: f32,
+    /// Pixels between labels in a row; unset means twice the line height.
+    pub spacing: Option<u32>,
+    /// Pixels between rows; unset means three times the line height.
+    pub row_spacing: Option<u32>,
+}
+
+impl Default for Tile {
+    fn default() -> Self {
+        Tile { angle: 30.0, spacing: None, row_spacing: None }
+    }
+}
+
+/// How a visible label looks. Every field has a default, the defaults give the
+/// original 50% white label at 2% of the image width.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct WatermarkStyle {
+    /// Text colour, RGBA.
+    pub color: [u8; 4],
+    /// Applied to the whole label (fill, outline and shadow), 0..=1.
+    pub opacity: f32,
+    pub size: FontSize,
+    /// Degrees, counter-clockwise.
+    pub rotation: f32,
+    pub outline: Option<Outline>,
+    pub shadow: Option<Shadow>,
+    /// Pixels added to the anchored position; positive moves right / down.
+    /// Tiled labels use them to shift the whole pattern.
+    pub offset_x: i32,
+    pub offset_y: i32,
+    /// Tile the label across the image; the position and `rotation` are then unused.
+    pub tile: Option<Tile>,
+    /// Choose light or dark text from the pixels under the label; the RGB part
+    /// of `color` is then unused, its alpha still applies.
+    pub auto_color: bool,
+    /// Contrast ratio auto colour must reach, 1 to 21 (4.5 is WCAG AA for text).
+    pub min_contrast: f32,
+    /// For text with several lines; unset follows the anchor (left in the left
+    /// corners, right in the right ones, centred otherwise).
+    pub align: Option<TextAlign>,
+}
+
+impl Default for WatermarkStyle {
+    fn default() -> Self {
+        WatermarkStyle {
+            color: [255, 255, 255, 255],
+            opacity: 0.5,
+            size: FontSize::Relative(0.02),
+            rotation: 0.0,
+            outline: None,
+            shadow: None,
+            offset_x: 0,
+            offset_y: 0,
+            tile: None,
+            auto_color: false,
+            min_contrast: 4.5,
+            align: None,
+        }
+    }
+}
+
+impl WatermarkStyle {
+    fn check(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(anyhow!("Watermark opacity must be between 0 and 1, got {}", self.opacity));
+        }
+        let size = match self.size { FontSize::Px(v) | FontSize::Relative(v) => v };
+        if !size.is_finite() || size <= 0.0 {
+            return Err(anyhow!("Watermark font size must be positive, got {}", size));
+        }
+        if !self.rotation.is_finite() || self.tile.is_some_and(|t| !t.angle.is_finite()) {
+            return Err(anyhow!("Watermark rotation must be a number of degrees"));
+        }
+        if !(1.0..=21.0).contains(&self.min_contrast) {
+            return Err(anyhow!("Watermark contrast ratio must be between 1 and 21, got {}", self.min_contrast));
+        }
+        Ok(())
+    }
+
+    fn font_px(&self, image_width: u32) -> f32 {
+        match self.size {
+            FontSize::Px(px) => px,
+            FontSize::Relative(share) => (image_width as f32 * share).max(MIN_FONT_PX),
+        }
+    }
+}
+
+/// How a logo overlay is placed.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct OverlayStyle {
+    /// Logo width as a share of the image width; unset keeps the logo's own size.
+    pub scale: Option<f32>,
+    /// 0..=1, multiplies the logo's own alpha.
+    pub opacity: f32,
+    /// Pixels added to the anchored position; positive moves right / down.
+    pub offset_x: i32,
+    pub offset_y: i32,
+}
+
+impl Default for OverlayStyle {
+    fn default() -> Self {
+        OverlayStyle { scale: None, opacity: 0.5, offset_x: 0, offset_y: 0 }
+    }
+}
+
+/// Premultiplied RGBA pixels, 0..1.
+struct Layer {
+    width: usize,
+    height: usize,
+    px: Vec<[f32; 4]>,
+}
+
+impl Layer {
+    fn new(width: usize, height: usize) -> Self {
+        Layer { width, height, px: vec![[0.0; 4]; width * height] }
+    }
+
+    /// Paint `color` through `mask` (shifted by `dx`, `dy`) under what is already there.
+    fn paint_under(&mut self, mask: &Mask, dx: i32, dy: i32, color: [u8; 4]) {
+        let a = color[3] as f32 / 255.0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let cov = mask.at(x as i32 - dx, y as i32 - dy) * a;
+                if cov == 0.0 { continue; }
+                let p = &mut self.px[y * self.width + x];
+                let keep = 1.0 - p[3];
+                for c in 0..3 { p[c] += color[c] as f32 / 255.0 * cov * keep; }
+                p[3] += cov * keep;
+            }
+        }
+    }
+
+    /// From an image whose colour channels are already multiplied by alpha.
+    fn from_premultiplied(img: &Rgba32FImage) -> Self {
+        Layer { width: img.width() as usize, height: img.height() as usize, px: img.pixels().map(|p| p.0).collect() }
+    }
+
+    fn sample(&self, x: f32, y: f32) -> [f32; 4] {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let mut out = [0.0; 4];
+        for (ox, oy, w) in [(0, 0, (1.0 - fx) * (1.0 - fy)), (1, 0, fx * (1.0 - fy)), (0, 1, (1.0 - fx) * fy), (1, 1, fx * fy)] {
+            let (sx, sy) = (x0 + ox, y0 + oy);
+            if sx < 0 || sy < 0 || sx >= self.width as i64 || sy >= self.height as i64 { continue; }
+            let p = self.px[sy as usize * self.width + sx as usize];
+            for c in 0..4 { out[c] += p[c] * w; }
+        }
+        out
+    }
+
+    /// The layer turned `degrees` counter-clockwise about its centre, on a canvas
+    /// just large enough to hold it.
+    fn rotated(self, degrees: f32) -> Layer {
+        if degrees % 360.0 == 0.0 { return self; }
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (w, h) = (self.width as f32, self.height as f32);
+        let width = (w * cos.abs() + h * sin.abs()).ceil() as usize;
+        let height = (w * sin.abs() + h * cos.abs()).ceil() as usize;
+        let mut out = Layer::new(width, height);
+        let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+        for y in 0..height {
+            for x in 0..width {
+                // Inverse mapping; y grows downwards, so counter-clockwise on screen
+                // takes (1, 0) to (cos, -sin)
+                let (rx, ry) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                let sx = rx * cos - ry * sin + w / 2.0 - 0.5;
+                let sy = rx * sin + ry * cos + h / 2.0 - 0.5;
+                out.px[y * width + x] = self.sample(sx, sy);
+            }
+        }
+        out
+    }
+}
+
+/// Glyph coverage, 0..1.
+struct Mask {
+    width: usize,
+    height: usize,
+    v: Vec<f32>,
+}
+
+impl Mask {
+    fn at(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height { return 0.0; }
+        self.v[y as usize * self.width + x as usize]
+    }
+
+    /// Coverage grown by `radius` pixels in every direction, for outlines.
+    fn dilated(&self, radius: u32) -> Mask {
+        let r = radius as i32;
+        let disk: Vec<(i32, i32)> = (-r..=r)
+            .flat_map(|dy| (-r..=r).map(move |dx| (dx, dy)))
+            .filter(|&(dx, dy)| dx * dx + dy * dy <= r * r + r)
+            .collect();
+        let mut v = vec![0.0; self.v.len()];
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                v[y as usize * self.width + x as usize] = disk.iter()
+                    .map(|&(dx, dy)| self.at(x + dx, y + dy))
+                    .fold(0.0, f32::max);
+            }
+        }
+        Mask { width: self.width, height: self.height, v }
+    }
+}
+
+/// Coverage of `text`, one line per `\n`, with `margin` empty pixels on every
+/// side. Lines are spaced by the font's line height; the box spans the first
+/// line's ascent to the last one's descent and the widest line's ink.
+fn text_mask(font: &Font, text: &str, px: f32, margin: usize, align: TextAlign) -> anyhow::Result<Mask> {
+    let scale = Scale::uniform(px);
+    let v_metrics = font.v_metrics(scale);
+    let line_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
+    let lines: Vec<Vec<_>> = text.lines().enumerate()
+        .map(|(i, line)| font.layout(line, scale, point(0.0, v_metrics.ascent + i as f32 * line_height)).collect())
+        .collect();
+    // Ink extent of each line, None for blank ones
+    let extents: Vec<Option<(i32, i32)>> = lines.iter().map(|glyphs| {
+        let boxes: Vec<_> = glyphs.iter().filter_map(|g| g.pixel_bounding_box()).collect();
+        Some((boxes.iter().map(|b| b.min.x).min()?.min(0), boxes.iter().map(|b| b.max.x).max()?))
+    }).collect();
+    let Some(ink_width) = extents.iter().flatten().map(|(l, r)| r - l).max() else {
+        return Err(anyhow!("Font has no visible glyphs for \"{}\"", text));
+    };
+
+    let width = ink_width as usize + 2 * margin;
+    let height = (v_metrics.ascent - v_metrics.descent + (lines.len() - 1) as f32 * line_height).ceil() as usize + 2 * margin;
+    let mut v = vec![0.0f32; width * height];
+    for (glyphs, extent) in lines.iter().zip(&extents) {
+        let Some((left, right)) = *extent else { continue };
+        let shift = margin as i32 + match align {
+            TextAlign::Left => -left,
+            TextAlign::Center => (ink_width - (right - left)) / 2 - left,
+            TextAlign::Right => ink_width - right,
+        };
+        for g in glyphs {
+            let Some(bb) = g.pixel_bounding_box() else { continue };
+            g.draw(|gx, gy, cov| {
+                let x = bb.min.x + gx as i32 + shift;
+                let y = bb.min.y + gy as i32 + margin as i32;
+                if x < 0 || y < 0 || x as usize >= width || y as usize >= height { return; }
+                let cell = &mut v[y as usize * width + x as usize];
+                *cell = cell.max(cov);
+            });
+        }
+    }
+    Ok(Mask { width, height, v })
+}
+
+/// The finished label, not yet rotated.
+fn render_label(font: &Font, text: &str, style: &WatermarkStyle, px: f32) -> anyhow::Result<Layer> {
+    let outline = style.outline.map_or(0, |o| o.width);
+    let shadow = style.shadow.map_or(0, |s| s.dx.unsigned_abs().max(s.dy.unsigned_abs()));
+    let margin = (outline + shadow) as usize;
+    let mask = text_mask(font, text, px, margin, style.align.unwrap_or(TextAlign::Left))?;
+
+    let mut layer = Layer::new(mask.width, mask.height);
+    layer.paint_under(&mask, 0, 0, style.color);
+    let grown = style.outline.map(|o| (mask.dilated(o.width), o.color));
+    if let Some((grown, color)) = &grown {
+        layer.paint_under(grown, 0, 0, *color);
+    }
+    if let Some(s) = style.shadow {
+        layer.paint_under(grown.as_ref().map_or(&mask, |(g, _)| g), s.dx, s.dy, s.color);
+    }
+    Ok(layer)
+}
+
+/// Blend `layer` onto `img` with its top-left corner at (`left`, `top`).
+fn blend<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, layer: &Layer, left: i32, top: i32, opacity: f32)
+where
+    P::Subpixel: Channel,
+{
+    for y in 0..layer.height {
+        for x in 0..layer.width {
+            let (ix, iy) = (left + x as i32, top + y as i32);
+            if ix < 0 || iy < 0 || ix as u32 >= img.width() || iy as u32 >= img.height() { continue; }
+            let s = layer.px[y * layer.width + x].map(|c| c * opacity);
+            if s[3] <= 0.0 { continue; }
+            let d = img.get_pixel_mut(ix as u32, iy as u32);
+            let d = d.channels_mut();
+            let da = d[3].to_unit();
+            let out_a = s[3] + da * (1.0 - s[3]);
+            for c in 0..3 {
+                d[c] = Channel::from_unit((s[c] + d[c].to_unit() * da * (1.0 - s[3])) / out_a);
+            }
+            d[3] = Channel::from_unit(out_a);
+        }
+    }
+}
+
+/// Draw `text` on `img` at `position`, or tiled over all of it when the style
+/// says so. Fails if the style is invalid, the font can't show the text, or a
+/// single label doesn't fit inside the image.
+pub fn draw(img: &mut Canvas, text: &str, position: TextPosition, style: &WatermarkStyle, font: &Font) -> anyhow::Result<()> {
+    match img {
+        Canvas::Rgba8(img) => draw_on(img, text, position, style, font),
+        Canvas::Rgba16(img) => draw_on(img, text, position, style, font),
+        Canvas::Rgba32F(img) => draw_on(img, text, position, style, font),
+    }
+}
+
+fn draw_on<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, text: &str, position: TextPosition, style: &WatermarkStyle, font: &Font) -> anyhow::Result<()>
+where
+    P::Subpixel: Channel,
+{
+    if text.trim().is_empty() { return Err(anyhow!("Watermark text is empty")); }
+    style.check()?;
+    let align = style.align.unwrap_or(match (style.tile, position) {
+        (None, TextPosition::TopLeft | TextPosition::BottomLeft) => TextAlign::Left,
+        (None, TextPosition::TopRight | TextPosition::BottomRight) => TextAlign::Right,
+        _ => TextAlign::Center,
+    });
+    let style = &WatermarkStyle { align: Some(align), ..*style };
+    let px = style.font_px(img.width());
+    let label = render_label(font, text, style, px)?;
+    match style.tile {
+        Some(tile) if style.auto_color => {
+            // The pattern covers everything, so judge by the whole picture
+            let step = (img.width() as usize * img.height() as usize / 20_000).max(1);
+            let background: Vec<[u8; 3]> = img.pixels().step_by(step).map(rgb8).collect();
+            let style = contrasting(style, &background, px);
+            draw_tiled(img, render_label(font, text, &style, px)?, &style, tile)
+        }
+        Some(tile) => draw_tiled(img, label, style, tile),
+        None => {
+            let offset = (style.offset_x, style.offset_y);
+            let what = format!("Watermark text \"{}\"", text);
+            let mut layer = label.rotated(style.rotation);
+            let mut at = place(img, &layer, position, offset, &what)?;
+            if style.auto_color {
+                let style = contrasting(style, &covered(img, &layer, at), px);
+                layer = render_label(font, text, &style, px)?.rotated(style.rotation);
+                at = place(img, &layer, position, offset, &what)?;
+            }
+            blend(img, &layer, at.0, at.1, style.opacity);
+            Ok(())
+        }
+    }
+}
+
+/// Image pixels under the visible part of `layer` placed at `at`.
+fn covered<P: Pixel>(img: &ImageBuffer<P, Vec<P::Subpixel>>, layer: &Layer, at: (i32, i32)) -> Vec<[u8; 3]>
+where
+    P::Subpixel: Channel,
+{
+    let mut out = Vec::new();
+    for y in 0..layer.height {
+        for x in 0..layer.width {
+            let (ix, iy) = ((at.0 + x as i32) as u32, (at.1 + y as i32) as u32);
+            if layer.px[y * layer.width + x][3] > 0.25 {
+                out.push(rgb8(img.get_pixel(ix, iy)));
+            }
+        }
+    }
+    out
+}
+
+/// Colour of `p` at 8 bits per channel, which is plenty to judge contrast by.
+fn rgb8<P: Pixel>(p: &P) -> [u8; 3]
+where
+    P::Subpixel: Channel,
+{
+    [0, 1, 2].map(|c| u8::from_unit(p.channels()[c].to_unit()))
+}
+
+/// WCAG relative luminance of an sRGB colour.
+fn luminance(rgb: [f32; 3]) -> f32 {
+    let lin = |c: f32| {
+        let c = c / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    0.2126 * lin(rgb[0]) + 0.7152 * lin(rgb[1]) + 0.0722 * lin(rgb[2])
+}
+
+fn contrast_ratio(a: f32, b: f32) -> f32 {
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+/// `style` with white or black text, whichever keeps more contrast over all but
+/// the worst tenth of `background`, and an outline when that isn't enough.
+fn contrasting(style: &WatermarkStyle, background: &[[u8; 3]], px: f32) -> WatermarkStyle {
+    let alpha = style.color[3] as f32 / 255.0 * style.opacity;
+    // Contrast the text keeps against most of the background, after blending
+    let worst = |tone: f32| -> f32 {
+        let mut ratios: Vec<f32> = background.iter().map(|bg| {
+            let bg = bg.map(|c| c as f32);
+            let text = bg.map(|c| tone * alpha + c * (1.0 - alpha));
+            contrast_ratio(luminance(bg), luminance(text))
+        }).collect();
+        ratios.sort_by(f32::total_cmp);
+        ratios.get(ratios.len() / 10).copied().unwrap_or(21.0)
+    };
+    let (light, dark) = (worst(255.0), worst(0.0));
+    let (tone, other, best) = if light >= dark { (255, 0, light) } else { (0, 255, dark) };
+
+    let mut out = *style;
+    out.color = [tone, tone, tone, style.color[3]];
+    if best < style.min_contrast && style.outline.is_none() {
+        let width = (px / 12.0).round().max(1.0) as u32;
+        out.outline = Some(Outline { color: [other, other, other, 255], width });
+    }
+    out
+}
+
+/// Composite `logo` (alpha respected) onto `img` at `position`.
+pub fn overlay(img: &mut Canvas, logo: &RgbaImage, position: TextPosition, style: &OverlayStyle) -> anyhow::Result<()> {
+    match img {
+        Canvas::Rgba8(img) => overlay_on(img, logo, position, style),
+        Canvas::Rgba16(img) => overlay_on(img, logo, position, style),
+        Canvas::Rgba32F(img) => overlay_on(img, logo, position, style),
+    }
+}
+
+fn overlay_on<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, logo: &RgbaImage, position: TextPosition, style: &OverlayStyle) -> anyhow::Result<()>
+where
+    P::Subpixel: Channel,
+{
+    if !(0.0..=1.0).contains(&style.opacity) {
+        return Err(anyhow!("Overlay opacity must be between 0 and 1, got {}", style.opacity));
+    }
+    let mut premul = image::DynamicImage::ImageRgba8(logo.clone()).into_rgba32f();
+    for p in premul.pixels_mut() {
+        let a = p.0[3];
+        for c in 0..3 { p.0[c] *= a; }
+    }
+    if let Some(share) = style.scale {
+        if !share.is_finite() || share <= 0.0 {
+            return Err(anyhow!("Overlay scale must be positive, got {}", share));
+        }
+        let width = (img.width() as f32 * share).round().max(1.0);
+        let height = (logo.height() as f32 * width / logo.width() as f32).round().max(1.0);
+        premul = imageops::resize(&premul, width as u32, height as u32, FilterType::Triangle);
+    }
+    let layer = Layer::from_premultiplied(&premul);
+    let at = place(img, &layer, position, (style.offset_x, style.offset_y), "Overlay")?;
+    blend(img, &layer, at.0, at.1, style.opacity);
+    Ok(())
+}
+
+/// Top-left corner for `layer` at `position`; `what` names it in the error when
+/// it doesn't fit.
+fn place<P: Pixel>(img: &ImageBuffer<P, Vec<P::Subpixel>>, layer: &Layer, position: TextPosition, offset: (i32, i32), what: &str) -> anyhow::Result<(i32, i32)> {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let (lw, lh) = (layer.width as i32, layer.height as i32);
+
+    let (left, top) = match position {
+        TextPosition::TopLeft => (PADDING, PADDING),
+        TextPosition::TopRight => (w - lw - PADDING, PADDING),
+        TextPosition::Center => ((w - lw) / 2, (h - lh) / 2),
+        TextPosition::BottomLeft => (PADDING, h - lh - PADDING),
+        TextPosition::BottomRight => (w - lw - PADDING, h - lh - PADDING),
+    };
+    let (left, top) = (left + offset.0, top + offset.1);
+    if left < 0 || top < 0 || left + lw > w || top + lh > h {
+        return Err(anyhow!("{} doesn't fit in {}x{} image", what, w, h));
+    }
+    Ok((left, top))
+}
+
+fn draw_tiled<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, label: Layer, style: &WatermarkStyle, tile: Tile) -> anyhow::Result<()>
+where
+    P::Subpixel: Channel,
+{
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let line = label.height as f32;
+    let step = label.width as f32 + tile.spacing.map_or(2.0 * line, |s| s as f32);
+    let row_step = line + tile.row_spacing.map_or(3.0 * line, |s| s as f32);
+    if step < 1.0 || row_step < 1.0 { return Err(anyhow!("Watermark tile spacing too small")); }
+
+    let layer = label.rotated(tile.angle);
+    let (half_w, half_h) = (layer.width as f32 / 2.0, layer.height as f32 / 2.0);
+    // Along the text and across it, in screen coordinates
+    let (sin, cos) = tile.angle.to_radians().sin_cos();
+    let (along, across) = ((cos, -sin), (sin, cos));
+    let origin = (w / 2.0 + style.offset_x as f32, h / 2.0 + style.offset_y as f32);
+
+    // Enough rows and columns that the lattice covers the image from any origin
+    let reach = (w.hypot(h) + origin.0.abs().max(origin.1.abs()) + half_w.hypot(half_h)).ceil();
+    let rows = (reach / row_step).ceil() as i32;
+    let cols = (reach / step).ceil() as i32 + 1;
+    for r in -rows..=rows {
+        let shift = if r % 2 == 0 { 0.0 } else { step / 2.0 };
+        for c in -cols..=cols {
+            let (a, b) = (c as f32 * step + shift, r as f32 * row_step);
+            let cx = origin.0 + a * along.0 + b * across.0;
+            let cy = origin.1 + a * along.1 + b * across.1;
+            if cx + half_w < 0.0 || cy + half_h < 0.0 || cx - half_w > w || cy - half_h > h { continue; }
+            blend(img, &layer, (cx - half_w).round() as i32, (cy - half_h).round() as i32, style.opacity);
+        }
+    }
+    Ok(())
+}
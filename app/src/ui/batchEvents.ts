@@ -0,0 +1,94 @@
+// Runs batch_copy_and_encode (or resume_batch) as a background job and follows its `batch` events
+// with the progress bar, the console and the cancel button
+
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { BatchEvent, BatchPhase } from './types.js';
+import { consoleManager } from './console.js';
+import { progressBar } from './progressBar.js';
+
+const BATCH_PHASES: BatchPhase[] = ['copy', 'watermark', 'mark', 'swap', 'zip'];
+
+// Share of its copy done at a progress event: the copy's phases take equal parts
+function copyShare(event: Extract<BatchEvent, { type: 'progress' }>): number {
+  const phaseShare = event.total_bytes > 0 ? event.bytes / event.total_bytes : event.file / Math.max(event.files, 1);
+  return (BATCH_PHASES.indexOf(event.phase) + phaseShare) / BATCH_PHASES.length;
+}
+
+// Start a batch and wait for it to finish; rejects when it fails or is cancelled. `args` are the
+// command's: `{ args: BatchArgs }` for batch_copy_and_encode, `{ sourceFolder }` for resume_batch
+export async function runBatch(args: Record<string, unknown>, command = 'batch_copy_and_encode'): Promise<void> {
+  const cancelButton = document.getElementById('cancel-batch-btn') as HTMLButtonElement | null;
+  let jobId: number | null = null;
+  // Events can arrive before the job ID does
+  const early: (BatchEvent & { job: number })[] = [];
+  // Copies are worked on in parallel, so the bar sums the share done of each
+  const shares = new Map<number, number>();
+  let settle: (error?: Error) => void = () => {};
+  const finished = new Promise<void>((resolve, reject) => {
+    settle = (error) => (error ? reject(error) : resolve());
+  });
+
+  const handle = (event: BatchEvent) => {
+    switch (event.type) {
+      case 'progress':
+        shares.set(event.copy, copyShare(event));
+        progressBar.setProgress(([...shares.values()].reduce((a, b) => a + b, 0) / event.copies) * 100);
+        break;
+      case 'skipped':
+        shares.set(event.copy, 1);
+        consoleManager.info(`[${event.copy}/${event.copies}] Finished in an earlier run, skipped`);
+        break;
+      case 'log':
+        consoleManager.info(`[${event.copy}/${event.copies} ${event.phase}] ${event.message}`);
+        break;
+      case 'error':
+        settle(new Error(`[${event.copy}/${event.copies} ${event.phase}] ${event.message}`));
+        break;
+      case 'cancelled':
+        consoleManager.warning(`Cancelled at copy ${event.copy}/${event.copies}, unfinished copies were removed`);
+        settle(new Error('Batch cancelled'));
+        break;
+      case 'done':
+        progressBar.setProgress(100);
+        settle();
+        break;
+    }
+  };
+
+  progressBar.show();
+  const unlisten = await listen<BatchEvent & { job: number }>('batch', ({ payload }) => {
+    if (jobId === null) {
+      early.push(payload);
+    } else if (payload.job === jobId) {
+      handle(payload);
+    }
+  });
+  const cancel = () => {
+    if (jobId !== null) {
+      invoke<boolean>('cancel_job', { id: jobId });
+    }
+  };
+
+  try {
+    jobId = await invoke<number>(command, args);
+    early.filter(event => event.job === jobId).forEach(handle);
+    if (cancelButton) {
+      cancelButton.hidden = false;
+      cancelButton.addEventListener('click', cancel);
+    }
+    await finished;
+  } finally {
+    unlisten();
+    progressBar.hide();
+    if (cancelButton) {
+      cancelButton.hidden = true;
+      cancelButton.removeEventListener('click', cancel);
+    }
+  }
+}
+
+// Carry on with the batch of a source folder from the state file in its -Copies folder
+export function resumeBatch(sourceFolder: string): Promise<void> {
+  return runBatch({ sourceFolder }, 'resume_batch');
+}
@@ -1,12 +1,11 @@
 // Batch operation form component
 
-import { invoke } from '@tauri-apps/api/core';
-import { BatchOptions } from './types.js';
+import { BatchArgs, BatchOptions } from './types.js';
 import { stateManager } from './state.js';
 import { consoleManager } from './console.js';
 import { folderPicker } from './folderPicker.js';
 import { topBar } from './topbar.js';
-import { progressBar } from './progressBar.js';
+import { runBatch } from './batchEvents.js';
 
 class BatchForm {
   private form: HTMLFormElement | null = null;
@@ -157,36 +156,26 @@ class BatchForm {
     }
 
     try {
-      // Start progress simulation
-      const progressPromise = progressBar.simulateProgress(5000);
+      const args: BatchArgs = {
+        source_folder: options.sourceFolder,
+        num_copies: options.numCopies,
+        base_text: options.baseText,
+        add_swap: options.addSwap,
+        add_watermark: options.addWatermark,
+        create_zip: options.createZip,
+        watermark_text: options.watermarkText,
+        photo_number: options.photoNumber
+      };
+      await runBatch({ args });
+
+      consoleManager.success('Batch operation completed successfully!');
       
-      const result = await invoke<boolean>('batch_copy_and_encode', {
-        sourceFolder: options.sourceFolder,
-        numCopies: options.numCopies,
-        baseText: options.baseText,
-        addSwap: options.addSwap,
-        addWatermark: options.addWatermark,
-        createZip: options.createZip,
-        watermarkText: options.watermarkText,
-        photoNumber: options.photoNumber
-      });
-
-      // Wait for progress to complete
-      await progressPromise;
-
-      if (result) {
-        consoleManager.success('Batch operation completed successfully!');
-        
-        // Show output location
-        const outputPath = `${options.sourceFolder}-Copies`;
-        consoleManager.info(`Output location: ${outputPath}`);
-        
-        if (options.createZip) {
-          consoleManager.info('ZIP archives created in the output folder');
-        }
-      } else {
-        consoleManager.error('Batch operation failed');
-        topBar.setError('Batch operation failed');
+      // Show output location
+      const outputPath = `${options.sourceFolder}-Copies`;
+      consoleManager.info(`Output location: ${outputPath}`);
+      
+      if (options.createZip) {
+        consoleManager.info('ZIP archives created in the output folder');
       }
     } catch (error) {
       const errorMessage = error instanceof Error ? error.message : String(error);
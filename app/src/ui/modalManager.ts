@@ -6,8 +6,10 @@ import { stateManager } from './state.js';
 import { consoleManager } from './console.js';
 import { folderPicker } from './folderPicker.js';
 import { progressBar } from './progressBar.js';
+import { runBatch, resumeBatch } from './batchEvents.js';
 import { topBar } from './topbar.js';
 import { settingsManager } from './settingsManager.js';
+import { BatchArgs } from './types.js';
 
 interface BatchModalData {
   baseText: string;
@@ -34,6 +36,7 @@ class ModalManager {
   // Batch Modal
   private batchModal: HTMLElement | null = null;
   private batchSetupBtn: HTMLButtonElement | null = null;
+  private batchResumeBtn: HTMLButtonElement | null = null;
   private batchForm: HTMLFormElement | null = null;
   private watermarkOptions: HTMLElement | null = null;
 
@@ -48,6 +51,7 @@ class ModalManager {
     // Get modal elements
     this.batchModal = document.getElementById('batch-modal');
     this.batchSetupBtn = document.getElementById('batch-setup-btn') as HTMLButtonElement;
+    this.batchResumeBtn = document.getElementById('batch-resume-btn') as HTMLButtonElement;
     this.batchForm = document.getElementById('batch-form-modal') as HTMLFormElement;
     this.watermarkOptions = document.getElementById('watermark-options');
 
@@ -66,6 +70,10 @@ class ModalManager {
       this.openBatchModal();
     });
 
+    this.batchResumeBtn?.addEventListener('click', () => {
+      this.resumeBatchOperation();
+    });
+
     this.batchForm?.addEventListener('submit', (e) => {
       e.preventDefault();
       this.executeBatchOperation();
@@ -147,6 +155,10 @@ class ModalManager {
       this.batchSetupBtn.disabled = !hasPath || isWorking;
     }
 
+    if (this.batchResumeBtn) {
+      this.batchResumeBtn.disabled = !hasPath || isWorking;
+    }
+
     if (this.watermarkSettingsBtn) {
       this.watermarkSettingsBtn.disabled = !hasPath || isWorking;
     }
@@ -222,32 +234,24 @@ class ModalManager {
     consoleManager.info(`Options: swap=${data.addSwap}, watermark=${data.addWatermark}, zip=${data.createZip}`);
 
     try {
-      const progressPromise = progressBar.simulateProgress(data.numCopies * 1000 + 2000);
+      const args: BatchArgs = {
+        source_folder: selectedPath,
+        num_copies: data.numCopies,
+        base_text: data.baseText,
+        add_swap: data.addSwap,
+        add_watermark: data.addWatermark,
+        create_zip: data.createZip,
+        watermark_text: data.watermarkText,
+        photo_number: data.photoNumber
+      };
+      await runBatch({ args });
+
+      consoleManager.success('Batch operation completed successfully!');
+      const outputPath = `${selectedPath}-Copies`;
+      consoleManager.info(`Output location: ${outputPath}`);
       
-      const result = await invoke<boolean>('batch_copy_and_encode', {
-        sourceFolder: selectedPath,
-        numCopies: data.numCopies,
-        baseText: data.baseText,
-        addSwap: data.addSwap,
-        addWatermark: data.addWatermark,
-        createZip: data.createZip,
-        watermarkText: data.watermarkText,
-        photoNumber: data.photoNumber
-      });
-
-      await progressPromise;
-
-      if (result) {
-        consoleManager.success('Batch operation completed successfully!');
-        const outputPath = `${selectedPath}-Copies`;
-        consoleManager.info(`Output location: ${outputPath}`);
-        
-        if (data.createZip) {
-          consoleManager.info('ZIP archives created in the output folder');
-        }
-      } else {
-        consoleManager.error('Batch operation failed');
-        topBar.setError('Batch operation failed');
+      if (data.createZip) {
+        consoleManager.info('ZIP archives created in the output folder');
       }
     } catch (error) {
       const errorMessage = error instanceof Error ? error.message : String(error);
@@ -259,6 +263,36 @@ class ModalManager {
     }
   }
 
+  // Carry on with the batch last run on the selected folder; its arguments come from the state file
+  private async resumeBatchOperation(): Promise<void> {
+    const selectedPath = folderPicker.getCurrentPath();
+
+    if (!selectedPath) {
+      consoleManager.error('No folder selected');
+      return;
+    }
+
+    stateManager.setWorkingStatus(true, 'Resume Batch');
+    topBar.updateStatus('working', 'Resuming batch...');
+
+    consoleManager.operation('Resume Batch');
+    consoleManager.info(`Source: ${selectedPath}`);
+
+    try {
+      await resumeBatch(selectedPath);
+
+      consoleManager.success('Batch operation completed successfully!');
+      consoleManager.info(`Output location: ${selectedPath}-Copies`);
+    } catch (error) {
+      const errorMessage = error instanceof Error ? error.message : String(error);
+      consoleManager.error(`Batch resume error: ${errorMessage}`);
+      topBar.setError('Batch resume error');
+    } finally {
+      stateManager.setWorkingStatus(false);
+      topBar.updateStatus('ready', 'Ready');
+    }
+  }
+
   // Watermark Modal Methods
   private openWatermarkModal(): void {
     if (!this.watermarkModal) return;
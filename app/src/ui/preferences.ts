@@ -40,11 +40,11 @@ class PreferencesManager {
     try {
       const preferences = await invoke<Preferences>('load_preferences');
       
-      // Validate and merge with defaults
+      // Validate and merge with defaults, keeping the preferences this UI has no controls for
       const validatedPreferences: Preferences = {
+        ...preferences,
         theme_mode: preferences.theme_mode === 'dark' ? 'dark' : 'light',
-        auto_clear_console: preferences.auto_clear_console ?? true,
-        last_selected_path: preferences.last_selected_path
+        auto_clear_console: preferences.auto_clear_console ?? true
       };
 
       stateManager.updatePreferences(validatedPreferences);
@@ -68,6 +68,7 @@ class PreferencesManager {
   // Public methods for external use
 
   async resetToDefaults(): Promise<void> {
+    // Preferences without controls here keep their stored value, see save_preferences
     const defaultPreferences: Preferences = {
       theme_mode: 'light',
       auto_clear_console: true,
@@ -90,9 +91,10 @@ class PreferencesManager {
       
       // Validate imported preferences
       const validatedPreferences: Preferences = {
+        ...stateManager.getPreferences(),
+        ...imported,
         theme_mode: imported.theme_mode === 'dark' ? 'dark' : 'light',
-        auto_clear_console: imported.auto_clear_console ?? true,
-        last_selected_path: imported.last_selected_path
+        auto_clear_console: imported.auto_clear_console ?? true
       };
 
       stateManager.updatePreferences(validatedPreferences);
@@ -4,6 +4,16 @@ export interface Preferences {
   theme_mode: 'light' | 'dark';
   auto_clear_console: boolean;
   last_selected_path?: string;
+  // Key file for the keyed scheme; unset means the per-installation key
+  watermark_key_path?: string;
+  keep_backups?: boolean;
+  // Font file for visible watermarks; unset means the bundled DejaVu Sans
+  watermark_font_path?: string;
+  // 1-100; unset means the original's estimated quality
+  jpeg_quality?: number;
+  upright_images?: boolean;
+  batch_workers?: number;
+  batch_io_limit?: number;
 }
 
 export interface BatchOptions {
@@ -17,6 +27,57 @@ export interface BatchOptions {
   photoNumber?: number;
 }
 
+// Arguments of batch_copy_and_encode and plan_batch, passed as one `args` object with the
+// Rust BatchArgs field names
+export interface BatchArgs {
+  source_folder: string;
+  num_copies: number;
+  base_text: string;
+  add_swap: boolean;
+  add_watermark: boolean;
+  create_zip: boolean;
+  watermark_text?: string;
+  photo_number?: number;
+  seed?: number;
+}
+
+// Payload of the `batch` event emitted by batch_copy_and_encode jobs, next to the job ID
+export type BatchPhase = 'copy' | 'watermark' | 'mark' | 'swap' | 'zip';
+
+export type BatchEvent =
+  | { type: 'progress'; phase: BatchPhase; copy: number; copies: number; file: number; files: number; bytes: number; total_bytes: number }
+  | { type: 'log'; phase: BatchPhase; copy: number; copies: number; message: string }
+  | { type: 'error'; phase: BatchPhase; copy: number; copies: number; message: string }
+  | { type: 'skipped'; copy: number; copies: number }
+  | { type: 'cancelled'; copy: number; copies: number }
+  | { type: 'done'; copies: number; output: string };
+
+// Returned by plan_batch, which takes the same BatchArgs as batch_copy_and_encode
+export interface FilePlan {
+  path: string;
+  bytes: number;
+  marker: boolean;
+  visible_watermark: boolean;
+  swapped_with: string | null;
+}
+
+export interface CopyPlan {
+  order: string;
+  folder: string;
+  zip: string | null;
+  files: FilePlan[];
+}
+
+export interface BatchPlan {
+  copies_folder: string;
+  seed: number;
+  bytes_per_copy: number;
+  disk_bytes: number;
+  peak_disk_bytes: number;
+  copies: CopyPlan[];
+  warnings: string[];
+}
+
 export interface AppState {
   selectedPath: string | null;
   preferences: Preferences;